@@ -1,5 +1,5 @@
 use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Op, SledKvsEngine};
 use rand::prelude::*;
 use rand::rngs::SmallRng;
 use tempfile::TempDir;
@@ -39,7 +39,7 @@ fn set_bench(c: &mut Criterion) {
 
 fn get_bench(c: &mut Criterion) {
     let mut group = c.benchmark_group("get_bench");
-    for i in &vec![8, 12, 16] {
+    for i in &[8, 12, 16] {
         group.bench_with_input(format!("kvs_{:02}", i), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
             let store = KvStore::open(temp_dir.path()).unwrap();
@@ -56,7 +56,7 @@ fn get_bench(c: &mut Criterion) {
             })
         });
     }
-    for i in &vec![8, 12, 16] {
+    for i in &[8, 12, 16] {
         group.bench_with_input(format!("sled_{:02}", i), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
             let db = SledKvsEngine::open(temp_dir.path()).unwrap();
@@ -73,9 +73,63 @@ fn get_bench(c: &mut Criterion) {
     group.finish();
 }
 
+// reads right after writes, served by the reader of the active generation
+fn read_after_write_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read_after_write_bench");
+    group.bench_function("kvs", |b| {
+        let temp_dir = TempDir::new().unwrap();
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        // an older generation, so the readers aren't alone in the map
+        for i in 0..(1 << 10) {
+            store.set(format!("old{}", i), "value".to_string()).unwrap();
+        }
+        store.checkpoint_barrier().unwrap();
+        let mut i = 0;
+        b.iter(|| {
+            i += 1;
+            store.set(format!("key{}", i), "value".to_string()).unwrap();
+            store.get(format!("key{}", i)).unwrap();
+        })
+    });
+    group.finish();
+}
+
+// compaction of a store with many small records, most of them adjacent
+fn compaction_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compaction_bench");
+    group.bench_function("kvs_100k", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                let store = KvStore::open(temp_dir.path()).unwrap();
+                let ops = (0..100_000)
+                    .map(|i| Op::Set {
+                        key: format!("key{}", i),
+                        value: "value".to_string(),
+                    })
+                    .collect();
+                store.transaction(ops).unwrap();
+                drop(store);
+                // two generations exist on open, the next write compacts
+                let options = KvStoreOptions {
+                    max_generations: Some(1),
+                    ..Default::default()
+                };
+                let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+                (store, temp_dir)
+            },
+            |(store, _temp_dir)| {
+                store.set("key".to_string(), "value".to_string()).unwrap();
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
 criterion_group! {
     name = benches;
     config = Criterion::default().sample_size(10);
-    targets = set_bench, get_bench
+    targets = set_bench, get_bench, read_after_write_bench, compaction_bench
 }
 criterion_main!(benches);
@@ -27,7 +27,7 @@ fn write_bench<E: KvsEngine, P: ThreadPool>(b: &mut Bencher, pool_size: usize) {
         let server_pool = P::new(pool_size).unwrap();
         let server = KvsServer::new(eng, server_pool, is_stop_c);
 
-        server.run(&ADDRESS.to_owned()).unwrap();
+        server.run(ADDRESS).unwrap();
     });
 
     let value = "value".to_owned();
@@ -37,8 +37,8 @@ fn write_bench<E: KvsEngine, P: ThreadPool>(b: &mut Bencher, pool_size: usize) {
     thread::sleep(Duration::from_secs(1));
     b.iter(|| {
         let wg = WaitGroup::new();
-        for i in 0..ENTRY_COUNT {
-            let key = keys[i].clone();
+        for key in &keys {
+            let key = key.clone();
             let value = value.clone();
             let wg = wg.clone();
             client_pool.spawn(move || {
@@ -88,7 +88,7 @@ fn read_bench<E: KvsEngine, P: ThreadPool>(b: &mut Bencher, pool_size: usize) {
         let server_pool = P::new(pool_size).unwrap();
         let server = KvsServer::new(eng, server_pool, is_stop_c);
 
-        server.run(&ADDRESS.to_owned()).unwrap();
+        server.run(ADDRESS).unwrap();
     });
 
     let value = "value".to_owned();
@@ -96,17 +96,17 @@ fn read_bench<E: KvsEngine, P: ThreadPool>(b: &mut Bencher, pool_size: usize) {
     let client_pool = RayonThreadPool::new(ENTRY_COUNT).unwrap();
 
     thread::sleep(Duration::from_secs(1));
-    for i in 0..ENTRY_COUNT {
+    for key in &keys {
         let mut write_client = KvsClient::connect(ADDRESS).unwrap();
-        let key = keys[i].clone();
+        let key = key.clone();
         let value = value.clone();
         write_client.set(key, value).unwrap();
     }
 
     b.iter(|| {
         let wg = WaitGroup::new();
-        for i in 0..ENTRY_COUNT {
-            let key = keys[i].clone();
+        for key in &keys {
+            let key = key.clone();
             let wg = wg.clone();
             client_pool.spawn(move || {
                 let mut client = KvsClient::connect(ADDRESS).unwrap();
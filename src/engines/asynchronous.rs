@@ -0,0 +1,100 @@
+use tokio::runtime::Handle;
+use tokio::sync::{mpsc, oneshot};
+
+use super::KvsEngine;
+use crate::{KvsError, Result};
+
+type Job<E> = Box<dyn FnOnce(&E) + Send>;
+
+/// The operations queued at most by default.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+
+/// A wrapper running the blocking operations of an engine on the blocking
+/// thread pool of tokio, for async code.
+///
+/// The methods are async instead of blocking, so the reactor threads are
+/// never blocked on the disk. Operations go through a channel to a single
+/// task spawned with `spawn_blocking` and run one by one, so they complete
+/// in the order they're queued, which is the order their futures are first
+/// polled in. The task ends once all clones are dropped. Only available
+/// with the `tokio` feature.
+///
+/// The channel is bounded, `DEFAULT_QUEUE_CAPACITY` by default. An
+/// operation called with the queue full waits for room, so producers
+/// outpacing the engine are slowed down instead of piling up memory.
+pub struct AsyncKvStore<E: KvsEngine> {
+    sender: mpsc::Sender<Job<E>>,
+}
+impl<E: KvsEngine> Clone for AsyncKvStore<E> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<E: KvsEngine> AsyncKvStore<E> {
+    /// Wraps `engine` with the default queue capacity, spawning its task on
+    /// the current runtime.
+    ///
+    /// # Errors
+    /// It returns `KvsError::StringError` if it's called outside of a tokio
+    /// runtime.
+    pub fn new(engine: E) -> Result<Self> {
+        AsyncKvStore::with_capacity(engine, DEFAULT_QUEUE_CAPACITY)
+    }
+
+    /// Wraps `engine` with room for `capacity` queued operations, spawning
+    /// its task on the current runtime. A `capacity` of 0 is taken as 1.
+    ///
+    /// # Errors
+    /// It returns `KvsError::StringError` if it's called outside of a tokio
+    /// runtime.
+    pub fn with_capacity(engine: E, capacity: usize) -> Result<Self> {
+        let handle = Handle::try_current().map_err(|err| KvsError::StringError(err.to_string()))?;
+        let (sender, mut receiver) = mpsc::channel::<Job<E>>(capacity.max(1));
+        handle.spawn_blocking(move || {
+            while let Some(job) = receiver.blocking_recv() {
+                job(&engine);
+            }
+        });
+        Ok(AsyncKvStore { sender })
+    }
+
+    /// Sets the value of a string key to a string, see `KvsEngine::set`.
+    pub async fn set(&self, key: String, value: String) -> Result<()> {
+        self.call(move |engine| engine.set(key, value)).await
+    }
+
+    /// Gets the string value of a given string key, see `KvsEngine::get`.
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        self.call(move |engine| engine.get(key)).await
+    }
+
+    /// Removes a given key, see `KvsEngine::remove`.
+    pub async fn remove(&self, key: String) -> Result<()> {
+        self.call(move |engine| engine.remove(key)).await
+    }
+
+    /// Returns the number of operations queued, at most the capacity. The
+    /// running one and the ones waiting for room aren't counted.
+    pub fn queue_depth(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+
+    async fn call<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&E) -> Result<T> + Send + 'static,
+    {
+        let (done, result) = oneshot::channel();
+        let job: Job<E> = Box::new(move |engine| {
+            // the caller might not wait for it anymore
+            let _ = done.send(f(engine));
+        });
+        // the task is gone, e.g. an operation panicked
+        let gone = || KvsError::StringError("The async store task is gone".to_owned());
+        self.sender.send(job).await.map_err(|_| gone())?;
+        result.await.map_err(|_| gone())?
+    }
+}
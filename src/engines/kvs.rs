@@ -1,25 +1,95 @@
-use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::cell::{Cell, RefCell};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::ffi::OsStr;
-use std::fs::{self, File, OpenOptions};
+use std::fmt::{self, Display};
+use std::fs;
 use std::io::prelude::*;
 use std::io::{self, BufReader, BufWriter, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use dashmap::DashMap;
 use log::warn;
+use serde::de::IgnoredAny;
 use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
 
-use crate::{KvsEngine, KvsError, Result};
+use crate::{KvsEngine, KvsError, OpenError, Result};
 
-// compact if more than `threshold` bytes can be saved
-const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+mod access;
+mod bloom;
+mod cache;
+mod cancel;
+mod checkpoint;
+mod clock;
+mod commit;
+mod csv;
+#[cfg(feature = "fault-injection")]
+mod fault;
+mod generations;
+mod history;
+mod manifest;
+#[cfg(all(feature = "mmap", unix))]
+mod mmap;
+mod options;
+mod prefix_index;
+mod read_only;
+mod reader;
+mod recency;
+mod retry;
+mod storage;
+mod tail;
+mod verify;
+mod versions;
+
+use self::access::AccessTimes;
+use self::bloom::BloomFilters;
+pub use self::cache::CacheStats;
+use self::cache::ValueCache;
+pub use self::cancel::CancellationToken;
+use self::checkpoint::Checkpoint;
+pub use self::clock::{Clock, SystemClock};
+pub use self::commit::CommitPolicy;
+use self::commit::GroupCommit;
+use self::csv::CsvReader;
+#[cfg(feature = "fault-injection")]
+pub use self::fault::{FaultSchedule, FaultyStorage};
+pub use self::generations::GenInfo;
+pub use self::history::{CompactionHistory, CompactionRecord};
+use self::manifest::Manifest;
+#[cfg(all(feature = "mmap", unix))]
+use self::mmap::Mappings;
+pub use self::options::{KvStoreOptions, OnWrite};
+use self::prefix_index::PrefixIndex;
+use self::read_only::ReadOnlyStorage;
+pub use self::reader::KvReader;
+use self::recency::Recency;
+pub use self::retry::RetryPolicy;
+use self::retry::RetryStorage;
+pub use self::storage::{FsStorage, LogKind, LogReader, LogStorage, LogWriter, MemoryStorage};
+pub use self::tail::{LogTailer, TailRecord};
+pub use self::verify::{CorruptSpan, VerifyReport};
+use self::versions::Versions;
+
+// tiered compaction merges adjacent generations of at most this size ratio
+const TIERED_SIZE_RATIO: u64 = 4;
+// logs start with the magic and the format version in little endian, a log
+// without it is version 0. The magic can't start a JSON document.
+const FORMAT_MAGIC: &[u8; 4] = b"\0kvs";
+const FORMAT_HEADER_LEN: u64 = 8;
+// the most overwritten keys `ImportPreview` names
+const IMPORT_PREVIEW_SAMPLE: usize = 10;
 
 // better to make reader map ordered on generation for removal operations
-type ReaderMap = BTreeMap<u64, BufReader<File>>;
+type LogBufReader = BufReader<Box<dyn LogReader>>;
+type ReaderMap = BTreeMap<u64, LogBufReader>;
 // index map is shared among readers and the writer, concurrent collection is
 // more convenient (and usually faster) than RwLock<...>
 type IndexMap = DashMap<String, CommandPos>;
@@ -47,6 +117,10 @@ type IndexMap = DashMap<String, CommandPos>;
 /// monotonically increasing generation numbers with a `log` extension name.
 /// A `BTreeMap` in memory stores the keys and the value locations for fast query.
 ///
+/// Log files start with a format version header. Headerless logs of older
+/// versions are still read, and a compaction rewrites them in the current
+/// format.
+///
 /// Example:
 ///
 /// ```rust
@@ -71,6 +145,30 @@ pub struct KvStore {
     reader: ReadAgent,
     // Interior KvsStoreWriter works as a singleton
     writer: Arc<Mutex<WriteAgent>>,
+    // last access of keys, only tracked in bounded cache mode
+    recency: Option<Arc<Recency>>,
+    // wall-clock time of the last access of keys, if enabled
+    access: Option<Arc<AccessTimes>>,
+    // recently read values, if enabled
+    cache: Option<Arc<ValueCache>>,
+    // keys of each generation, if enabled
+    filters: Option<Arc<BloomFilters>>,
+    // keys by value prefix, if enabled
+    prefixes: Option<Arc<PrefixIndex>>,
+    // previous values of the keys, if enabled
+    versions: Option<Arc<Versions>>,
+    // the options it's opened with, for `reopen`
+    options: KvStoreOptions,
+    // the value of the last `get_buffered`, per clone
+    value_buf: String,
+}
+impl fmt::Debug for KvStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KvStore")
+            .field("path", &self.path)
+            .field("keys", &self.index.len())
+            .finish_non_exhaustive()
+    }
 }
 impl Clone for KvStore {
     fn clone(&self) -> Self {
@@ -79,6 +177,14 @@ impl Clone for KvStore {
             index: self.index.clone(),
             reader: self.reader.clone(),
             writer: self.writer.clone(),
+            recency: self.recency.clone(),
+            access: self.access.clone(),
+            cache: self.cache.clone(),
+            filters: self.filters.clone(),
+            prefixes: self.prefixes.clone(),
+            versions: self.versions.clone(),
+            options: self.options.clone(),
+            value_buf: String::new(),
         }
     }
 }
@@ -89,281 +195,3981 @@ impl KvsEngine for KvStore {
     /// This will create a new directory if the given one does not exist.
     ///
     /// # Errors
+    /// It returns `KvsError::WrongEngine` if the directory belongs to another
+    /// engine, see `try_open`.
     /// It propagates I/O or deserialization errors during the log replay.
     fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        KvStore::try_open(path).map_err(KvsError::from)
+    }
+
+    /// Sets the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    ///
+    /// # Errors
+    /// It propagates I/O or serialization errors during writing the log.
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.lock_writer()?.set(key, &value)
+    }
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist. With `cache_values`,
+    /// a cached value is returned without reading the log.
+    ///
+    /// # Errors
+    /// It returns `KvsError::UnexpectedCommandType` if the given command type unexpected.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        // reading is concurrent
+        let cmd_pos = match self.lookup(&key) {
+            Some(cmd_pos) => cmd_pos,
+            None => return Ok(None),
+        };
+        if let Some(recency) = &self.recency {
+            recency.touch(&key);
+        }
+        if let Some(access) = &self.access {
+            access.touch(&key);
+        }
+        self.reader
+            .read_cached(&key, &cmd_pos, self.cache.as_deref(), &self.index)
+            .map(Some)
+    }
+
+    /// Remove a given key.
+    ///
+    /// # Errors
+    /// It returns `KvsError::KeyNotFound` if the given key is not found, unless
+    /// `remove_missing_is_error` is turned off.
+    /// It propagates I/O or serialization errors during writing the log.
+    fn remove(&self, key: String) -> Result<()> {
+        self.lock_writer()?.remove(key)
+    }
+}
+
+impl KvStore {
+    /// The log format version written by this build.
+    pub const FORMAT_VERSION: u32 = 1;
+
+    /// Returns the oldest log format version of the store at `path`.
+    ///
+    /// It's `FORMAT_VERSION` if there are no logs yet.
+    ///
+    /// # Errors
+    /// It propagates I/O errors during reading the logs.
+    pub fn format_version(path: impl AsRef<Path>) -> Result<u32> {
+        let path = path.as_ref();
+        let mut oldest = None;
+        for gen in sorted_gen_list(path)? {
+            let mut reader = BufReader::new(fs::File::open(log_file_path(path, gen))?);
+            let version = read_format_version(&mut reader)?;
+            oldest = Some(oldest.map_or(version, |oldest: u32| oldest.min(version)));
+        }
+        Ok(oldest.unwrap_or(Self::FORMAT_VERSION))
+    }
+
+    /// Estimates the number of keys of the store at `path` without opening it.
+    ///
+    /// Only the keys of the logs are tracked, no value location, reader or
+    /// writer is built. It's approximate as transaction boundaries are
+    /// ignored, operations of a transaction never committed are counted.
+    ///
+    /// # Errors
+    /// It propagates I/O or deserialization errors during reading the logs.
+    pub fn estimate_key_count(path: impl AsRef<Path>) -> Result<usize> {
+        let path = path.as_ref();
+        let mut keys = HashSet::new();
+        for gen in sorted_gen_list(path)? {
+            let mut reader = BufReader::new(fs::File::open(log_file_path(path, gen))?);
+            read_format_version(&mut reader)?;
+            for cmd in Deserializer::from_reader(reader).into_iter::<ReplayCommand>() {
+                match cmd? {
+                    Command::Set { key, .. } | Command::SetRef { key, .. } => {
+                        keys.insert(key);
+                    }
+                    Command::Remove { key, .. } | Command::SoftRemove { key, .. } => {
+                        keys.remove(&key);
+                    }
+                    Command::Reset => keys.clear(),
+                    Command::TxnBegin | Command::TxnCommit => {}
+                }
+            }
+        }
+        Ok(keys.len())
+    }
+
+    /// Checks every log of the store at `path` without opening it.
+    ///
+    /// It's read-only, the report locates the corrupt byte ranges for
+    /// inspection. Values are not checked to be valid UTF-8.
+    ///
+    /// # Errors
+    /// It propagates I/O errors during reading the logs.
+    pub fn verify(path: impl AsRef<Path>) -> Result<VerifyReport> {
+        verify::verify(path.as_ref())
+    }
+
+    /// Lists the generations of the store at `path` without opening it.
+    ///
+    /// It's read-only, meant for watching fragmentation and the effect of
+    /// compactions. Counting the records scans every log, so it's only done
+    /// with `count_records`.
+    ///
+    /// # Errors
+    /// It propagates I/O or deserialization errors during reading the logs.
+    pub fn list_generations(path: impl AsRef<Path>, count_records: bool) -> Result<Vec<GenInfo>> {
+        generations::list_generations(path.as_ref(), count_records)
+    }
+
+    /// Copies the logs of the store at `src` byte for byte to `dst` and opens
+    /// a store there, rebuilding the index by replaying them.
+    ///
+    /// It's meant for conformance tests, e.g. checking that the replay is
+    /// deterministic by comparing `content_hash` of both stores, or that the
+    /// logs of another version are read the same. Value logs go along,
+    /// checkpoints don't, so everything is replayed. `options` must match
+    /// the layout of `src`. The source isn't opened, it shouldn't be written
+    /// meanwhile.
+    ///
+    /// # Errors
+    /// It returns `KvsError::InvalidGeneration` if `dst` has logs already,
+    /// nothing is copied in that case. It propagates I/O errors during
+    /// copying and the errors of `open_with_options`.
+    pub fn replay_log_bytes(
+        src: impl AsRef<Path>,
+        dst: impl Into<PathBuf>,
+        options: KvStoreOptions,
+    ) -> Result<Self> {
+        let (src, dst) = (src.as_ref(), dst.into());
+        fs::create_dir_all(&dst)?;
+        if let Some(&gen) = sorted_gen_list(&dst)?.first() {
+            return Err(KvsError::InvalidGeneration { gen });
+        }
+        for gen in sorted_gen_list(src)? {
+            fs::copy(log_file_path(src, gen), log_file_path(&dst, gen))?;
+            let value_log = value_log_file_path(src, gen);
+            if value_log.exists() {
+                fs::copy(value_log, value_log_file_path(&dst, gen))?;
+            }
+        }
+        KvStore::open_with_options(dst, options)
+    }
+
+    /// Open the KvStore at a given path like `open`, with an error telling
+    /// why it can't be opened.
+    ///
+    /// The directory is refused if its engine file, written by `kvs-server`,
+    /// names another engine.
+    ///
+    /// # Errors
+    /// It returns an `OpenError` classifying the errors of `open`.
+    pub fn try_open(path: impl Into<PathBuf>) -> std::result::Result<Self, OpenError> {
         let path = path.into();
-        fs::create_dir_all(&path)?;
+        if path.exists() && !path.is_dir() {
+            return Err(OpenError::NotADirectory(path.display().to_string()));
+        }
+        if let Ok(engine) = fs::read_to_string(path.join("engine")) {
+            if engine.trim() != "kvs" {
+                return Err(OpenError::WrongEngine {
+                    found: engine.trim().to_owned(),
+                });
+            }
+        }
+        KvStore::open_with_options(path.clone(), KvStoreOptions::default())
+            .map_err(|err| OpenError::classify(path, err))
+    }
+
+    /// Open the KvStore at a given path with the given options.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    /// If checkpointing is enabled and a valid checkpoint is found, only the
+    /// commands written after it are replayed.
+    ///
+    /// # Errors
+    /// It returns `KvsError::UnsupportedFormat` if a log is written by a newer
+    /// version, `KvsError::IncompleteRecovery` if `recovery_limit` is hit
+    /// without a checkpoint, or `KvsError::InvalidGeneration` if `start_gen`
+    /// is taken.
+    /// It propagates I/O or deserialization errors during the log replay.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<Self> {
+        KvStore::open_reporting(path.into(), options).map(|(store, _)| store)
+    }
+
+    /// Open the KvStore at a given path like `open`, along with a report of
+    /// the recovery.
+    ///
+    /// The report tells what `open` does silently, e.g. to find out why a
+    /// startup is slow or data is missing.
+    ///
+    /// # Errors
+    /// It propagates the errors of `open`.
+    pub fn open_verbose(path: impl Into<PathBuf>) -> Result<(Self, OpenReport)> {
+        KvStore::open_reporting(path.into(), KvStoreOptions::default())
+    }
+
+    /// Open the KvStore at a given path without writing anything there, e.g.
+    /// a read-only file system snapshot.
+    ///
+    /// No directory, generation, checkpoint or manifest is created, and
+    /// leftovers of an interrupted compaction are left in place. The newest
+    /// log might be the active one of a store still running when the
+    /// snapshot is taken, so its replay stops at the first unreadable record
+    /// instead of failing, keeping the records before it. Every write returns
+    /// `KvsError::ReadOnly`. It's `open_with_options` with `read_only`, which
+    /// combines with the other options, e.g. `split_values`.
+    ///
+    /// # Errors
+    /// It propagates I/O or deserialization errors during the log replay,
+    /// e.g. if the directory doesn't exist.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<Self> {
+        let options = KvStoreOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        KvStore::open_with_options(path, options)
+    }
+
+    fn open_reporting(path: PathBuf, options: KvStoreOptions) -> Result<(Self, OpenReport)> {
+        // the generation is taken once written to
+        let stored_options = KvStoreOptions {
+            start_gen: None,
+            ..options.clone()
+        };
+        // only the files of the default storage can be mapped
+        #[cfg(all(feature = "mmap", unix))]
+        let mmap_dir = (options.mmap_reads && options.storage.is_none()).then(|| path.clone());
+        let storage = match options.storage {
+            Some(storage) => storage,
+            None => {
+                if !options.read_only {
+                    fs::create_dir_all(&path)?;
+                }
+                Arc::new(FsStorage::new(&path).sync_directory(options.sync_directory))
+            }
+        };
+        let storage: Arc<dyn LogStorage> = match options.io_retry {
+            Some(policy) => Arc::new(RetryStorage {
+                inner: storage,
+                policy,
+            }),
+            None => storage,
+        };
+        let storage: Arc<dyn LogStorage> = if options.read_only {
+            Arc::new(ReadOnlyStorage { inner: storage })
+        } else {
+            storage
+        };
 
-        let index = Arc::new(IndexMap::new());
+        let index = Arc::new(IndexMap::with_capacity(options.expected_keys));
         // build all exist logs into readers
         let mut readers = ReaderMap::new();
 
-        let gen_list = sorted_gen_list(&path)?;
+        // partial outputs of a compaction interrupted by a crash
+        storage.remove_temps()?;
+        let mut gen_list = storage.list_generations()?;
+        let current_gen = match options.start_gen {
+            // it has to come after the others to be replayed last
+            Some(gen)
+                if gen > *gen_list.last().unwrap_or(&0)
+                    && storage.len(gen, LogKind::ValueLog)?.is_none() =>
+            {
+                gen
+            }
+            Some(gen) => return Err(KvsError::InvalidGeneration { gen }),
+            None => gen_list.last().unwrap_or(&0) + 1,
+        };
         let mut stale_bytes = 0;
 
-        for &gen in &gen_list {
-            let filepath = log_file_path(&path, gen);
-            let mut reader = BufReader::new(File::open(&filepath)?);
-            stale_bytes += load_log(gen, &mut reader, &*index)?;
-            readers.insert(gen, reader);
+        let checkpoint_path = options
+            .checkpoint_path
+            .clone()
+            .unwrap_or_else(|| path.join("checkpoint"));
+        // (gen, pos) where the replay starts
+        let mut replay_from = (0, 0);
+        let mut checkpoint_loaded = false;
+        let mut replay = Replay {
+            versions: (options.retained_versions > 0)
+                .then(|| Arc::new(Versions::new(options.retained_versions))),
+            ..Default::default()
+        };
+        if options.checkpoint_interval.is_some() {
+            match Checkpoint::load(&checkpoint_path) {
+                Ok(Some(checkpoint)) if checkpoint.is_valid(&*storage, &gen_list) => {
+                    replay_from = (checkpoint.gen, checkpoint.pos);
+                    checkpoint_loaded = true;
+                    stale_bytes = checkpoint.stale_bytes;
+                    replay.seq = checkpoint.seq;
+                    for (key, cmd_pos) in checkpoint.index {
+                        index.insert(key, cmd_pos);
+                    }
+                }
+                Ok(Some(_)) => replay
+                    .report
+                    .warn("Checkpoint is outdated, replay all logs".to_owned()),
+                Ok(None) => {}
+                Err(err) => replay.report.warn(format!(
+                    "Failed to load checkpoint, replay all logs: {}",
+                    err
+                )),
+            }
+        }
+
+        let mut starts: Vec<Option<u64>> = gen_list
+            .iter()
+            .map(|gen| match gen.cmp(&replay_from.0) {
+                std::cmp::Ordering::Less => None,
+                std::cmp::Ordering::Equal => Some(replay_from.1),
+                std::cmp::Ordering::Greater => Some(0),
+            })
+            .collect();
+        if let Some(limit) = options.recovery_limit {
+            let mut pending = Vec::with_capacity(gen_list.len());
+            for (&gen, start) in gen_list.iter().zip(&starts) {
+                let len = storage.len(gen, LogKind::Log)?.unwrap_or(0);
+                pending.push(start.map_or(0, |start| len.saturating_sub(start)));
+            }
+            let mut needed: u64 = pending.iter().sum();
+            if needed > limit {
+                if !checkpoint_loaded {
+                    return Err(KvsError::IncompleteRecovery { limit, needed });
+                }
+                // skip the oldest logs, their commands are lost
+                for (start, len) in starts.iter_mut().zip(pending) {
+                    if needed <= limit {
+                        break;
+                    }
+                    if start.take().is_some() {
+                        needed -= len;
+                    }
+                }
+                replay.report.warn(format!(
+                    "Replay limit of {} bytes is hit, older logs are skipped",
+                    limit
+                ));
+            }
+        }
+
+        let split_values = options.split_values;
+        let newest_gen = gen_list.last().copied();
+        for (&gen, start) in gen_list.iter().zip(starts) {
+            // the active log of the snapshot might end with a torn record
+            replay.lenient = options.read_only && Some(gen) == newest_gen;
+            let mut reader = BufReader::new(storage.open(gen, LogKind::Log)?);
+            if let Some(start) = start {
+                if storage.len(gen, LogKind::Log)? == Some(0) {
+                    replay
+                        .report
+                        .warn(format!("Log of generation {} is empty", gen));
+                }
+                let gen_stale_bytes = load_log(
+                    gen,
+                    &mut reader,
+                    start,
+                    &index,
+                    split_values,
+                    options.max_record_len,
+                    &mut replay,
+                )?;
+                if replay.reset_gen == Some(gen) {
+                    stale_bytes = 0;
+                }
+                stale_bytes += gen_stale_bytes;
+                replay.report.generations += 1;
+            }
+            // values are read from the value logs, they're opened lazily
+            if !split_values {
+                readers.insert(gen, reader);
+            }
+        }
+
+        // a compaction dropping keys crashed before removing the older logs
+        if let Some(reset_gen) = replay.reset_gen {
+            for &gen in gen_list.iter().filter(|&&gen| gen < reset_gen) {
+                readers.remove(&gen);
+                if options.read_only {
+                    continue;
+                }
+                for kind in [LogKind::Log, LogKind::ValueLog] {
+                    storage.remove(gen, kind)?;
+                }
+            }
+            gen_list.retain(|&gen| gen >= reset_gen);
         }
 
-        let current_gen = gen_list.last().unwrap_or(&0) + 1;
+        let versions = replay.versions.take();
+        let live_bytes = index.iter().map(|cmd_pos| cmd_pos.len).sum();
+        let recency = options.max_bytes.map(|_| Arc::new(Recency::default()));
+        let cache = options
+            .cache_values
+            .then(|| Arc::new(ValueCache::new(options.value_cache_bytes)));
+        let filters = options
+            .bloom_filters
+            .then(|| Arc::new(BloomFilters::from_index(&index)));
         let reader = ReadAgent {
-            path: path.clone(),
+            storage: storage.clone(),
+            split_values,
             first_gen: Arc::new(AtomicU64::new(current_gen)),
+            epoch: Arc::new(AtomicU64::new(0)),
+            seen_epoch: Cell::new(0),
             readers: RefCell::new(readers),
+            newest: RefCell::new(None),
+            scratch: RefCell::new(Vec::new()),
+            poisoned: options.strict.then(|| Arc::new(AtomicBool::new(false))),
+            #[cfg(all(feature = "mmap", unix))]
+            mapped: mmap_dir.map(|dir| {
+                let kind = if split_values {
+                    LogKind::ValueLog
+                } else {
+                    LogKind::Log
+                };
+                Mappings::new(dir, kind)
+            }),
+        };
+        let prefixes = match options.value_prefix_len {
+            Some(len) => {
+                let prefixes = PrefixIndex::new(len);
+                for entry in index.iter() {
+                    prefixes.insert(entry.key(), &reader.read_value(entry.key(), &entry)?);
+                }
+                Some(Arc::new(prefixes))
+            }
+            None => None,
         };
+        // value logs hold no commands to check
+        if options.strict && !split_values {
+            let reader = reader.clone();
+            let index = index.clone();
+            thread::Builder::new()
+                .name("kvs-consistency-check".to_owned())
+                .spawn(move || reader.check_consistency(&index))?;
+        }
+
+        let clock = options.clock.unwrap_or_else(|| Arc::new(SystemClock));
+        let access = options
+            .track_access_times
+            .then(|| Arc::new(AccessTimes::new(clock.clone())));
+        let checkpointer = options.checkpoint_interval.filter(|_| !options.read_only);
+        let checkpointer = checkpointer.map(|interval| Checkpointer {
+            path: checkpoint_path,
+            interval,
+            last_ms: clock.now_ms(),
+        });
 
-        let writer = new_log_file(&path, current_gen)?;
+        // nothing is ever written to the active generation of a read-only
+        // store, it's kept in memory
+        let active_storage: Arc<dyn LogStorage> = if options.read_only {
+            Arc::new(MemoryStorage::default())
+        } else {
+            storage.clone()
+        };
+        let (writer, value_writer) = new_generation(&*active_storage, current_gen, split_values)?;
         let writer = WriteAgent {
-            path: path.clone(),
+            storage,
             current_gen,
+            // the new generation included
+            gen_count: gen_list.len() + 1,
+            max_generations: options.max_generations,
+            compaction_cooldown: options.compaction_cooldown,
+            last_compaction_ms: None,
             reader: reader.clone(),
             writer,
+            value_writer,
             stale_bytes,
+            compaction_threshold: options.compaction_threshold,
+            max_record_len: options.max_record_len,
+            compaction_skip_len: options.compaction_skip_len,
+            pretty_records: options.pretty_records,
+            dedup_values: options.dedup_values,
+            seq: replay.seq,
+            live_bytes,
+            max_bytes: options.max_bytes,
+            max_total_bytes: options.max_total_bytes,
+            user_bytes: 0,
+            written_bytes: 0,
+            recency: recency.clone(),
+            access: access.clone(),
+            cache: cache.clone(),
+            filters: filters.clone(),
+            prefixes: prefixes.clone(),
+            versions: versions.clone(),
+            trash: std::mem::take(&mut replay.trash),
+            pinned: HashSet::new(),
+            fsync_on_drop: options.flush_and_fsync_on_drop,
+            group: options.commit_policy.map(GroupCommit::new),
+            remove_missing_is_error: options.remove_missing_is_error,
             index: index.clone(),
+            checkpointer,
+            manifest_path: (options.write_manifest && !options.read_only)
+                .then(|| path.join("MANIFEST")),
+            history: CompactionHistory::default(),
+            watchers: HashMap::new(),
+            on_write: options.on_write.clone(),
+            clock,
         };
 
-        Ok(KvStore {
+        writer.update_manifest();
+        let writer = Arc::new(Mutex::new(writer));
+        if let Some(interval) = options.compaction_interval.filter(|_| !options.read_only) {
+            let writer = Arc::downgrade(&writer);
+            thread::Builder::new()
+                .name("kvs-compaction".to_owned())
+                .spawn(move || compact_periodically(&writer, interval))?;
+        }
+        if let Some(policy) = options.commit_policy.filter(|_| !options.read_only) {
+            let writer = Arc::downgrade(&writer);
+            thread::Builder::new()
+                .name("kvs-commit".to_owned())
+                .spawn(move || sync_periodically(&writer, policy.max_delay))?;
+        }
+        let store = KvStore {
             path,
             index,
             reader,
-            writer: Arc::new(Mutex::new(writer)),
-        })
+            writer,
+            recency,
+            access,
+            cache,
+            filters,
+            prefixes,
+            versions,
+            options: stored_options,
+            value_buf: String::new(),
+        };
+        replay.report.stale_bytes = stale_bytes;
+        Ok((store, replay.report))
     }
 
-    /// Sets the value of a string key to a string.
+    /// Rebuilds the store from the disk like `open_with_options` with the
+    /// same path and options, e.g. after another process changed the logs.
     ///
-    /// If the key already exists, the previous value will be overwritten.
+    /// The log is flushed first. Other clones keep working on the old state
+    /// with their own writer, so it's meant for a store without clones.
+    /// Strict mode starts over unpoisoned.
     ///
     /// # Errors
-    /// It propagates I/O or serialization errors during writing the log.
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.writer.lock().unwrap().set(key, value)
+    /// It propagates the errors of `open_with_options`, the store is left
+    /// as it was then.
+    pub fn reopen(&mut self) -> Result<()> {
+        self.writer.lock().unwrap().writer.flush()?;
+        *self = KvStore::open_with_options(self.path.clone(), self.options.clone())?;
+        Ok(())
     }
 
-    /// Gets the string value of a given string key.
+    /// Rebuilds the in-memory index by replaying every log from the start,
+    /// e.g. after `append_raw` without `index_raw` or to recover from a bug
+    /// corrupting the index. Nothing is written to the logs.
     ///
-    /// Returns `None` if the given key does not exist.
+    /// It's `open` without reopening the files: the stale bytes, the live
+    /// bytes, the sequence number and the previous values of
+    /// `retained_versions` are recounted, and the value cache, the bloom
+    /// filters and the value prefix index follow the keys that changed. A
+    /// loaded checkpoint is ignored and overwritten if enabled. Watchers and
+    /// `on_write` aren't called, the logs don't change. It takes time and
+    /// reads in proportion to the total size of the logs, and writes of any
+    /// clone wait for it.
     ///
     /// # Errors
-    /// It returns `KvsError::UnexpectedCommandType` if the given command type unexpected.
-    fn get(&self, key: String) -> Result<Option<String>> {
-        // reading is concurrent
-        if let Some(cmd_pos) = self.index.get(&key) {
-            self.reader.read_and(&cmd_pos, |rdr| {
-                if let Command::Set { value, .. } = serde_json::from_reader(rdr)? {
-                    Ok(Some(value))
-                } else {
-                    Err(KvsError::UnexpectedCommandType)
-                }
-            })
-        } else {
-            Ok(None)
+    /// It propagates I/O or deserialization errors during the replay, the
+    /// index is left as it was then.
+    pub fn rebuild_index(&mut self) -> Result<()> {
+        self.writer.lock().unwrap().rebuild_index()
+    }
+
+    /// Returns a channel receiving the new value of `key` whenever it's set,
+    /// or `None` when it's removed.
+    ///
+    /// The values are sent inside the call changing the key, before it
+    /// returns, whichever clone of the store it's called on. Transactions,
+    /// renames, evictions and `replace_all` notify too, compactions don't
+    /// as values don't change. It's best-effort, a watcher whose receiver
+    /// is dropped is only pruned on the next change of its key.
+    pub fn watch(&mut self, key: String) -> Receiver<Option<String>> {
+        let (sender, receiver) = mpsc::channel();
+        let mut writer = self.writer.lock().unwrap();
+        writer.watchers.entry(key).or_default().push(sender);
+        receiver
+    }
+
+    /// Pins `key` so that compactions leave its record in place instead of
+    /// copying it, e.g. for a hot key which rarely changes.
+    ///
+    /// The generation holding the record is kept whole like the ones of
+    /// `compaction_skip_len`, its stale records included, so a pin trades
+    /// the disk space of the generation for not rewriting the key. Pins on
+    /// keys spread across many generations can keep most of the stale bytes
+    /// from being reclaimed. A compaction dropping keys, e.g.
+    /// `delete_prefix`, still rewrites everything.
+    ///
+    /// Pins are by key: they hold across writes and removals of the key,
+    /// and they're kept in memory only, shared by the clones of the store.
+    pub fn pin(&mut self, key: String) {
+        self.writer.lock().unwrap().pinned.insert(key);
+    }
+
+    /// Unpins `key`, returns whether it was pinned. Its generation is
+    /// reclaimed by the next compaction if nothing else keeps it.
+    pub fn unpin(&mut self, key: &str) -> bool {
+        self.writer.lock().unwrap().pinned.remove(key)
+    }
+
+    /// Returns the directory of the store, the path it's opened with.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Removes a given key like `remove`, returns the bytes it makes stale.
+    ///
+    /// It's meant for compaction policies reacting to removals. Removing a
+    /// missing key with `remove_missing_is_error` off makes no bytes stale.
+    ///
+    /// # Errors
+    /// It returns the errors of `remove`.
+    pub fn remove_with_stats(&self, key: String) -> Result<RemoveStats> {
+        self.lock_writer()?.remove_with_stats(key)
+    }
+
+    /// Removes a given key like `remove` but keeps its value for `undelete`.
+    ///
+    /// The key is hidden from `get`, the scans and every other read as if
+    /// removed, and it's still hidden after a reopen. Setting it again drops
+    /// the kept value. Any compaction, `replace_all` included, removes the
+    /// soft-removed keys for good, and so does opening from a checkpoint
+    /// written after the removal.
+    ///
+    /// # Errors
+    /// It returns the errors of `remove`.
+    pub fn soft_remove(&mut self, key: String) -> Result<()> {
+        self.lock_writer()?.soft_remove(key)
+    }
+
+    /// Sets a soft-removed key back to the value it had, returns whether it
+    /// was soft-removed.
+    ///
+    /// It's a new `set`, watchers and `on_write` see it as one. A key set or
+    /// removed since, or dropped by a compaction, isn't restored.
+    ///
+    /// # Errors
+    /// It propagates I/O or deserialization errors during reading the value
+    /// and the errors of `set`.
+    pub fn undelete(&mut self, key: String) -> Result<bool> {
+        self.lock_writer()?.undelete(key)
+    }
+
+    /// Appends pre-serialized records to the active log as they are, returns
+    /// where they're written.
+    ///
+    /// It's a primitive for replication or custom protocols. Nothing is
+    /// checked and the index is left alone, so the caller takes over what
+    /// `set` and `remove` do:
+    /// - `bytes` must be whole records of the log format, or the next `open`
+    ///   fails to replay the log.
+    /// - The index has to be pointed to a written `Set` with `index_raw`, or
+    ///   it only shows up after the next `open`.
+    ///
+    /// The bytes are flushed but not synced.
+    ///
+    /// # Errors
+    /// It propagates I/O errors during writing the log.
+    pub fn append_raw(&self, bytes: &[u8]) -> Result<CommandPosInfo> {
+        self.lock_writer()?.append_raw(bytes)
+    }
+
+    /// Points the index entry of `key` to a `Set` record written by
+    /// `append_raw`, or removes it if `record` is `None`.
+    ///
+    /// `record` must be the location of a single `Set` of `key` in a log,
+    /// reads of `key` fail otherwise. The replaced record counts as stale.
+    /// Nothing is written, a removal only lasts until the next `open` unless
+    /// a `Remove` record is appended too.
+    ///
+    /// # Errors
+    /// It returns `KvsError::StringError` with the split layout, where the
+    /// index refers to values instead of records.
+    /// It propagates I/O errors during reading the value for the value
+    /// prefix index.
+    pub fn index_raw(&self, key: String, record: Option<CommandPosInfo>) -> Result<()> {
+        self.lock_writer()?.index_raw(key, record)
+    }
+
+    /// Applies the operations all or nothing.
+    ///
+    /// The operations are bracketed by transaction markers in the log, and
+    /// only a transaction with its commit marker is replayed on open, so a
+    /// crash never leaves a part of it applied. The index is updated after
+    /// the commit marker is flushed.
+    ///
+    /// # Errors
+    /// It returns `KvsError::KeyNotFound` if a removed key is not found,
+    /// nothing is written in that case.
+    pub fn transaction(&self, ops: Vec<Op>) -> Result<()> {
+        self.lock_writer()?.transaction(ops)
+    }
+
+    /// Replaces the whole content of the store with `entries`, the last one
+    /// wins for a repeated key.
+    ///
+    /// The new content is written to a fresh generation under a temporary
+    /// name, with removals of the keys it drops, and persisted after a sync,
+    /// then the older generations are removed. A crash before the rename
+    /// leaves the old content, a crash after it replays the new generation
+    /// last, so it's always either the old or the new content, never a mix.
+    /// Readers of other clones see the keys switch one by one.
+    ///
+    /// # Errors
+    /// It returns `KvsError::RecordTooLarge` if a record is over
+    /// `max_record_len`, nothing is written in that case. It propagates I/O
+    /// or serialization errors during writing the log, the old content is
+    /// left then.
+    pub fn replace_all(
+        &mut self,
+        entries: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<()> {
+        self.lock_writer()?
+            .replace_all(entries.into_iter().collect())
+    }
+
+    /// Replaces the whole content of the store with the one of the store in
+    /// `backup_dir`, e.g. the files of a `checkpoint_barrier` copied there.
+    ///
+    /// The backup is checked first: it's opened read-only with the layout
+    /// of this store, and every value is read into memory. Any warning of
+    /// the replay, e.g. an unreadable record even at the end of the newest
+    /// log, fails the recovery before anything is written. The content is
+    /// then swapped in by `replace_all`, so a crash leaves either the old or
+    /// the recovered content, and `get` reflects the backup once it returns.
+    /// The backup isn't written to. Sequence numbers and previous versions
+    /// of the backup aren't kept.
+    ///
+    /// # Errors
+    /// It returns `KvsError::StringError` if `backup_dir` has no logs or the
+    /// replay warns. It propagates I/O or deserialization errors during
+    /// reading the backup and the errors of `replace_all`, the store is left
+    /// as it was then.
+    pub fn recover_from(&mut self, backup_dir: impl AsRef<Path>) -> Result<()> {
+        let backup_dir = backup_dir.as_ref();
+        if sorted_gen_list(backup_dir)?.is_empty() {
+            return Err(KvsError::StringError(format!(
+                "Backup {} has no logs",
+                backup_dir.display()
+            )));
         }
+        let options = KvStoreOptions {
+            split_values: self.options.split_values,
+            read_only: true,
+            ..Default::default()
+        };
+        let (backup, report) = KvStore::open_reporting(backup_dir.to_owned(), options)?;
+        if let Some(warning) = report.warnings.first() {
+            return Err(KvsError::StringError(format!(
+                "Backup {} is damaged: {}",
+                backup_dir.display(),
+                warning
+            )));
+        }
+        let entries = backup.into_iter().collect::<Result<BTreeMap<_, _>>>()?;
+        self.lock_writer()?.replace_all(entries)
     }
 
-    /// Remove a given key.
+    /// Compacts the store renaming every key to `f` of it, e.g. to add a
+    /// namespace prefix in a migration.
+    ///
+    /// It's a `replace_all` with the renamed keys: the live pairs are read
+    /// and written to a fresh generation, which is persisted atomically, so a
+    /// crash leaves either the old keys or the new ones. If `f` maps several
+    /// keys to the same one, the last of them in key order wins and the
+    /// collision is logged. The previous values of `retained_versions` are
+    /// dropped.
     ///
     /// # Errors
-    /// It returns `KvsError::KeyNotFound` if the given key is not found.
-    /// It propagates I/O or serialization errors during writing the log.
-    fn remove(&self, key: String) -> Result<()> {
-        self.writer.lock().unwrap().remove(key)
+    /// It propagates I/O or deserialization errors during reading the values
+    /// and the errors of `replace_all`, the old keys are left then.
+    pub fn compact_with_key_transform(&mut self, f: impl Fn(&str) -> String) -> Result<()> {
+        self.lock_writer()?.compact_with_key_transform(f)
     }
-}
 
-/// A reader for a single thread.
-///
-/// Each `KvStore` instance has its own `ReadAgent` and `ReadAgent`s open the
-/// same files separately. It's lazy on opening files, and after WriteAgent
-/// compacts, reader should close stale files,  `Send + !Sync`
-struct ReadAgent {
-    path: PathBuf,
-    // first generation availble, changes due to compaction
-    first_gen: Arc<AtomicU64>,
-    // map gen to file reader, use interior mutability due to accessing from
-    // multiple places in same thread (we're `Send` but not `Sync`)
-    readers: RefCell<ReaderMap>,
-}
-impl Clone for ReadAgent {
-    fn clone(&self) -> Self {
-        Self {
-            path: self.path.clone(),
-            first_gen: self.first_gen.clone(),
-            readers: RefCell::new(ReaderMap::new()), // each instance maintains a unique map of readers
+    /// Gets the value of `key` from `versions_ago` writes before, 0 for the
+    /// current one like `get`.
+    ///
+    /// Up to `retained_versions` previous values are kept for each key, an
+    /// older one or one of a removed key is `None`.
+    ///
+    /// # Errors
+    /// It returns `KvsError::StringError` if `retained_versions` is 0.
+    pub fn get_version(&mut self, key: String, versions_ago: usize) -> Result<Option<String>> {
+        let versions = match &self.versions {
+            Some(versions) => versions,
+            None => {
+                return Err(KvsError::StringError(
+                    "Versions are not retained".to_owned(),
+                ))
+            }
+        };
+        if versions_ago == 0 {
+            return self.get(key);
+        }
+        match versions.get(&key, versions_ago) {
+            Some(cmd_pos) => self.reader.read_value(&key, &cmd_pos).map(Some),
+            None => Ok(None),
         }
     }
-}
-impl ReadAgent {
-    /// Close file handles with generation number less than first_gen.
+
+    /// Gets the string value of a given string key like `get`, but leaves
+    /// any read statistics or caches untouched.
     ///
-    /// `first_gen` is updated to the latest compaction gen after a compaction finishes.
-    /// The compaction generation contains the sum of all operations before it and the
-    /// in-memory index contains no entries with generation number less than it.
-    /// So we can safely close those file handles and the stale files can be deleted.
-    fn close_stale_files(&self) {
-        let gen = self.first_gen.load(Ordering::SeqCst);
-        self.readers.replace_with(|cur| cur.split_off(&gen));
+    /// It's meant for administrative reads which shouldn't skew the metrics.
+    ///
+    /// # Errors
+    /// It returns `KvsError::UnexpectedCommandType` if the given command type unexpected.
+    pub fn peek(&self, key: String) -> Result<Option<String>> {
+        match self.lookup(&key) {
+            Some(cmd_pos) => self.reader.read_value(&key, &cmd_pos).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Gets the string value of a given string key into `buf`.
+    ///
+    /// `buf` is cleared first, returns whether the key exists. Reusing `buf`
+    /// avoids allocating a new `String` for every read.
+    ///
+    /// # Errors
+    /// It returns `KvsError::UnexpectedCommandType` if the given command type unexpected.
+    pub fn get_into(&self, key: String, buf: &mut String) -> Result<bool> {
+        buf.clear();
+        match self.lookup(&key) {
+            Some(cmd_pos) => {
+                if let Some(recency) = &self.recency {
+                    recency.touch(&key);
+                }
+                if let Some(access) = &self.access {
+                    access.touch(&key);
+                }
+                self.reader.read_value_into(&key, &cmd_pos, buf)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Gets the string value of a given string key like `get_into`, into a
+    /// buffer of the store reused across calls.
+    ///
+    /// The value returned is only valid until the next call: the buffer is
+    /// overwritten then, which the borrow of `self` enforces. Each clone of
+    /// the store has its own buffer, it keeps the capacity of the largest
+    /// value read, so a tight loop reads without any allocation.
+    ///
+    /// # Errors
+    /// It returns `KvsError::UnexpectedCommandType` if the given command type unexpected.
+    pub fn get_buffered(&mut self, key: String) -> Result<Option<&str>> {
+        let mut buf = std::mem::take(&mut self.value_buf);
+        let found = self.get_into(key, &mut buf);
+        self.value_buf = buf;
+        Ok(found?.then_some(self.value_buf.as_str()))
+    }
+
+    /// Gets the string value of a given string key along with its location.
+    ///
+    /// The location changes whenever the key is written or moved by a
+    /// compaction, so a caching layer can keep it and compare it with
+    /// `position` to tell if its copy might be stale. Only
+    /// `normalize_generations` can bring back a location seen before, a
+    /// cache has to be dropped after it.
+    ///
+    /// # Errors
+    /// It returns `KvsError::UnexpectedCommandType` if the given command type unexpected.
+    pub fn get_versioned(&self, key: String) -> Result<Option<(String, CommandPosInfo)>> {
+        let cmd_pos = match self.lookup(&key) {
+            Some(cmd_pos) => cmd_pos,
+            None => return Ok(None),
+        };
+        if let Some(recency) = &self.recency {
+            recency.touch(&key);
+        }
+        if let Some(access) = &self.access {
+            access.touch(&key);
+        }
+        let value = self.reader.read_value(&key, &cmd_pos)?;
+        Ok(Some((value, cmd_pos.into())))
+    }
+
+    /// Returns a read-only handle sharing the index, for reads from other
+    /// threads while this handle writes, see `KvReader`.
+    ///
+    /// Unlike a clone of the store, it can't write, so the writer stays with
+    /// the handles of the store.
+    pub fn reader(&self) -> KvReader {
+        KvReader {
+            index: self.index.clone(),
+            reader: self.reader.clone(),
+            recency: self.recency.clone(),
+            access: self.access.clone(),
+            cache: self.cache.clone(),
+            filters: self.filters.clone(),
+        }
+    }
+
+    /// Returns the current location of the value of `key`, without reading
+    /// it, see `get_versioned`.
+    pub fn position(&self, key: &str) -> Option<CommandPosInfo> {
+        self.lookup(key).map(CommandPosInfo::from)
+    }
+
+    /// Returns when `key` was last read by a `get` or written, by the
+    /// `clock` of the options.
+    ///
+    /// It's `None` without `track_access_times`, or if the key isn't accessed
+    /// since `open` or is removed. Reads of any clone and of `KvReader`s
+    /// count, `peek` and scans don't.
+    pub fn last_access(&self, key: &str) -> Option<SystemTime> {
+        let ms = self.access.as_ref()?.get(key)?;
+        Some(UNIX_EPOCH + Duration::from_millis(ms))
+    }
+
+    /// Returns the bytes of the record of `key` as they were written, e.g. to
+    /// forward them to a CDC pipeline. Nothing is deserialized.
+    ///
+    /// It's the JSON of the `Set` command, pretty-printed if it was written
+    /// so. With the split layout, it's the value as stored in the value log.
+    /// It doesn't count as a use for `max_bytes`.
+    ///
+    /// # Errors
+    /// It propagates I/O errors during reading the log, or an
+    /// `UnexpectedEof` if the log is shorter than the record.
+    pub fn raw_record(&mut self, key: String) -> Result<Option<Vec<u8>>> {
+        let cmd_pos = match self.lookup(&key) {
+            Some(cmd_pos) => cmd_pos,
+            None => return Ok(None),
+        };
+        let bytes = self.reader.read_and(&cmd_pos, |mut rdr| {
+            let mut bytes = Vec::with_capacity(cmd_pos.len as usize);
+            rdr.read_to_end(&mut bytes)?;
+            Ok(bytes)
+        })?;
+        if bytes.len() as u64 != cmd_pos.len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(Some(bytes))
+    }
+
+    /// The position of the command of `key`, the bloom filters are checked
+    /// first if enabled.
+    fn lookup(&self, key: &str) -> Option<CommandPos> {
+        if let Some(filters) = &self.filters {
+            if !filters.may_contain(key) {
+                return None;
+            }
+        }
+        self.index.get(key).map(|cmd_pos| *cmd_pos)
+    }
+
+    /// Returns the keys whose values start with `prefix`, sorted.
+    ///
+    /// It's answered by the index of `value_prefix_len`. Values are only read
+    /// for a `prefix` longer than the indexed one, to check the rest. The
+    /// index is updated right before the key index by writes, so a
+    /// concurrent write might show up a bit early.
+    ///
+    /// # Errors
+    /// It returns `KvsError::StringError` if `value_prefix_len` is off.
+    /// It propagates I/O errors during reading the values.
+    pub fn keys_with_value_prefix(&self, prefix: &str) -> Result<Vec<String>> {
+        let prefixes = match &self.prefixes {
+            Some(prefixes) => prefixes,
+            None => {
+                return Err(KvsError::StringError(
+                    "The value prefix index is off".to_owned(),
+                ))
+            }
+        };
+        let candidates = prefixes.candidates(prefix);
+        if !prefixes.is_partial(prefix) {
+            return Ok(candidates);
+        }
+        let mut keys = Vec::new();
+        for key in candidates {
+            if let Some(value) = self.peek(key.clone())? {
+                if value.starts_with(prefix) {
+                    keys.push(key);
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Gets the value of a given string key parsed into `T`.
+    ///
+    /// It only works for values stored as strings, e.g. numbers or booleans
+    /// set with `to_string`.
+    ///
+    /// # Errors
+    /// It returns `KvsError::Parse` if the value can't be parsed.
+    pub fn get_parsed<T>(&self, key: String) -> Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        match self.get(key.clone())? {
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|err: T::Err| KvsError::Parse {
+                    key,
+                    reason: err.to_string(),
+                }),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets the value of a string key like `set`, but borrows the key and the
+    /// value.
+    ///
+    /// The command is serialized right from the borrowed strings. The index
+    /// still owns a copy of the key, so only the allocation of the value is
+    /// saved.
+    ///
+    /// # Errors
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn set_ref(&self, key: &str, value: &str) -> Result<()> {
+        self.lock_writer()?.set(key.to_owned(), value)
+    }
+
+    /// Sets the value of a string key only if the key does not exist.
+    ///
+    /// Returns whether the value is stored, nothing is written to the log
+    /// for an existing key.
+    ///
+    /// # Errors
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        let mut writer = self.lock_writer()?;
+        if self.index.contains_key(&key) {
+            return Ok(false);
+        }
+        writer.set(key, &value)?;
+        Ok(true)
+    }
+
+    /// Sets the value of a string key only if it differs from the current
+    /// one.
+    ///
+    /// Returns whether the value is written. Rewriting an unchanged value
+    /// only grows the log and brings compaction closer, but checking reads
+    /// the current value, a seek and a read on every call. It pays off when
+    /// values are mostly rewritten as they are, e.g. a periodic re-sync.
+    ///
+    /// # Errors
+    /// It propagates I/O or serialization errors during reading the current
+    /// value or writing the log.
+    pub fn set_if_changed(&self, key: String, value: String) -> Result<bool> {
+        let mut writer = self.lock_writer()?;
+        let cmd_pos = self.index.get(&key).map(|cmd_pos| *cmd_pos);
+        if let Some(cmd_pos) = cmd_pos {
+            if self.reader.read_value(&key, &cmd_pos)? == value {
+                return Ok(false);
+            }
+        }
+        writer.set(key, &value)?;
+        Ok(true)
+    }
+
+    /// Renames the key `from` to `to`.
+    ///
+    /// An existing `to` is overwritten only if `overwrite` is set. The new key
+    /// is written before the old one is removed, so a crash in between leaves
+    /// both keys rather than none.
+    ///
+    /// # Errors
+    /// It returns `KvsError::KeyNotFound` if `from` is not found, or
+    /// `KvsError::KeyExists` if `to` exists and `overwrite` is not set.
+    pub fn rename(&self, from: String, to: String, overwrite: bool) -> Result<()> {
+        self.lock_writer()?.rename(from, to, overwrite)
+    }
+
+    /// Swaps the values of the keys `a` and `b`.
+    ///
+    /// Both sets are written as a transaction, see `transaction`. A crash
+    /// before its commit marker is flushed leaves both values as they were,
+    /// one after it leaves both swapped, never only one.
+    ///
+    /// # Errors
+    /// It returns `KvsError::KeyNotFound` if either key is not found, nothing
+    /// is written in that case.
+    pub fn swap(&self, a: String, b: String) -> Result<()> {
+        self.lock_writer()?.swap(a, b)
+    }
+
+    /// Returns the immediate children of `prefix` in the key hierarchy,
+    /// sorted and deduplicated, like listing a directory.
+    ///
+    /// A child is the part of a key after `prefix` up to the next
+    /// `key_separator`, so with `:` the keys `a:b:c` and `a:b:d:e` are the
+    /// children `c` and `d` of `a:b:`. A missing trailing separator is
+    /// added, `a:b` lists the same children and not the ones of `a:bc`. An
+    /// empty `prefix` lists the first segments. A key equal to the prefix
+    /// with its separator, e.g. `a:b:`, isn't a child, and a doubled
+    /// separator, e.g. in `a:b::c`, gives an empty child. It only reads the
+    /// in-memory index.
+    pub fn list_children(&self, prefix: &str) -> Vec<String> {
+        let separator = self.options.key_separator;
+        let mut prefix = prefix.to_owned();
+        if !prefix.is_empty() && !prefix.ends_with(separator) {
+            prefix.push(separator);
+        }
+        let children: BTreeSet<String> = self
+            .index
+            .iter()
+            .filter_map(|entry| {
+                let rest = entry.key().strip_prefix(&prefix)?;
+                if rest.is_empty() {
+                    return None;
+                }
+                Some(rest.split(separator).next().unwrap_or(rest).to_owned())
+            })
+            .collect();
+        children.into_iter().collect()
+    }
+
+    /// Returns an iterator over all the values in key order.
+    ///
+    /// The keys are snapshotted when it's called and each value is read from
+    /// the log when it's reached, a key removed by then is skipped. A value
+    /// failing to read is yielded as an `Err` and the iteration goes on.
+    pub fn values(&self) -> impl Iterator<Item = Result<String>> + '_ {
+        let mut keys: Vec<String> = self.index.iter().map(|entry| entry.key().clone()).collect();
+        keys.sort_unstable();
+        keys.into_iter().filter_map(move |key| {
+            let cmd_pos = *self.index.get(&key)?;
+            Some(self.reader.read_value(&key, &cmd_pos))
+        })
+    }
+
+    /// Returns a hash of the live content, to compare stores, e.g. a primary
+    /// and a replica.
+    ///
+    /// It's 64-bit FNV-1a over every live pair in key order, each as the
+    /// length of the key as 8 little-endian bytes, the key, then the length
+    /// and the bytes of the value likewise. Stale records aren't covered, so
+    /// it's the same however the content is written and whether it's
+    /// compacted or not. Writes wait till it's done, every value is read.
+    ///
+    /// # Errors
+    /// It propagates I/O or deserialization errors during reading the values.
+    pub fn content_hash(&mut self) -> Result<u64> {
+        let _writer = self.writer.lock().unwrap();
+        let mut entries: Vec<(String, CommandPos)> = self
+            .index
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        let mut hash = Fnv1a::new();
+        for (key, cmd_pos) in entries {
+            let value = self.reader.read_value(&key, &cmd_pos)?;
+            for bytes in [key.as_bytes(), value.as_bytes()] {
+                hash.update(&(bytes.len() as u64).to_le_bytes());
+                hash.update(bytes);
+            }
+        }
+        Ok(hash.0)
+    }
+
+    /// Writes every live pair as a `key,value` CSV row in key order, e.g.
+    /// for spreadsheets. There's no header row.
+    ///
+    /// A key or value with a comma, a quote or a line break is quoted, its
+    /// quotes doubled, as in RFC 4180. The keys are snapshotted when it's
+    /// called and each value is read and written in turn, a key removed by
+    /// then is skipped.
+    ///
+    /// # Errors
+    /// It propagates errors during reading the values or writing to `w`.
+    pub fn export_csv(&mut self, w: &mut impl Write) -> Result<()> {
+        let mut keys: Vec<String> = self.index.iter().map(|entry| entry.key().clone()).collect();
+        keys.sort_unstable();
+        for key in keys {
+            let cmd_pos = match self.index.get(&key) {
+                Some(cmd_pos) => *cmd_pos,
+                None => continue,
+            };
+            let value = self.reader.read_value(&key, &cmd_pos)?;
+            csv::write_row(w, &[&key, &value])?;
+        }
+        Ok(())
+    }
+
+    /// Sets each `key,value` row of a CSV like the ones of `export_csv`,
+    /// returns how many.
+    ///
+    /// Each row is a separate `set`, the rows before a failing one stay set.
+    ///
+    /// # Errors
+    /// It returns `KvsError::StringError` for a malformed row or one without
+    /// exactly two fields, and propagates the errors of reading `r` and of
+    /// `set`.
+    pub fn import_csv(&self, r: impl BufRead) -> Result<usize> {
+        let mut reader = CsvReader::new(r);
+        let mut count = 0;
+        while let Some(row) = reader.next_row()? {
+            let [key, value]: [String; 2] = row.try_into().map_err(|row: Vec<String>| {
+                KvsError::StringError(format!(
+                    "CSV line {}: expected 2 fields, found {}",
+                    reader.line_no(),
+                    row.len()
+                ))
+            })?;
+            self.set(key, value)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Compares the pairs of `src` with the store as an import would set
+    /// them, nothing is written.
+    ///
+    /// `src` is e.g. another store, which yields its pairs, or the rows of
+    /// a CSV, see `import_csv_preview`. A key repeated in `src` counts once
+    /// with its last value, as it's left by the import. The values of the
+    /// keys already in the store are read to tell the identical ones.
+    ///
+    /// # Errors
+    /// It propagates the errors of `src` and the I/O or deserialization
+    /// errors during reading the values.
+    pub fn import_preview(
+        &self,
+        src: impl IntoIterator<Item = Result<(String, String)>>,
+    ) -> Result<ImportPreview> {
+        let entries = src.into_iter().collect::<Result<BTreeMap<_, _>>>()?;
+        let mut preview = ImportPreview::default();
+        for (key, value) in entries {
+            let cmd_pos = match self.index.get(&key) {
+                Some(cmd_pos) => *cmd_pos,
+                None => {
+                    preview.new_keys += 1;
+                    continue;
+                }
+            };
+            if self.reader.read_value(&key, &cmd_pos)? == value {
+                preview.identical += 1;
+            } else {
+                preview.overwritten += 1;
+                if preview.overwritten_sample.len() < IMPORT_PREVIEW_SAMPLE {
+                    preview.overwritten_sample.push(key);
+                }
+            }
+        }
+        Ok(preview)
+    }
+
+    /// Previews `import_csv` with the rows of `r`, see `import_preview`.
+    ///
+    /// # Errors
+    /// It returns `KvsError::StringError` for a malformed row or one without
+    /// exactly two fields, and propagates the errors of reading `r` and the
+    /// values.
+    pub fn import_csv_preview(&self, r: impl BufRead) -> Result<ImportPreview> {
+        let mut reader = CsvReader::new(r);
+        let rows = std::iter::from_fn(|| {
+            let row = match reader.next_row() {
+                Ok(row) => row?,
+                Err(err) => return Some(Err(err)),
+            };
+            Some(row.try_into().map_err(|row: Vec<String>| {
+                KvsError::StringError(format!(
+                    "CSV line {}: expected 2 fields, found {}",
+                    reader.line_no(),
+                    row.len()
+                ))
+            }))
+        });
+        self.import_preview(rows.map(|row| row.map(|[key, value]: [String; 2]| (key, value))))
+    }
+
+    /// Renumbers the generations to be contiguous from 1.
+    ///
+    /// Compaction leaves gaps in the generation sequence over time, which
+    /// complicates tooling shipping the log files. Other clones of the store
+    /// might fail reads with a missing file error while it's running.
+    ///
+    /// # Errors
+    /// It propagates I/O errors during renaming the log files.
+    pub fn normalize_generations(&self) -> Result<()> {
+        self.lock_writer()?.normalize_generations()
+    }
+
+    /// Returns the sequence number of the last command written, 0 if none.
+    ///
+    /// Each set or removal gets the next number, it's persisted in the record
+    /// and recovered on `open`. Compaction keeps the numbers of the records
+    /// it copies, records it writes anew get the current number. Removals
+    /// dropped by a compaction don't count, so the number goes back if the
+    /// newest commands are all dropped removals. Logs written before
+    /// sequence numbers have 0.
+    pub fn current_seq(&self) -> u64 {
+        self.writer.lock().unwrap().seq
+    }
+
+    /// Returns the `(generation, offset)` of the active log up to which the
+    /// commands are flushed.
+    ///
+    /// Every write flushes before returning, so it's the end of the last
+    /// successful one. Flushed data survives a crash of the process but not
+    /// of the machine unless the log is synced. It only reads the tracked
+    /// position, no system call is made.
+    pub fn durable_offset(&self) -> (u64, u64) {
+        let writer = self.writer.lock().unwrap();
+        (writer.current_gen, writer.writer.flushed_pos())
+    }
+
+    /// Follows the logs from `from_offset` of generation `from_gen` as they
+    /// grow, e.g. to replicate the store.
+    ///
+    /// An offset of 0 starts from the first record of the log, and
+    /// `durable_offset` is where the commands written so far end. The
+    /// tailer reads the storage on its own, see `LogTailer`. A compaction
+    /// shows up as a `TailRecord::Reset` followed by every live record.
+    ///
+    /// # Errors
+    /// It returns `KvsError::MissingGeneration` if there's no log of
+    /// `from_gen`.
+    pub fn tail(&self, from_gen: u64, from_offset: u64) -> Result<LogTailer> {
+        let storage = self.writer.lock().unwrap().storage.clone();
+        LogTailer::new(storage, from_gen, from_offset)
+    }
+
+    /// Returns the hits and the size of the value cache, `None` if
+    /// `cache_values` is off.
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(|cache| cache.stats())
+    }
+
+    /// Returns the number of bytes a compaction could reclaim right now.
+    pub fn stale_bytes(&self) -> u64 {
+        self.writer.lock().unwrap().stale_bytes
+    }
+
+    /// Returns whether the compaction policy calls for a compaction, more
+    /// stale bytes than `compaction_threshold` or more generations than
+    /// `max_generations`.
+    ///
+    /// Writes compact by themselves when they cross it, but removals don't,
+    /// so it's a cheap check for a scheduler before calling
+    /// `compact_if_worthwhile`. It only reads in-memory counters.
+    pub fn needs_compaction(&self) -> bool {
+        self.writer.lock().unwrap().needs_compaction()
+    }
+
+    /// Returns the number of bytes of the live records, the ones `max_bytes`
+    /// bounds.
+    pub fn live_bytes(&self) -> u64 {
+        self.writer.lock().unwrap().live_bytes
+    }
+
+    /// Returns the bytes written to the logs per byte written by sets and
+    /// removals since opening, 1.0 before any of them.
+    ///
+    /// The bytes rewritten by compactions, merges and purges of tombstones
+    /// count on top of the ones of the writes. It only reads in-memory
+    /// counters.
+    pub fn write_amplification(&self) -> f64 {
+        let writer = self.writer.lock().unwrap();
+        if writer.user_bytes == 0 {
+            return 1.0;
+        }
+        writer.written_bytes as f64 / writer.user_bytes as f64
+    }
+
+    /// Compacts if the compaction policy calls for it, see
+    /// `needs_compaction`, returns whether it ran.
+    ///
+    /// It's meant for a timer of the application, so the stale bytes of a
+    /// store going quiet after removals are reclaimed, as writes only compact
+    /// when they cross the threshold. The check and the compaction happen
+    /// under the writer lock, so it can be called from any thread.
+    /// `KvStoreOptions::compaction_interval` runs it on a thread of the store.
+    ///
+    /// # Errors
+    /// It propagates I/O or serialization errors during writing the logs.
+    pub fn maybe_compact(&self) -> Result<bool> {
+        let mut writer = self.lock_writer()?;
+        if !writer.needs_compaction() {
+            return Ok(false);
+        }
+        writer.compact()?;
+        Ok(true)
+    }
+
+    /// Compacts only if at least `min_savings` bytes are stale, returns
+    /// whether it ran.
+    ///
+    /// The check and the compaction happen under the writer lock, so no
+    /// write slips in between.
+    ///
+    /// # Errors
+    /// It propagates I/O or serialization errors during writing the logs.
+    pub fn compact_if_worthwhile(&self, min_savings: u64) -> Result<bool> {
+        let mut writer = self.lock_writer()?;
+        if writer.stale_bytes < min_savings {
+            return Ok(false);
+        }
+        writer.compact()?;
+        Ok(true)
+    }
+
+    /// Compacts like a write crossing the threshold does, giving up with
+    /// `KvsError::Cancelled` once `cancel` is cancelled, e.g. by a timeout.
+    ///
+    /// The token is checked before starting and between the copies of the
+    /// live records. A cancelled compaction persists nothing, the store goes
+    /// on with the old generations plus a new active one, and the stale
+    /// bytes are reclaimed by a later compaction. Once the records are copied
+    /// it runs to the end.
+    ///
+    /// # Errors
+    /// It propagates I/O or serialization errors during writing the logs.
+    pub fn compact_cancellable(&self, cancel: &CancellationToken) -> Result<()> {
+        let mut writer = self.lock_writer()?;
+        cancel.check()?;
+        let compaction_gen = writer.current_gen + 1;
+        writer.compact_dropping(compaction_gen, &HashSet::new(), Some(cancel))
+    }
+
+    /// Estimates what a compaction would do right now, nothing is written.
+    ///
+    /// It only reads the index and the tracked counters.
+    pub fn compaction_preview(&self) -> CompactionPreview {
+        let writer = self.writer.lock().unwrap();
+        CompactionPreview {
+            reclaimable_bytes: writer.stale_bytes,
+            live_entries: self.index.len(),
+            output_bytes: FORMAT_HEADER_LEN + writer.live_bytes,
+        }
+    }
+
+    /// Changes the stale bytes above which a write triggers a compaction.
+    ///
+    /// If more bytes are stale than the new threshold already, it compacts
+    /// right away. A very low threshold makes nearly every write compact.
+    ///
+    /// # Errors
+    /// It propagates I/O or serialization errors during the compaction.
+    pub fn set_compaction_threshold(&self, bytes: u64) -> Result<()> {
+        let mut writer = self.lock_writer()?;
+        writer.compaction_threshold = bytes;
+        writer.update_manifest();
+        if writer.needs_compaction() {
+            writer.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Removes every key starting with `prefix`, returns how many.
+    ///
+    /// Instead of a removal for each key, it compacts the store leaving the
+    /// keys out, so the logs shrink rather than grow. The new generation
+    /// starts with a marker making the older ones obsolete on replay, so a
+    /// crash before they're removed doesn't bring the keys back, and a crash
+    /// before it's persisted leaves all of them. Nothing is written if no key
+    /// matches.
+    ///
+    /// # Errors
+    /// It propagates I/O or serialization errors during the compaction.
+    pub fn delete_prefix(&mut self, prefix: &str) -> Result<usize> {
+        self.lock_writer()?.delete_prefix(prefix)
+    }
+
+    /// Removes every key starting with `prefix` like `delete_prefix`,
+    /// returns their pairs in key order, e.g. to consume a batch of work
+    /// items.
+    ///
+    /// The values are read and the keys removed under the writer lock, so no
+    /// write of another clone slips in between. If a value fails to read,
+    /// nothing is removed. The removal is all or nothing as for
+    /// `delete_prefix`, a crash leaves either all the keys or none of them.
+    ///
+    /// # Errors
+    /// It propagates I/O or deserialization errors during reading the values
+    /// and the errors of the compaction.
+    pub fn drain_prefix(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.lock_writer()?.drain_prefix(prefix)
+    }
+
+    /// Compacts into the generation `target_gen` instead of the one after
+    /// the active generation, `target_gen + 1` becomes the active one.
+    ///
+    /// Test-only, it's available with the `test-util` feature.
+    ///
+    /// # Errors
+    /// It returns `KvsError::InvalidGeneration` if `target_gen` is not newer
+    /// than the active generation or either generation has a file already.
+    #[cfg(feature = "test-util")]
+    pub fn compact_into(&self, target_gen: u64) -> Result<()> {
+        let mut writer = self.lock_writer()?;
+        if target_gen <= writer.current_gen {
+            return Err(KvsError::InvalidGeneration { gen: target_gen });
+        }
+        for gen in [target_gen, target_gen + 1] {
+            for kind in [LogKind::Log, LogKind::ValueLog] {
+                if writer.storage.len(gen, kind)?.is_some() {
+                    return Err(KvsError::InvalidGeneration { gen });
+                }
+            }
+        }
+        writer.compact_into(target_gen)
+    }
+
+    /// Sets a key like `set` after moving to a new generation, so a test can
+    /// lay out a store with its keys spread across many generations.
+    ///
+    /// Test-only, it's available with the `test-util` feature.
+    ///
+    /// # Errors
+    /// It propagates I/O errors during creating the log and the errors of
+    /// `set`.
+    #[cfg(feature = "test-util")]
+    pub fn set_in_new_generation(&mut self, key: String, value: String) -> Result<()> {
+        let mut writer = self.lock_writer()?;
+        writer.rotate()?;
+        writer.set(key, &value)
+    }
+
+    /// Overrides the stale bytes counter, so that the next write triggers a
+    /// compaction without writing megabytes of data first.
+    ///
+    /// Test-only, it's available with the `test-util` feature.
+    #[cfg(feature = "test-util")]
+    pub fn set_stale_bytes(&self, stale_bytes: u64) {
+        self.writer.lock().unwrap().stale_bytes = stale_bytes;
+    }
+
+    /// Merges pairs of adjacent generations instead of rewriting the whole
+    /// store, returns the number of bytes reclaimed.
+    ///
+    /// Of the adjacent pairs where the larger generation is at most 4 times
+    /// the smaller one, the smallest pair is merged first, until no pair is
+    /// left. Generations of similar sizes are merged into bigger ones over
+    /// time, so each record is rewritten only a few times. The merged
+    /// generation replaces the older one of the pair, written under a
+    /// temporary name persisted after a sync, so the logs are replayed in
+    /// the same order. It keeps the removals of keys that older generations
+    /// might still set. The active generation is left untouched. Other
+    /// clones of the store might fail reads while it's running.
+    ///
+    /// # Errors
+    /// It propagates I/O or serialization errors during writing the logs.
+    pub fn compact_tiered(&self) -> Result<u64> {
+        self.lock_writer()?.compact_tiered()
+    }
+
+    /// Drops the removals of keys which are never set before them from the
+    /// logs, returns the number of bytes freed.
+    ///
+    /// Such a removal has nothing to hide on replay, so it's safe to drop
+    /// without a full compaction. Only the affected logs are rewritten, each
+    /// under a temporary name persisted after a sync. The active log is left
+    /// untouched. Other clones of the store might fail reads while it's
+    /// running.
+    ///
+    /// # Errors
+    /// It propagates I/O or deserialization errors during rewriting the logs.
+    pub fn purge_tombstones(&self) -> Result<u64> {
+        self.lock_writer()?.purge_tombstones()
+    }
+
+    /// Rolls the store back to the sequence number `seq`, discarding every
+    /// record written after it, see `current_seq`.
+    ///
+    /// The log holding the first record after `seq` is truncated right
+    /// before it, or before its transaction, and the newer generations are
+    /// removed, whether it's the active log or a sealed one. Then the index
+    /// is rebuilt like `rebuild_index` and writes go on in a new generation.
+    /// Nothing is done if no record is after `seq`.
+    ///
+    /// The logs are streamed, the records from the first one after `seq` on
+    /// are only checked to be after it too. The truncated log is persisted
+    /// under a temporary name before the newer generations are removed,
+    /// newest first, so rolling back again after a crash midway finishes
+    /// the job.
+    ///
+    /// It's destructive: the discarded records are gone for good, there's
+    /// no rolling forward again. Neither can it bring back what a compaction
+    /// since `seq` dropped: the values overwritten after `seq` and compacted
+    /// away stay gone. Other clones of the store might fail reads while it's
+    /// running.
+    ///
+    /// # Errors
+    /// It returns `KvsError::SeqOutOfOrder` if a record up to `seq` comes
+    /// after one past it, and propagates I/O or deserialization errors
+    /// during rewriting the logs and the replay.
+    pub fn rollback_to_seq(&mut self, seq: u64) -> Result<()> {
+        self.lock_writer()?.rollback_to_seq(seq)
+    }
+
+    /// Returns the sorted keys with removals in the logs which are not live,
+    /// the tombstones a compaction would reclaim. `purge_tombstones` only
+    /// reclaims the ones of keys never set before.
+    ///
+    /// It scans the logs on disk, so it reflects what is stored rather than
+    /// the in-memory counters. Nothing is written.
+    ///
+    /// # Errors
+    /// It propagates I/O or deserialization errors during reading the logs.
+    pub fn pending_tombstones(&self) -> Result<Vec<String>> {
+        let writer = self.writer.lock().unwrap();
+        let mut keys = BTreeSet::new();
+        for gen in writer.storage.list_generations()? {
+            let mut reader = BufReader::new(writer.storage.open(gen, LogKind::Log)?);
+            read_format_version(&mut reader)?;
+            for cmd in Deserializer::from_reader(reader).into_iter::<ReplayCommand>() {
+                if let Command::Remove { key, .. } | Command::SoftRemove { key, .. } = cmd? {
+                    if !self.index.contains_key(&key) {
+                        keys.insert(key);
+                    }
+                }
+            }
+        }
+        Ok(keys.into_iter().collect())
+    }
+
+    /// Returns the recent compactions.
+    pub fn compaction_history(&self) -> CompactionHistory {
+        self.writer.lock().unwrap().history.clone()
+    }
+
+    /// Whether strict mode detected an inconsistency between the index and
+    /// the logs, writes are refused since.
+    pub fn is_poisoned(&self) -> bool {
+        self.reader.is_poisoned()
+    }
+
+    /// Lock the writer, unless strict mode refuses writes.
+    fn lock_writer(&self) -> Result<MutexGuard<'_, WriteAgent>> {
+        if self.options.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        if self.reader.is_poisoned() {
+            return Err(KvsError::Poisoned);
+        }
+        Ok(self.writer.lock().unwrap())
+    }
+
+    /// Checks that the store is operational, e.g. for liveness probes.
+    ///
+    /// The writer is flushed and the header of the active log is read back.
+    /// Nothing is written, so the data and the counters are left untouched.
+    ///
+    /// # Errors
+    /// It returns `KvsError::Poisoned` if strict mode refuses writes, or
+    /// `KvsError::UnsupportedFormat` if the active log has an unexpected
+    /// header. It propagates I/O errors of the writer or the log.
+    pub fn health_check(&self) -> Result<()> {
+        let mut writer = self.lock_writer()?;
+        writer.writer.flush()?;
+        let mut reader = BufReader::new(writer.storage.open(writer.current_gen, LogKind::Log)?);
+        match read_format_version(&mut reader)? {
+            KvStore::FORMAT_VERSION => Ok(()),
+            found => Err(KvsError::UnsupportedFormat {
+                found,
+                supported: KvStore::FORMAT_VERSION,
+            }),
+        }
+    }
+
+    /// Persist the whole index to the checkpoint file right now.
+    ///
+    /// It does nothing if checkpointing is not enabled.
+    pub fn checkpoint(&self) -> Result<()> {
+        self.writer.lock().unwrap().checkpoint()
+    }
+
+    /// Seals the active generation and moves writes to a new one, returns
+    /// the sealed generations.
+    ///
+    /// Everything written before is synced to the sealed logs, which are
+    /// never written again, so copying their files while the store goes on
+    /// gives a consistent snapshot to open. A compaction removes them, so
+    /// they have to be copied before the next one, which might come with
+    /// the next write if `max_generations` is hit.
+    ///
+    /// # Errors
+    /// It propagates I/O errors during syncing or creating the logs.
+    pub fn checkpoint_barrier(&self) -> Result<CheckpointId> {
+        self.lock_writer()?.checkpoint_barrier()
+    }
+}
+
+/// A reader for a single thread.
+///
+/// Each `KvStore` instance has its own `ReadAgent` and `ReadAgent`s open the
+/// same files separately. It's lazy on opening files, and after WriteAgent
+/// compacts, reader should close stale files,  `Send + !Sync`
+struct ReadAgent {
+    storage: Arc<dyn LogStorage>,
+    // read values from the value logs instead of the logs
+    split_values: bool,
+    // first generation availble, changes due to compaction
+    first_gen: Arc<AtomicU64>,
+    // bumped when generations are renumbered, all file handles are closed
+    // once a reader sees a new epoch
+    epoch: Arc<AtomicU64>,
+    seen_epoch: Cell<u64>,
+    // map gen to file reader, use interior mutability due to accessing from
+    // multiple places in same thread (we're `Send` but not `Sync`)
+    readers: RefCell<ReaderMap>,
+    // the reader of the newest generation read, kept out of `readers` so
+    // reads right after writes skip the map
+    newest: RefCell<Option<(u64, LogBufReader)>>,
+    // reused by reads into caller's buffers
+    scratch: RefCell<Vec<u8>>,
+    // set in strict mode once a read finds an unexpected command
+    poisoned: Option<Arc<AtomicBool>>,
+    // mapped logs values are read from, if enabled
+    #[cfg(all(feature = "mmap", unix))]
+    mapped: Option<Mappings>,
+}
+impl Clone for ReadAgent {
+    fn clone(&self) -> Self {
+        Self {
+            storage: self.storage.clone(),
+            split_values: self.split_values,
+            first_gen: self.first_gen.clone(),
+            epoch: self.epoch.clone(),
+            seen_epoch: Cell::new(self.epoch.load(Ordering::SeqCst)),
+            readers: RefCell::new(ReaderMap::new()), // each instance maintains a unique map of readers
+            newest: RefCell::new(None),
+            scratch: RefCell::new(Vec::new()),
+            poisoned: self.poisoned.clone(),
+            #[cfg(all(feature = "mmap", unix))]
+            mapped: self.mapped.clone(),
+        }
+    }
+}
+impl ReadAgent {
+    fn is_poisoned(&self) -> bool {
+        self.poisoned
+            .as_ref()
+            .is_some_and(|poisoned| poisoned.load(Ordering::SeqCst))
+    }
+
+    /// The error of a record which is not a `Set`, it poisons the store in
+    /// strict mode.
+    fn unexpected_command(&self, key: &str) -> KvsError {
+        if let Some(poisoned) = &self.poisoned {
+            if !poisoned.swap(true, Ordering::SeqCst) {
+                warn!("Unexpected command for key {}, writes are refused", key);
+            }
+        }
+        KvsError::UnexpectedCommandType
+    }
+
+    /// Read the record of every key of `index`, poisoning the store on one
+    /// which is not a `Set`. Run by strict mode in the background.
+    ///
+    /// A record moved by a concurrent compaction is skipped rather than
+    /// misread.
+    fn check_consistency(&self, index: &IndexMap) {
+        let keys: Vec<String> = index.iter().map(|entry| entry.key().clone()).collect();
+        for key in keys {
+            if self.is_poisoned() {
+                return;
+            }
+            let cmd_pos = match index.get(&key) {
+                Some(cmd_pos) => *cmd_pos,
+                None => continue,
+            };
+            let cmd = self.read_and(&cmd_pos, |rdr| {
+                Ok(serde_json::from_reader::<_, ReplayCommand>(rdr)?)
+            });
+            let unexpected = matches!(cmd, Ok(cmd) if !matches!(cmd, Command::Set { .. }));
+            if unexpected && index.get(&key).is_some_and(|current| *current == cmd_pos) {
+                self.unexpected_command(&key);
+            }
+        }
+    }
+
+    /// Close file handles with generation number less than first_gen.
+    ///
+    /// `first_gen` is updated to the latest compaction gen after a compaction finishes.
+    /// The compaction generation contains the sum of all operations before it and the
+    /// in-memory index contains no entries with generation number less than it.
+    /// So we can safely close those file handles and the stale files can be deleted.
+    fn close_stale_files(&self) {
+        let epoch = self.epoch.load(Ordering::SeqCst);
+        if epoch != self.seen_epoch.get() {
+            self.readers.borrow_mut().clear();
+            self.newest.replace(None);
+            #[cfg(all(feature = "mmap", unix))]
+            if let Some(mapped) = &self.mapped {
+                mapped.clear();
+            }
+            self.seen_epoch.set(epoch);
+        }
+        let gen = self.first_gen.load(Ordering::SeqCst);
+        self.readers.replace_with(|cur| cur.split_off(&gen));
+        let mut newest = self.newest.borrow_mut();
+        if newest
+            .as_ref()
+            .is_some_and(|(newest_gen, _)| *newest_gen < gen)
+        {
+            *newest = None;
+        }
+        #[cfg(all(feature = "mmap", unix))]
+        if let Some(mapped) = &self.mapped {
+            mapped.retain_from(gen);
+        }
+    }
+
+    /// Call `f` with the bytes at `cmd_pos` sliced from the mapped log,
+    /// `None` if mapping is disabled or the log can't be mapped, the caller
+    /// reads the log then.
+    #[cfg(all(feature = "mmap", unix))]
+    fn read_mapped<R>(
+        &self,
+        cmd_pos: &CommandPos,
+        f: impl FnOnce(&[u8]) -> Result<R>,
+    ) -> Option<Result<R>> {
+        let mapped = self.mapped.as_ref()?;
+        self.close_stale_files();
+        match mapped.with_slice(cmd_pos, f) {
+            Ok(res) => res,
+            Err(err) => {
+                warn!(
+                    "Failed to map generation {}, reading it instead: {}",
+                    cmd_pos.gen, err
+                );
+                None
+            }
+        }
+    }
+
+    /// Read the log file at the given `CommandPos`.
+    ///
+    /// It returns `KvsError::MissingGeneration` if the log is deleted under
+    /// the store.
+    fn read_and<F, R>(&self, cmd_pos: &CommandPos, f: F) -> Result<R>
+    where
+        F: FnOnce(io::Take<&mut LogBufReader>) -> Result<R>,
+    {
+        self.close_stale_files();
+
+        // the newest generation, usually the active one, skips the map
+        let mut newest = self.newest.borrow_mut();
+        let newest_gen = newest.as_ref().map(|(gen, _)| *gen);
+        if newest_gen.is_none_or(|gen| gen < cmd_pos.gen) {
+            let reader = match self.readers.borrow_mut().remove(&cmd_pos.gen) {
+                Some(reader) => reader,
+                None => self.open_reader(cmd_pos.gen)?,
+            };
+            if let Some((gen, reader)) = newest.replace((cmd_pos.gen, reader)) {
+                self.readers.borrow_mut().insert(gen, reader);
+            }
+        }
+        if let Some((_, reader)) = newest.as_mut().filter(|(gen, _)| *gen == cmd_pos.gen) {
+            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+            return f(reader.take(cmd_pos.len));
+        }
+        drop(newest);
+
+        let mut readers = self.readers.borrow_mut();
+        if let std::collections::btree_map::Entry::Vacant(e) = readers.entry(cmd_pos.gen) {
+            e.insert(self.open_reader(cmd_pos.gen)?);
+        }
+
+        let reader = readers.get_mut(&cmd_pos.gen).unwrap();
+        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        let cmd_reader = reader.take(cmd_pos.len);
+
+        f(cmd_reader)
+    }
+
+    /// Open the log of `gen` values are read from.
+    fn open_reader(&self, gen: u64) -> Result<LogBufReader> {
+        let kind = if self.split_values {
+            LogKind::ValueLog
+        } else {
+            LogKind::Log
+        };
+        match self.storage.open(gen, kind) {
+            Err(KvsError::Io(err)) if err.kind() == io::ErrorKind::NotFound => {
+                warn!("Generation {} is missing", gen);
+                Err(KvsError::MissingGeneration { gen })
+            }
+            res => Ok(BufReader::new(res?)),
+        }
+    }
+
+    /// Read the value of `key` at the given `CommandPos`.
+    ///
+    /// # Errors
+    /// It returns `KvsError::UnexpectedCommandType` if it's not a `Set` command,
+    /// or `KvsError::InvalidUtf8` if the value is not valid UTF-8.
+    fn read_value(&self, key: &str, cmd_pos: &CommandPos) -> Result<String> {
+        #[cfg(all(feature = "mmap", unix))]
+        if let Some(res) = self.read_mapped(cmd_pos, |bytes| self.decode_value(key, bytes)) {
+            return res;
+        }
+        let bytes = self.read_and(cmd_pos, |mut rdr| {
+            let mut bytes = Vec::with_capacity(cmd_pos.len as usize);
+            rdr.read_to_end(&mut bytes)?;
+            Ok(bytes)
+        })?;
+        self.decode_value(key, &bytes)
+    }
+
+    /// Like `read_value` but served from `cache` if it's there, and kept in
+    /// it if `key` still has the same position in `index` once it's read.
+    fn read_cached(
+        &self,
+        key: &str,
+        cmd_pos: &CommandPos,
+        cache: Option<&ValueCache>,
+        index: &IndexMap,
+    ) -> Result<String> {
+        let cache = match cache {
+            Some(cache) => cache,
+            None => return self.read_value(key, cmd_pos),
+        };
+        if let Some(value) = cache.get(key) {
+            return Ok(value);
+        }
+        let value = self.read_value(key, cmd_pos)?;
+        cache.insert(key.to_owned(), value.clone(), || {
+            index.get(key).is_some_and(|current| *current == *cmd_pos)
+        });
+        Ok(value)
+    }
+
+    /// Like `read_value` but appends the value to `buf`.
+    ///
+    /// The record is read into a buffer reused across calls, and a value
+    /// without escapes is copied from it without any allocation.
+    fn read_value_into(&self, key: &str, cmd_pos: &CommandPos, buf: &mut String) -> Result<()> {
+        #[cfg(all(feature = "mmap", unix))]
+        if let Some(res) = self.read_mapped(cmd_pos, |bytes| self.push_value(key, bytes, buf)) {
+            return res;
+        }
+        let mut scratch = self.scratch.borrow_mut();
+        scratch.clear();
+        self.read_and(cmd_pos, |mut rdr| Ok(rdr.read_to_end(&mut scratch)?))?;
+        self.push_value(key, &scratch, buf)
+    }
+
+    /// Append the value of `key` deserialized from the bytes of its record
+    /// to `buf`, without allocating if it has no escapes.
+    fn push_value(&self, key: &str, bytes: &[u8], buf: &mut String) -> Result<()> {
+        let borrowed = if self.split_values {
+            serde_json::from_slice::<&str>(bytes).ok()
+        } else {
+            match serde_json::from_slice::<Command<&str>>(bytes) {
+                Ok(Command::Set { value, .. }) => Some(value),
+                Ok(_) => return Err(self.unexpected_command(key)),
+                Err(_) => None,
+            }
+        };
+        match borrowed {
+            Some(value) => buf.push_str(value),
+            None => buf.push_str(&self.decode_value(key, bytes)?),
+        }
+        Ok(())
+    }
+
+    /// Deserialize the value of `key` from the bytes of its record.
+    fn decode_value(&self, key: &str, bytes: &[u8]) -> Result<String> {
+        let value = if self.split_values {
+            serde_json::from_slice(bytes)
+        } else {
+            match serde_json::from_slice(bytes) {
+                Ok(Command::Set { value, .. }) => Ok(value),
+                Ok(_) => return Err(self.unexpected_command(key)),
+                Err(err) => Err(err),
+            }
+        };
+        value.map_err(|err| match std::str::from_utf8(bytes) {
+            Err(_) => KvsError::InvalidUtf8 {
+                key: key.to_owned(),
+            },
+            Ok(_) => err.into(),
+        })
+    }
+}
+
+/// WriteAgent singleton
+///
+/// Handles all set/remove requests from readers at multiple threads, works as
+/// a singleton for exclusive access, the writer itself is protected under a
+/// lock. Update the shared `IndexMap` during opertions for all readers (in
+/// different threads) to use.
+struct WriteAgent {
+    storage: Arc<dyn LogStorage>,
+    current_gen: u64,
+    // number of generations on the storage
+    gen_count: usize,
+    max_generations: Option<usize>,
+    // the least time between compactions triggered by writes
+    compaction_cooldown: Option<Duration>,
+    // `Clock::now_ms` of the last compaction
+    last_compaction_ms: Option<u64>,
+    // read helper only used in compaction
+    reader: ReadAgent,
+    writer: LogFileWriter,
+    // writer of the value log with the split layout
+    value_writer: Option<LogFileWriter>,
+    // the number of bytes representing "stale" commands that could be
+    // deleted during a compaction.
+    stale_bytes: u64,
+    // compact if more than this many bytes can be saved
+    compaction_threshold: u64,
+    // reject a `Set` whose record is longer
+    max_record_len: Option<u64>,
+    // compaction leaves longer live records in their generations
+    compaction_skip_len: Option<u64>,
+    // write pretty-printed records, one per line
+    pretty_records: bool,
+    // store equal values once in compactions with the split layout
+    dedup_values: bool,
+    // sequence number of the last command written
+    seq: u64,
+    // the number of bytes of the commands (or values) the index refers to
+    live_bytes: u64,
+    // evict the least recently used keys above this many live bytes
+    max_bytes: Option<u64>,
+    // refuse sets taking the logs over this many bytes
+    max_total_bytes: Option<u64>,
+    // bytes written by sets and removals since opening
+    user_bytes: u64,
+    // those plus the bytes rewritten by compactions
+    written_bytes: u64,
+    recency: Option<Arc<Recency>>,
+    access: Option<Arc<AccessTimes>>,
+    cache: Option<Arc<ValueCache>>,
+    filters: Option<Arc<BloomFilters>>,
+    prefixes: Option<Arc<PrefixIndex>>,
+    versions: Option<Arc<Versions>>,
+    // the values of the soft-removed keys, dropped on compaction
+    trash: HashMap<String, CommandPos>,
+    // keys left in place with their generations on compaction
+    pinned: HashSet<String>,
+    fsync_on_drop: bool,
+    // the writes to sync as a group, if enabled
+    group: Option<GroupCommit>,
+    remove_missing_is_error: bool,
+
+    // index reference to KvsStore
+    index: Arc<IndexMap>,
+    // persists the index periodically if enabled
+    checkpointer: Option<Checkpointer>,
+    // rewritten on rotations and compactions if enabled
+    manifest_path: Option<PathBuf>,
+    // recent compactions
+    history: CompactionHistory,
+    // senders of the new values by key, see `KvStore::watch`
+    watchers: HashMap<String, Vec<Sender<Option<String>>>>,
+    on_write: Option<OnWrite>,
+    clock: Arc<dyn Clock>,
+}
+impl WriteAgent {
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+
+    fn set(&mut self, key: String, value: &str) -> Result<()> {
+        self.check_record_len(&key, value, self.seq + 1)?;
+        self.make_room(&key, value)?;
+        let (pos, len) = self.append_set(&key, value)?;
+        self.writer.flush()?;
+
+        let keep = self.max_bytes.map(|_| key.clone());
+        if let Some(prefixes) = &self.prefixes {
+            prefixes.insert(&key, value);
+        }
+        self.index_insert(key, (self.current_gen, pos, len).into());
+        self.evict(keep.as_deref())?;
+        self.commit()?;
+
+        if self.needs_compaction() && self.cooled_down() {
+            self.compact()?;
+        }
+        self.maybe_checkpoint();
+        Ok(())
+    }
+
+    /// Check that a `Set` fits in `max_total_bytes`, compacting first if it
+    /// doesn't and there are stale bytes. Nothing is written otherwise.
+    fn make_room(&mut self, key: &str, value: &str) -> Result<()> {
+        let limit = match self.max_total_bytes {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        // about the value and its location with the split layout
+        let mut counter = ByteCounter(0);
+        let cmd = CommandRef::Set {
+            key,
+            value,
+            seq: self.seq + 1,
+        };
+        write_record(&mut counter, &cmd, self.pretty_records)?;
+        let needed = counter.0;
+        if self.total_bytes()? + needed <= limit {
+            return Ok(());
+        }
+        if self.stale_bytes > 0 {
+            self.compact()?;
+            if self.total_bytes()? + needed <= limit {
+                return Ok(());
+            }
+        }
+        Err(KvsError::StoreFull { limit, needed })
+    }
+
+    /// The bytes of all the logs and value logs on the storage.
+    fn total_bytes(&self) -> Result<u64> {
+        let mut total = 0;
+        for gen in self.storage.list_generations()? {
+            for kind in [LogKind::Log, LogKind::ValueLog] {
+                total += self.storage.len(gen, kind)?.unwrap_or(0);
+            }
+        }
+        Ok(total)
+    }
+
+    /// Check the record of a `Set` with the sequence number `seq` against
+    /// `max_record_len`, nothing is written.
+    ///
+    /// With the split layout, it's the record of the value in the value log.
+    fn check_record_len(&self, key: &str, value: &str, seq: u64) -> Result<()> {
+        let max = match self.max_record_len {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        let mut counter = ByteCounter(0);
+        if self.value_writer.is_some() {
+            serde_json::to_writer(&mut counter, value)?;
+        } else {
+            write_record(
+                &mut counter,
+                &CommandRef::Set { key, value, seq },
+                self.pretty_records,
+            )?;
+        }
+        if counter.0 > max {
+            return Err(KvsError::RecordTooLarge {
+                len: counter.0,
+                max,
+            });
+        }
+        Ok(())
+    }
+
+    /// Write a `Set` command without flushing the log.
+    ///
+    /// Returns the location of the value.
+    fn append_set(&mut self, key: &str, value: &str) -> Result<(u64, u64)> {
+        let seq = self.next_seq();
+        if let Some(value_writer) = &mut self.value_writer {
+            // value goes first, the log must never refer to a missing value
+            let pos = value_writer.pos;
+            serde_json::to_writer(&mut *value_writer, value)?;
+            value_writer.flush()?;
+            let len = value_writer.pos - pos;
+
+            let cmd = CommandRef::SetRef { key, pos, len, seq };
+            let record_pos = self.writer.pos;
+            write_record(&mut self.writer, &cmd, self.pretty_records)?;
+            self.count_user_write(len + self.writer.pos - record_pos);
+            Ok((pos, len))
+        } else {
+            let cmd = CommandRef::Set { key, value, seq };
+            let pos = self.writer.pos;
+            write_record(&mut self.writer, &cmd, self.pretty_records)?;
+            self.count_user_write(self.writer.pos - pos);
+            Ok((pos, self.writer.pos - pos))
+        }
+    }
+
+    /// Count the bytes written by a set or a removal, see
+    /// `KvStore::write_amplification`.
+    fn count_user_write(&mut self, bytes: u64) {
+        self.user_bytes += bytes;
+        self.written_bytes += bytes;
+    }
+
+    fn transaction(&mut self, ops: Vec<Op>) -> Result<()> {
+        // check removals against the keys as the previous operations leave them
+        let mut exists = HashMap::new();
+        for (i, op) in ops.iter().enumerate() {
+            match op {
+                Op::Set { key, value } => {
+                    self.check_record_len(key, value, self.seq + 1 + i as u64)?;
+                    exists.insert(key.as_str(), true);
+                }
+                Op::Remove { key } => {
+                    let found = exists.get(key.as_str()).copied();
+                    if !found.unwrap_or_else(|| self.index.contains_key(key)) {
+                        return Err(KvsError::KeyNotFound);
+                    }
+                    exists.insert(key.as_str(), false);
+                }
+            }
+        }
+
+        let begin_pos = self.writer.pos;
+        write_record(&mut self.writer, &Command::txn_begin(), self.pretty_records)?;
+        let mut markers_len = self.writer.pos - begin_pos;
+        let mut applied = Vec::with_capacity(ops.len());
+        for op in ops {
+            match op {
+                Op::Set { key, value } => {
+                    let (pos, len) = self.append_set(&key, &value)?;
+                    applied.push((key, Some((pos, len, value))));
+                }
+                Op::Remove { key } => {
+                    let cmd = Command::remove(key.clone(), self.next_seq());
+                    let pos = self.writer.pos;
+                    write_record(&mut self.writer, &cmd, self.pretty_records)?;
+                    self.count_user_write(self.writer.pos - pos);
+                    applied.push((key, None));
+                }
+            }
+        }
+        let commit_pos = self.writer.pos;
+        write_record(
+            &mut self.writer,
+            &Command::txn_commit(),
+            self.pretty_records,
+        )?;
+        self.writer.flush()?;
+        markers_len += self.writer.pos - commit_pos;
+        self.count_user_write(markers_len);
+
+        // committed, now we're safe to update the index
+        for (key, value_pos) in applied {
+            match value_pos {
+                Some((pos, len, value)) => {
+                    if let Some(prefixes) = &self.prefixes {
+                        prefixes.insert(&key, &value);
+                    }
+                    self.index_insert(key, (self.current_gen, pos, len).into());
+                }
+                None => {
+                    self.index_remove(&key);
+                }
+            }
+        }
+        self.stale_bytes += markers_len;
+        self.evict(None)?;
+        self.commit()?;
+
+        if self.needs_compaction() && self.cooled_down() {
+            self.compact()?;
+        }
+        self.maybe_checkpoint();
+        Ok(())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_with_stats(key).map(|_| ())
+    }
+
+    /// Count a write to the pending group, syncing the group if it's due.
+    fn commit(&mut self) -> Result<()> {
+        let now_ms = self.clock.now_ms();
+        let due = self.group.as_mut().is_some_and(|group| group.add(now_ms));
+        if due {
+            self.sync_group()?;
+        }
+        Ok(())
+    }
+
+    /// Sync the logs, making the pending group of writes durable.
+    fn sync_group(&mut self) -> Result<()> {
+        // values go first, the log must never refer to a missing value
+        if let Some(value_writer) = &mut self.value_writer {
+            value_writer.sync()?;
+        }
+        self.writer.sync()?;
+        if let Some(group) = &mut self.group {
+            group.clear();
+        }
+        Ok(())
+    }
+
+    fn append_raw(&mut self, bytes: &[u8]) -> Result<CommandPosInfo> {
+        let pos = self.writer.pos;
+        self.writer.write_all(bytes)?;
+        self.writer.flush()?;
+        Ok(CommandPosInfo {
+            gen: self.current_gen,
+            pos,
+            len: bytes.len() as u64,
+        })
+    }
+
+    fn index_raw(&mut self, key: String, record: Option<CommandPosInfo>) -> Result<()> {
+        if self.value_writer.is_some() {
+            return Err(KvsError::StringError(
+                "Raw records can't be indexed with the split layout".to_owned(),
+            ));
+        }
+        match record {
+            Some(record) => {
+                let cmd_pos = CommandPos::from(record);
+                if let Some(prefixes) = &self.prefixes {
+                    prefixes.insert(&key, &self.reader.read_value(&key, &cmd_pos)?);
+                }
+                self.index_insert(key, cmd_pos);
+            }
+            None => {
+                self.index_remove(&key);
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_with_stats(&mut self, key: String) -> Result<RemoveStats> {
+        // don't remove the key immediately, make sure writer successful first!
+        if !self.index.contains_key(&key) {
+            if !self.remove_missing_is_error {
+                return Ok(RemoveStats::default());
+            }
+            return Err(KvsError::KeyNotFound);
+        }
+
+        let cmd = Command::remove(key, self.next_seq());
+        let pos = self.writer.pos;
+        write_record(&mut self.writer, &cmd, self.pretty_records)?;
+        self.writer.flush()?;
+        let mut stats = RemoveStats {
+            tombstone_bytes: self.writer.pos - pos,
+            freed_bytes: 0,
+        };
+        self.count_user_write(stats.tombstone_bytes);
+
+        // flushed, now we're safe to remove the key
+        if let Command::Remove { key, .. } = cmd {
+            stats.freed_bytes = self.index_remove(&key);
+        }
+        self.commit()?;
+        self.maybe_checkpoint();
+        Ok(stats)
+    }
+
+    fn soft_remove(&mut self, key: String) -> Result<()> {
+        let old = match self.index.get(&key).map(|cmd_pos| *cmd_pos) {
+            Some(old) => old,
+            None if self.remove_missing_is_error => return Err(KvsError::KeyNotFound),
+            None => return Ok(()),
+        };
+        let cmd = Command::soft_remove(key, self.next_seq());
+        let pos = self.writer.pos;
+        write_record(&mut self.writer, &cmd, self.pretty_records)?;
+        self.writer.flush()?;
+        self.stale_bytes += self.writer.pos - pos;
+        self.count_user_write(self.writer.pos - pos);
+
+        let key = cmd.into_key();
+        self.index_remove(&key);
+        self.trash.insert(key, old);
+        self.commit()?;
+        self.maybe_checkpoint();
+        Ok(())
+    }
+
+    fn undelete(&mut self, key: String) -> Result<bool> {
+        let cmd_pos = match self.trash.get(&key) {
+            Some(&cmd_pos) => cmd_pos,
+            None => return Ok(false),
+        };
+        let value = self.reader.read_value(&key, &cmd_pos)?;
+        self.set(key, &value)?;
+        Ok(true)
+    }
+
+    fn swap(&mut self, a: String, b: String) -> Result<()> {
+        // one guard of the index at a time, both keys might be in a shard
+        let pos_a = self.index.get(&a).map(|cmd_pos| *cmd_pos);
+        let pos_b = self.index.get(&b).map(|cmd_pos| *cmd_pos);
+        let (pos_a, pos_b) = match (pos_a, pos_b) {
+            (Some(pos_a), Some(pos_b)) => (pos_a, pos_b),
+            _ => return Err(KvsError::KeyNotFound),
+        };
+        if a == b {
+            return Ok(());
+        }
+        let value_a = self.reader.read_value(&a, &pos_a)?;
+        let value_b = self.reader.read_value(&b, &pos_b)?;
+        self.transaction(vec![
+            Op::Set {
+                key: a,
+                value: value_b,
+            },
+            Op::Set {
+                key: b,
+                value: value_a,
+            },
+        ])
+    }
+
+    fn rename(&mut self, from: String, to: String, overwrite: bool) -> Result<()> {
+        let cmd_pos = match self.index.get(&from) {
+            Some(cmd_pos) => *cmd_pos,
+            None => return Err(KvsError::KeyNotFound),
+        };
+        if from == to {
+            return Ok(());
+        }
+        if !overwrite && self.index.contains_key(&to) {
+            return Err(KvsError::KeyExists);
+        }
+
+        if self.value_writer.is_some() && cmd_pos.gen == self.current_gen {
+            // the value is in the active value log already, refer to it
+            // instead of copying. Both keys share the bytes until `from` is
+            // removed below, counting them as stale only makes compaction
+            // come a bit earlier.
+            let cmd = Command::set_ref(to, cmd_pos.pos, cmd_pos.len, self.next_seq());
+            write_record(&mut self.writer, &cmd, self.pretty_records)?;
+            self.writer.flush()?;
+            let to = cmd.into_key();
+            if let Some(prefixes) = &self.prefixes {
+                prefixes.copy(&from, &to);
+            }
+            self.index_insert(to, cmd_pos);
+        } else {
+            let value = self.reader.read_value(&from, &cmd_pos)?;
+            self.set(to, &value)?;
+        }
+        // it might have been evicted by the set in bounded cache mode
+        if !self.index.contains_key(&from) {
+            return Ok(());
+        }
+        self.remove(from)
+    }
+
+    /// Point `key` to `cmd_pos` in the index, the overwritten command is stale
+    /// unless it's kept as a version.
+    fn index_insert(&mut self, key: String, cmd_pos: CommandPos) {
+        if !self.trash.is_empty() {
+            self.trash.remove(&key);
+        }
+        if let Some(recency) = &self.recency {
+            recency.touch_owned(key.clone());
+        }
+        if let Some(access) = &self.access {
+            access.touch_owned(key.clone());
+        }
+        self.live_bytes += cmd_pos.len;
+        // readers must never miss a key of the index in the filters
+        if let Some(filters) = &self.filters {
+            filters.insert(cmd_pos.gen, &key, &self.index);
+        }
+        // the key is still needed after the index is updated, e.g. the
+        // cached value is dropped then
+        let kept_key = (self.cache.is_some()
+            || self.versions.is_some()
+            || self.on_write.is_some()
+            || self.watchers.contains_key(&key))
+        .then(|| key.clone());
+        if let Some(old) = self.index.insert(key, cmd_pos) {
+            self.stale_bytes += match (&self.versions, &kept_key) {
+                (Some(versions), Some(key)) => versions.push(key, old).map_or(0, |old| old.len),
+                _ => old.len,
+            };
+            self.live_bytes -= old.len;
+        }
+        let key = match kept_key {
+            Some(key) => key,
+            None => return,
+        };
+        if let Some(cache) = &self.cache {
+            cache.invalidate(&key);
+        }
+        if self.watchers.contains_key(&key) {
+            // the command is flushed already
+            match self.reader.read_value(&key, &cmd_pos) {
+                Ok(value) => self.notify(&key, Some(value)),
+                Err(err) => warn!("Failed to read the value for watchers: {}", err),
+            }
+        }
+        if let Some(on_write) = &self.on_write {
+            on_write.call(&key);
+        }
+    }
+
+    /// Remove `key` from the index, the removed command and the versions
+    /// are stale.
+    ///
+    /// Returns the length of the stale commands.
+    fn index_remove(&mut self, key: &str) -> u64 {
+        let mut freed = 0;
+        if let Some((_, old)) = self.index.remove(key) {
+            self.live_bytes -= old.len;
+            freed = old.len;
+            self.notify(key, None);
+            if let Some(on_write) = &self.on_write {
+                on_write.call(key);
+            }
+        }
+        if let Some(versions) = &self.versions {
+            freed += versions.forget(key);
+        }
+        self.stale_bytes += freed;
+        if let Some(recency) = &self.recency {
+            recency.forget(key);
+        }
+        if let Some(access) = &self.access {
+            access.forget(key);
+        }
+        if let Some(cache) = &self.cache {
+            cache.invalidate(key);
+        }
+        if let Some(prefixes) = &self.prefixes {
+            prefixes.remove(key);
+        }
+        freed
+    }
+
+    fn rebuild_index(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        if let Some(value_writer) = &mut self.value_writer {
+            value_writer.flush()?;
+        }
+        let split_values = self.value_writer.is_some();
+        let fresh = IndexMap::with_capacity(self.index.len());
+        let mut replay = Replay {
+            seq: self.seq,
+            versions: self
+                .versions
+                .as_ref()
+                .map(|versions| Arc::new(versions.emptied())),
+            ..Default::default()
+        };
+        let mut stale_bytes = 0;
+        for gen in self.storage.list_generations()? {
+            let mut reader = BufReader::new(self.storage.open(gen, LogKind::Log)?);
+            let gen_stale_bytes = load_log(
+                gen,
+                &mut reader,
+                0,
+                &fresh,
+                split_values,
+                self.max_record_len,
+                &mut replay,
+            )?;
+            if replay.reset_gen == Some(gen) {
+                stale_bytes = 0;
+            }
+            stale_bytes += gen_stale_bytes;
+        }
+
+        // the keys in both are never missing in between
+        let changed: Vec<(String, CommandPos)> = fresh
+            .iter()
+            .filter(|entry| {
+                self.index
+                    .get(entry.key())
+                    .is_none_or(|cmd_pos| *cmd_pos != *entry.value())
+            })
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        for (key, cmd_pos) in changed {
+            if let Some(cache) = &self.cache {
+                cache.invalidate(&key);
+            }
+            if let Some(filters) = &self.filters {
+                filters.insert(cmd_pos.gen, &key, &self.index);
+            }
+            if let Some(prefixes) = &self.prefixes {
+                prefixes.insert(&key, &self.reader.read_value(&key, &cmd_pos)?);
+            }
+            self.index.insert(key, cmd_pos);
+        }
+        let missing: Vec<String> = self
+            .index
+            .iter()
+            .filter(|entry| !fresh.contains_key(entry.key()))
+            .map(|entry| entry.key().clone())
+            .collect();
+        for key in missing {
+            self.index.remove(&key);
+            if let Some(recency) = &self.recency {
+                recency.forget(&key);
+            }
+            if let Some(access) = &self.access {
+                access.forget(&key);
+            }
+            if let Some(cache) = &self.cache {
+                cache.invalidate(&key);
+            }
+            if let Some(prefixes) = &self.prefixes {
+                prefixes.remove(&key);
+            }
+        }
+        if let (Some(versions), Some(fresh)) = (&self.versions, &replay.versions) {
+            versions.replace_with(fresh);
+        }
+        self.trash = replay.trash;
+        self.stale_bytes = stale_bytes;
+        self.live_bytes = self.index.iter().map(|cmd_pos| cmd_pos.len).sum();
+        self.seq = replay.seq;
+
+        // the checkpoint might hold the broken index
+        if let Err(err) = self.checkpoint() {
+            warn!("Failed to write checkpoint: {}", err);
+        }
+        Ok(())
+    }
+
+    /// Send the new value of `key` to its watchers, dropping the ones whose
+    /// receivers are gone.
+    fn notify(&mut self, key: &str, value: Option<String>) {
+        if let Some(senders) = self.watchers.get_mut(key) {
+            senders.retain(|sender| sender.send(value.clone()).is_ok());
+            if senders.is_empty() {
+                self.watchers.remove(key);
+            }
+        }
+    }
+
+    /// Remove the least recently used keys until the live bytes are within
+    /// `max_bytes`, except `keep`.
+    ///
+    /// Each eviction writes a `Remove` command like an explicit removal, so
+    /// they add up to the stale bytes too.
+    fn evict(&mut self, keep: Option<&str>) -> Result<()> {
+        let (max_bytes, recency) = match (self.max_bytes, &self.recency) {
+            (Some(max_bytes), Some(recency)) if self.live_bytes > max_bytes => {
+                (max_bytes, recency.clone())
+            }
+            _ => return Ok(()),
+        };
+        for key in recency.oldest_first(&self.index) {
+            if self.live_bytes <= max_bytes {
+                break;
+            }
+            if Some(key.as_str()) != keep {
+                self.remove(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn normalize_generations(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        if let Some(value_writer) = &mut self.value_writer {
+            value_writer.flush()?;
+        }
+        let gens = self.storage.list_generations()?;
+        if (1..).zip(&gens).all(|(new_gen, &gen)| gen == new_gen) {
+            return Ok(());
+        }
+
+        // the checkpoint refers to the old numbers, it must not outlive a
+        // failure halfway
+        if let Some(checkpointer) = &self.checkpointer {
+            Checkpoint::remove(&checkpointer.path)?;
+        }
+        self.reader.first_gen.store(1, Ordering::SeqCst);
+        // in ascending order, the new number is never taken by another one
+        for (new_gen, gen) in (1..).zip(gens) {
+            if gen == new_gen {
+                continue;
+            }
+            let values =
+                self.value_writer.is_some() && self.storage.len(gen, LogKind::ValueLog)?.is_some();
+            self.storage.rename(gen, new_gen, LogKind::Log)?;
+            if values {
+                if let Err(err) = self.storage.rename(gen, new_gen, LogKind::ValueLog) {
+                    // a log and its value log share their number
+                    self.storage.rename(new_gen, gen, LogKind::Log)?;
+                    return Err(err);
+                }
+            }
+            self.renumber(gen, new_gen);
+        }
+
+        self.checkpoint()?;
+        self.update_manifest();
+        Ok(())
+    }
+
+    /// Point everything at generation `gen` to `new_gen`, once its logs are
+    /// renamed.
+    fn renumber(&mut self, gen: u64, new_gen: u64) {
+        // readers drop handles keyed by old numbers before they could see
+        // a renumbered position
+        self.reader.epoch.fetch_add(1, Ordering::SeqCst);
+        let renumber = |cmd_pos: &mut CommandPos| {
+            if cmd_pos.gen == gen {
+                cmd_pos.gen = new_gen;
+            }
+        };
+        for mut cmd_pos in self.index.iter_mut() {
+            renumber(cmd_pos.value_mut());
+        }
+        if let Some(versions) = &self.versions {
+            versions.update(renumber);
+        }
+        self.trash.values_mut().for_each(renumber);
+        if self.current_gen == gen {
+            self.current_gen = new_gen;
+        }
+        if let Some(filters) = &self.filters {
+            filters.rename(gen, new_gen);
+        }
+    }
+
+    /// Whether too many bytes are stale or there are too many generations.
+    fn needs_compaction(&self) -> bool {
+        self.stale_bytes > self.compaction_threshold
+            || self.max_generations.is_some_and(|max| self.gen_count > max)
+    }
+
+    /// Whether the compaction cooldown has elapsed since the last compaction.
+    fn cooled_down(&self) -> bool {
+        match (self.compaction_cooldown, self.last_compaction_ms) {
+            (Some(cooldown), Some(last_ms)) => {
+                let elapsed = self.clock.now_ms().saturating_sub(last_ms);
+                u128::from(elapsed) >= cooldown.as_millis()
+            }
+            _ => true,
+        }
+    }
+
+    /// Write a checkpoint if the interval has elapsed since the last one.
+    ///
+    /// The command is already durable, so a failed checkpoint is only logged.
+    fn maybe_checkpoint(&mut self) {
+        let due = match &self.checkpointer {
+            Some(checkpointer) => {
+                let elapsed = self.clock.now_ms().saturating_sub(checkpointer.last_ms);
+                u128::from(elapsed) >= checkpointer.interval.as_millis()
+            }
+            None => false,
+        };
+        if due {
+            if let Err(err) = self.checkpoint() {
+                warn!("Failed to write checkpoint: {}", err);
+            }
+        }
+    }
+
+    fn checkpoint(&mut self) -> Result<()> {
+        if let Some(checkpointer) = &mut self.checkpointer {
+            // the end of the log covered is read back
+            self.writer.flush()?;
+            Checkpoint::capture(
+                &*self.storage,
+                self.current_gen,
+                self.writer.pos,
+                self.stale_bytes,
+                self.seq,
+                &self.index,
+            )?
+            .save(&checkpointer.path)?;
+            checkpointer.last_ms = self.clock.now_ms();
+        }
+        Ok(())
+    }
+
+    /// Write to the new active generation `gen` from now on.
+    fn start_generation(&mut self, gen: u64) -> Result<()> {
+        let split_values = self.value_writer.is_some();
+        let (writer, value_writer) = new_generation(&*self.storage, gen, split_values)?;
+        self.current_gen = gen;
+        self.writer = writer;
+        self.value_writer = value_writer;
+        Ok(())
+    }
+
+    /// Seal the active generation and write to a new one after it.
+    fn rotate(&mut self) -> Result<()> {
+        self.start_generation(self.current_gen + 1)?;
+        self.gen_count += 1;
+        self.update_manifest();
+        Ok(())
+    }
+
+    fn checkpoint_barrier(&mut self) -> Result<CheckpointId> {
+        self.writer.sync()?;
+        if let Some(value_writer) = &mut self.value_writer {
+            value_writer.sync()?;
+        }
+        self.rotate()?;
+
+        let mut generations = self.storage.list_generations()?;
+        generations.retain(|&gen| gen < self.current_gen);
+        let mut files = Vec::new();
+        for &gen in &generations {
+            files.push(format!("{}.log", gen));
+            if self.storage.len(gen, LogKind::ValueLog)?.is_some() {
+                files.push(format!("{}.vlog", gen));
+            }
+        }
+        Ok(CheckpointId { generations, files })
+    }
+
+    /// Rewrite the manifest if enabled, keeping its creation time.
+    ///
+    /// It's informational, so a failure is only logged.
+    fn update_manifest(&self) {
+        let path = match &self.manifest_path {
+            Some(path) => path,
+            None => return,
+        };
+        let now_ms = self.clock.now_ms();
+        let manifest = Manifest {
+            current_gen: self.current_gen,
+            compaction_threshold: self.compaction_threshold,
+            format_version: KvStore::FORMAT_VERSION,
+            created_ms: Manifest::load(path).map_or(now_ms, |old| old.created_ms),
+            updated_ms: now_ms,
+        };
+        if let Err(err) = manifest.save(path) {
+            warn!("Failed to write manifest: {}", err);
+        }
+    }
+
+    /// Collect space by writing entries to a new log file then remove old log
+    /// files, staled bytes then gone.
+    fn compact(&mut self) -> Result<()> {
+        // current_gen + 1 for the compaction log.
+        self.compact_into(self.current_gen + 1)
+    }
+
+    /// Compact into the generation `compaction_gen`, the one after it becomes
+    /// the active generation. Both must be newer than `current_gen`.
+    fn compact_into(&mut self, compaction_gen: u64) -> Result<()> {
+        self.compact_dropping(compaction_gen, &HashSet::new(), None)
+    }
+
+    fn delete_prefix(&mut self, prefix: &str) -> Result<usize> {
+        let dropped: HashSet<String> = self
+            .index
+            .iter()
+            .filter(|entry| entry.key().starts_with(prefix))
+            .map(|entry| entry.key().clone())
+            .collect();
+        if dropped.is_empty() {
+            return Ok(0);
+        }
+        self.compact_dropping(self.current_gen + 1, &dropped, None)?;
+        Ok(dropped.len())
+    }
+
+    fn drain_prefix(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let mut entries: Vec<(String, CommandPos)> = self
+            .index
+            .iter()
+            .filter(|entry| entry.key().starts_with(prefix))
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        if entries.is_empty() {
+            return Ok(Vec::new());
+        }
+        entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        // every value is read before anything is written
+        let pairs = entries
+            .into_iter()
+            .map(|(key, cmd_pos)| {
+                let value = self.reader.read_value(&key, &cmd_pos)?;
+                Ok((key, value))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let dropped: HashSet<String> = pairs.iter().map(|(key, _)| key.clone()).collect();
+        self.compact_dropping(self.current_gen + 1, &dropped, None)?;
+        Ok(pairs)
+    }
+
+    /// Compact into `compaction_gen` like `compact_into`, leaving `dropped`
+    /// out as if they were removed but without writing removals.
+    ///
+    /// The compaction generation starts with a `Reset` then, so the keys
+    /// don't come back from the older logs if a crash leaves them around.
+    /// Nothing is retained for `compaction_skip_len`, as the `Reset` hides
+    /// the older generations.
+    ///
+    /// With `cancel`, copying the records gives up with `KvsError::Cancelled`
+    /// once it's cancelled. Nothing is persisted then, the index still refers
+    /// to the old generations and the writes go on in the new active one.
+    fn compact_dropping(
+        &mut self,
+        compaction_gen: u64,
+        dropped: &HashSet<String>,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<()> {
+        let start = Instant::now();
+        self.start_generation(compaction_gen + 1)?;
+
+        // generations left in place for their large values or pinned keys
+        let mut retained: BTreeSet<u64> = match self.compaction_skip_len {
+            Some(max) if dropped.is_empty() => self
+                .index
+                .iter()
+                .filter(|cmd_pos| cmd_pos.len > max)
+                .map(|cmd_pos| cmd_pos.gen)
+                .collect(),
+            _ => BTreeSet::new(),
+        };
+        if dropped.is_empty() {
+            retained.extend(
+                self.pinned
+                    .iter()
+                    .filter_map(|key| self.index.get(key).map(|cmd_pos| cmd_pos.gen)),
+            );
+        }
+        // their stale records of keys removed since must not come back
+        let mut removed = HashSet::new();
+        for &gen in &retained {
+            let mut reader = BufReader::new(self.storage.open(gen, LogKind::Log)?);
+            scan_set_keys(&mut reader, &mut removed)?;
+        }
+        removed.retain(|key| !self.index.contains_key(key));
+
+        // write all KV to a new log file.
+        self.written_bytes += self.copy_live_records(
+            compaction_gen,
+            |key, cmd_pos| !retained.contains(&cmd_pos.gen) && !dropped.contains(key),
+            removed,
+            !dropped.is_empty(),
+            self.seq,
+            cancel,
+        )?;
+        // they're left in the older generations about to be removed
+        for key in dropped {
+            self.index_remove(key);
+        }
+
+        // after update `first_gen`, all `ReadAgent`s will sense it and
+        // close files' handles of stale generations. Retained generations
+        // are older, readers drop all handles then.
+        let first_gen = retained.first().copied().unwrap_or(compaction_gen);
+        self.reader.first_gen.store(first_gen, Ordering::SeqCst);
+        if !retained.is_empty() {
+            self.reader.epoch.fetch_add(1, Ordering::SeqCst);
+        }
+        self.reader.close_stale_files();
+        if let Some(filters) = &self.filters {
+            filters.retain(|gen| gen >= compaction_gen || retained.contains(&gen));
+        }
+        // the ones left behind with their live records
+        if let Some(versions) = &self.versions {
+            versions.retain(|_, cmd_pos| {
+                cmd_pos.gen >= compaction_gen || retained.contains(&cmd_pos.gen)
+            });
+        }
+        // the soft-removed keys aren't copied, the `Reset` drops their records
+        self.trash.clear();
+
+        // remove stale log files
+        // Note that actually these files are not deleted immediately because `ReadAgent`s
+        // still keep open file handles. When `ReadAgent` is used next time, it will clear
+        // its stale file handles. On Unix, the files will be deleted after all the handles
+        // are closed. On Windows, the deletions below will fail and stale files are expected
+        // to be deleted in the next compaction.
+        let stale_gens = self
+            .storage
+            .list_generations()?
+            .into_iter()
+            .filter(|gen| gen < &compaction_gen && !retained.contains(gen));
+
+        for stale_gen in stale_gens {
+            // `remove_file` might return error on windows, but would succeed
+            // eventually at later compactions
+            for kind in [LogKind::Log, LogKind::ValueLog] {
+                if let Err(err) = self.storage.remove(stale_gen, kind) {
+                    warn!("Failed to remove file: {}", err);
+                }
+            }
+        }
+
+        self.history.push(CompactionRecord {
+            finished_at: Instant::now(),
+            duration: start.elapsed(),
+            reclaimed_bytes: self.stale_bytes,
+        });
+        self.last_compaction_ms = Some(self.clock.now_ms());
+
+        // fresh as new born, the rest of the retained generations is only
+        // reclaimed once their large values are stale or keys unpinned
+        self.stale_bytes = 0;
+        // the compaction generation and the active one, retained ones don't
+        // count or they would trigger compactions over and over
+        self.gen_count = 2;
+
+        // the old checkpoint refers to removed generations
+        if let Err(err) = self.checkpoint() {
+            warn!("Failed to write checkpoint: {}", err);
+        }
+        self.update_manifest();
+
+        Ok(())
+    }
+
+    fn replace_all(&mut self, entries: BTreeMap<String, String>) -> Result<()> {
+        for (i, (key, value)) in entries.iter().enumerate() {
+            self.check_record_len(key, value, self.seq + i as u64 + 1)?;
+        }
+        // the old content stays complete if anything goes wrong
+        self.writer.flush()?;
+        if let Some(value_writer) = &mut self.value_writer {
+            value_writer.flush()?;
+        }
+
+        let gen = self.current_gen + 1;
+        let (mut writer, mut value_writer) = self.new_temp_generation(gen)?;
+        // the removals hide the old generations until they're gone
+        let mut dropped: Vec<String> = self
+            .index
+            .iter()
+            .filter(|entry| !entries.contains_key(entry.key()))
+            .map(|entry| entry.key().clone())
+            .collect();
+        dropped.sort_unstable();
+        let mut tombstone_bytes = 0;
+        for key in &dropped {
+            let seq = self.next_seq();
+            let pos = writer.pos;
+            write_record(
+                &mut writer,
+                &Command::remove(key.clone(), seq),
+                self.pretty_records,
+            )?;
+            tombstone_bytes += writer.pos - pos;
+        }
+        let mut positions = Vec::with_capacity(entries.len());
+        for (key, value) in &entries {
+            let seq = self.next_seq();
+            if let Some(value_writer) = &mut value_writer {
+                let pos = value_writer.pos;
+                serde_json::to_writer(&mut *value_writer, value)?;
+                let len = value_writer.pos - pos;
+                let cmd = CommandRef::SetRef { key, pos, len, seq };
+                write_record(&mut writer, &cmd, self.pretty_records)?;
+                positions.push((pos, len));
+            } else {
+                let pos = writer.pos;
+                let cmd = CommandRef::Set { key, value, seq };
+                write_record(&mut writer, &cmd, self.pretty_records)?;
+                positions.push((pos, writer.pos - pos));
+            }
+        }
+        // from here on, the new content is what's replayed
+        self.written_bytes += self.persist_generation(gen, writer, value_writer)?;
+
+        self.start_generation(gen + 1)?;
+        for key in &dropped {
+            self.index_remove(key);
+        }
+        for ((key, value), (pos, len)) in entries.into_iter().zip(positions) {
+            if let Some(prefixes) = &self.prefixes {
+                prefixes.insert(&key, &value);
+            }
+            self.index_insert(key, (gen, pos, len).into());
+        }
+
+        // readers drop the handles of the old generations
+        self.reader.first_gen.store(gen, Ordering::SeqCst);
+        self.reader.close_stale_files();
+        if let Some(filters) = &self.filters {
+            filters.retain(|filter_gen| filter_gen >= gen);
+        }
+        // the old content goes with its generations
+        if let Some(versions) = &self.versions {
+            versions.retain(|_, cmd_pos| cmd_pos.gen >= gen);
+        }
+        self.trash.clear();
+        let stale_gens = self
+            .storage
+            .list_generations()?
+            .into_iter()
+            .filter(|&stale_gen| stale_gen < gen);
+        for stale_gen in stale_gens {
+            // like after a compaction, a later one retries on failure
+            for kind in [LogKind::Log, LogKind::ValueLog] {
+                if let Err(err) = self.storage.remove(stale_gen, kind) {
+                    warn!("Failed to remove file: {}", err);
+                }
+            }
+        }
+        // only the removals are stale with the old generations gone
+        self.stale_bytes = tombstone_bytes;
+        self.gen_count = 2;
+
+        // the old checkpoint refers to removed generations
+        if let Err(err) = self.checkpoint() {
+            warn!("Failed to write checkpoint: {}", err);
+        }
+        self.update_manifest();
+        Ok(())
+    }
+
+    fn compact_with_key_transform(&mut self, f: impl Fn(&str) -> String) -> Result<()> {
+        let start = Instant::now();
+        let reclaimed_bytes = self.stale_bytes;
+        let mut entries: Vec<(String, CommandPos)> = self
+            .index
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        let mut renamed: BTreeMap<String, (String, String)> = BTreeMap::new();
+        for (key, cmd_pos) in entries {
+            let value = self.reader.read_value(&key, &cmd_pos)?;
+            let new_key = f(&key);
+            if let Some((old_key, _)) = renamed.insert(new_key.clone(), (key.clone(), value)) {
+                warn!(
+                    "Keys {} and {} are both transformed to {}, keeping the value of {}",
+                    old_key, key, new_key, key
+                );
+            }
+        }
+        let entries = renamed
+            .into_iter()
+            .map(|(new_key, (_, value))| (new_key, value))
+            .collect();
+        self.replace_all(entries)?;
+
+        self.history.push(CompactionRecord {
+            finished_at: Instant::now(),
+            duration: start.elapsed(),
+            reclaimed_bytes,
+        });
+        self.last_compaction_ms = Some(self.clock.now_ms());
+        Ok(())
+    }
+
+    fn compact_tiered(&mut self) -> Result<u64> {
+        let start = Instant::now();
+        // the active generation is still being written
+        let mut gens = self.storage.list_generations()?;
+        gens.retain(|&gen| gen != self.current_gen);
+        if self.tiered_pair(&gens)?.is_none() {
+            return Ok(0);
+        }
+
+        let mut reclaimed_bytes = 0;
+        while let Some((first, second)) = self.tiered_pair(&gens)? {
+            reclaimed_bytes += self.merge_generations(first, second)?;
+            gens.retain(|&gen| gen != second);
+        }
+        self.gen_count = gens.len() + 1;
+        self.stale_bytes = self.stale_bytes.saturating_sub(reclaimed_bytes);
+
+        self.history.push(CompactionRecord {
+            finished_at: Instant::now(),
+            duration: start.elapsed(),
+            reclaimed_bytes,
+        });
+        self.last_compaction_ms = Some(self.clock.now_ms());
+        // the old checkpoint refers to replaced generations
+        if let Err(err) = self.checkpoint() {
+            warn!("Failed to write checkpoint: {}", err);
+        }
+        self.update_manifest();
+        Ok(reclaimed_bytes)
+    }
+
+    fn purge_tombstones(&mut self) -> Result<u64> {
+        // keys set by any command replayed so far
+        let mut set_keys = HashSet::new();
+        let mut freed = 0;
+        for gen in self.storage.list_generations()? {
+            let mut bytes = Vec::new();
+            self.storage
+                .open(gen, LogKind::Log)?
+                .read_to_end(&mut bytes)?;
+            let header = match read_format_version(&mut &bytes[..])? {
+                0 => 0,
+                _ => FORMAT_HEADER_LEN as usize,
+            };
+
+            // byte ranges of the removals to drop
+            let mut dropped = Vec::new();
+            let mut pos = header;
+            let mut stream =
+                Deserializer::from_slice(&bytes[header..]).into_iter::<ReplayCommand>();
+            while let Some(cmd) = stream.next() {
+                let end = header + stream.byte_offset();
+                match cmd? {
+                    Command::Set { key, .. } | Command::SetRef { key, .. } => {
+                        set_keys.insert(key);
+                    }
+                    Command::Remove { key, .. }
+                        if gen != self.current_gen
+                            && !set_keys.contains(&key)
+                            && !self.index.contains_key(&key) =>
+                    {
+                        dropped.push((pos, end));
+                    }
+                    _ => {}
+                }
+                pos = end;
+            }
+            if dropped.is_empty() {
+                continue;
+            }
+
+            // a headerless log gets the header, records shift by its length
+            let mut writer = new_temp_log_file(&*self.storage, gen, LogKind::Log)?;
+            let base = writer.pos;
+            let mut last = header;
+            for &(start, end) in &dropped {
+                writer.write_all(&bytes[last..start])?;
+                last = end;
+            }
+            writer.write_all(&bytes[last..])?;
+            writer.sync()?;
+            let new_len = writer.pos;
+            drop(writer);
+            self.storage.persist_temp(gen, LogKind::Log)?;
+            freed += (bytes.len() as u64).saturating_sub(new_len);
+            self.written_bytes += new_len;
+
+            // readers drop the handles of the old log before seeing new positions
+            self.reader.epoch.fetch_add(1, Ordering::SeqCst);
+            if self.value_writer.is_none() {
+                let relocate = |cmd_pos: &mut CommandPos| {
+                    if cmd_pos.gen != gen {
+                        return;
+                    }
+                    let old_pos = cmd_pos.pos as usize;
+                    let shift: usize = dropped
+                        .iter()
+                        .take_while(|(start, _)| *start < old_pos)
+                        .map(|(start, end)| end - start)
+                        .sum();
+                    cmd_pos.pos = base + (old_pos - header - shift) as u64;
+                };
+                for mut cmd_pos in self.index.iter_mut() {
+                    relocate(&mut cmd_pos);
+                }
+                if let Some(versions) = &self.versions {
+                    versions.update(relocate);
+                }
+                self.trash.values_mut().for_each(relocate);
+            }
+        }
+        if freed == 0 {
+            return Ok(0);
+        }
+        self.stale_bytes = self.stale_bytes.saturating_sub(freed);
+
+        // the old checkpoint refers to old positions
+        if let Err(err) = self.checkpoint() {
+            warn!("Failed to write checkpoint: {}", err);
+        }
+        Ok(freed)
+    }
+
+    fn rollback_to_seq(&mut self, seq: u64) -> Result<()> {
+        self.writer.sync()?;
+        if let Some(value_writer) = &mut self.value_writer {
+            value_writer.sync()?;
+        }
+
+        // the log, the offset and the value offset the records after `seq`
+        // start at
+        let mut cut = None;
+        let gens = self.storage.list_generations()?;
+        for &gen in &gens {
+            let log_cut = self.find_rollback_cut(gen, seq, cut.is_some())?;
+            if cut.is_none() {
+                cut = log_cut.map(|(offset, value_end)| (gen, offset, value_end));
+            }
+        }
+        let (cut_gen, offset, value_end) = match cut {
+            Some(cut) => cut,
+            None => return Ok(()),
+        };
+
+        // a headerless log gets the header, the index is rebuilt anyway
+        let (mut writer, _) = self.new_temp_generation(cut_gen)?;
+        let mut reader = BufReader::new(self.storage.open(cut_gen, LogKind::Log)?);
+        let header = match read_format_version(&mut reader)? {
+            0 => 0,
+            _ => FORMAT_HEADER_LEN,
+        };
+        if io::copy(&mut reader.take(offset - header), &mut writer)? != offset - header {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        let value_len = match self.value_writer {
+            Some(_) => self.storage.len(cut_gen, LogKind::ValueLog)?,
+            None => None,
+        };
+        let value_writer = match value_len {
+            Some(value_len) if value_len > value_end => {
+                let mut value_writer =
+                    new_temp_log_file(&*self.storage, cut_gen, LogKind::ValueLog)?;
+                let reader = self.storage.open(cut_gen, LogKind::ValueLog)?;
+                io::copy(&mut reader.take(value_end), &mut value_writer)?;
+                Some(value_writer)
+            }
+            _ => None,
+        };
+        self.persist_generation(cut_gen, writer, value_writer)?;
+        for &gen in gens.iter().rev().filter(|&&gen| gen > cut_gen) {
+            for kind in [LogKind::Log, LogKind::ValueLog] {
+                self.storage.remove(gen, kind)?;
+            }
+        }
+
+        // readers drop the handles of the old logs
+        self.reader.epoch.fetch_add(1, Ordering::SeqCst);
+        self.start_generation(self.current_gen + 1)?;
+        self.gen_count = gens.iter().filter(|&&gen| gen <= cut_gen).count() + 1;
+        self.update_manifest();
+
+        // the sequence numbers restart from the last one left
+        self.seq = 0;
+        self.rebuild_index()
+    }
+
+    /// Find where the log of `gen` is cut to roll back to `seq`: the offset
+    /// of the first record after `seq`, or of its transaction, and the end
+    /// of the values the records before it refer to. `None` if no record is
+    /// after `seq`.
+    ///
+    /// The records from the cut on, all of them if `past`, are checked to be
+    /// after `seq`, they'd be discarded otherwise.
+    fn find_rollback_cut(&self, gen: u64, seq: u64, past: bool) -> Result<Option<(u64, u64)>> {
+        let mut reader = BufReader::new(self.storage.open(gen, LogKind::Log)?);
+        let header = match read_format_version(&mut reader)? {
+            0 => 0,
+            _ => FORMAT_HEADER_LEN,
+        };
+        let mut cut = None;
+        let mut pos = header;
+        let mut txn_begin = None;
+        // the values of a transaction are kept once it's committed
+        let mut value_end = 0;
+        let mut txn_value_end = None;
+        let mut stream = Deserializer::from_reader(reader).into_iter::<ReplayCommand>();
+        while let Some(cmd) = stream.next() {
+            let end = header + stream.byte_offset() as u64;
+            let cmd = cmd?;
+            if cut.is_some() || past {
+                // markers have no sequence number
+                let marker = matches!(cmd, Command::TxnBegin | Command::TxnCommit | Command::Reset);
+                if !marker && cmd.seq() <= seq {
+                    return Err(KvsError::SeqOutOfOrder {
+                        seq,
+                        found: cmd.seq(),
+                        gen,
+                    });
+                }
+                continue;
+            }
+            match cmd {
+                Command::TxnBegin => {
+                    txn_begin = Some(pos);
+                    txn_value_end = Some(value_end);
+                }
+                Command::TxnCommit => {
+                    txn_begin = None;
+                    value_end = txn_value_end.take().unwrap_or(value_end);
+                }
+                Command::Reset => {}
+                cmd if cmd.seq() > seq => cut = Some((txn_begin.unwrap_or(pos), value_end)),
+                Command::SetRef {
+                    pos: value_pos,
+                    len,
+                    ..
+                } => {
+                    let end = txn_value_end.as_mut().unwrap_or(&mut value_end);
+                    *end = (*end).max(value_pos + len);
+                }
+                _ => {}
+            }
+            pos = end;
+        }
+        Ok(cut)
     }
 
-    /// Read the log file at the given `CommandPos`.
-    fn read_and<F, R>(&self, cmd_pos: &CommandPos, f: F) -> Result<R>
+    /// The smallest adjacent pair of `gens` within the size ratio.
+    fn tiered_pair(&self, gens: &[u64]) -> Result<Option<(u64, u64)>> {
+        let mut sizes = Vec::with_capacity(gens.len());
+        for &gen in gens {
+            sizes.push(self.generation_size(gen)?);
+        }
+        Ok(gens
+            .windows(2)
+            .zip(sizes.windows(2))
+            .filter(|(_, size)| size[0].max(size[1]) <= TIERED_SIZE_RATIO * size[0].min(size[1]))
+            .min_by_key(|(_, size)| size[0] + size[1])
+            .map(|(gen, _)| (gen[0], gen[1])))
+    }
+
+    fn generation_size(&self, gen: u64) -> Result<u64> {
+        let mut size = self.storage.len(gen, LogKind::Log)?.unwrap_or(0);
+        if self.value_writer.is_some() {
+            size += self.storage.len(gen, LogKind::ValueLog)?.unwrap_or(0);
+        }
+        Ok(size)
+    }
+
+    /// Copy the live records matching `filter` and the removals of `removed`
+    /// to the new generation `gen`, and point the index to the copies.
+    ///
+    /// Records adjacent in the same source log are copied as a single run,
+    /// saving a read and a write for each small record. With the split
+    /// layout, values go to the value log of `gen` and the log gets their
+    /// locations. The logs are written under temporary names and persisted
+    /// after a sync, so `open` never replays a partial generation. Records
+    /// written anew, the value locations and the removals, get the sequence
+    /// number `seq`. With `reset`, the log starts with a `Reset`. It gives
+    /// up between runs once `cancel` is cancelled. Returns the number of
+    /// bytes written.
+    ///
+    /// `gen` might replace a log readers have open, so they're told to drop
+    /// their handles before the index points to the copies.
+    fn copy_live_records<F>(
+        &self,
+        gen: u64,
+        filter: F,
+        removed: HashSet<String>,
+        reset: bool,
+        seq: u64,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<u64>
     where
-        F: FnOnce(io::Take<&mut BufReader<File>>) -> Result<R>,
+        F: Fn(&str, &CommandPos) -> bool,
     {
-        self.close_stale_files();
+        // with how many versions ago they are, 0 for the live ones
+        let mut records: Vec<(String, CommandPos, usize)> = self
+            .index
+            .iter()
+            .filter(|entry| filter(entry.key(), entry.value()))
+            .map(|entry| (entry.key().clone(), *entry.value(), 0))
+            .collect();
+        // versions go along with their live records only, they'd be
+        // replayed after the live one otherwise
+        if let Some(versions) = &self.versions {
+            let live: HashSet<String> = records.iter().map(|(key, ..)| key.clone()).collect();
+            records.extend(
+                versions
+                    .entries()
+                    .into_iter()
+                    .filter(|(key, cmd_pos, _)| live.contains(key) && filter(key, cmd_pos)),
+            );
+        }
+        records.sort_unstable_by_key(|(_, cmd_pos, _)| (cmd_pos.gen, cmd_pos.pos));
 
-        let mut readers = self.readers.borrow_mut();
-        if let std::collections::btree_map::Entry::Vacant(e) = readers.entry(cmd_pos.gen) {
-            let reader = BufReader::new(File::open(log_file_path(&self.path, cmd_pos.gen))?);
-            e.insert(reader);
+        let (mut writer, mut value_writer) = self.new_temp_generation(gen)?;
+        if reset {
+            write_record(&mut writer, &Command::reset(), self.pretty_records)?;
         }
+        let mut new_pos = match &mut value_writer {
+            Some(value_writer) if self.dedup_values => {
+                self.copy_values_deduped(&records, value_writer, cancel)?
+            }
+            _ => Vec::with_capacity(records.len()),
+        };
+        // the runs of adjacent records are copied at once
+        let mut start = new_pos.len();
+        while start < records.len() {
+            if let Some(cancel) = cancel {
+                cancel.check()?;
+            }
+            let first = records[start].1;
+            let mut run = first;
+            let mut end = start + 1;
+            while end < records.len()
+                && records[end].1.gen == run.gen
+                && records[end].1.pos == run.pos + run.len
+            {
+                run.len += records[end].1.len;
+                end += 1;
+            }
 
-        let reader = readers.get_mut(&cmd_pos.gen).unwrap();
-        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-        let cmd_reader = reader.take(cmd_pos.len);
+            let run_writer = value_writer.as_mut().unwrap_or(&mut writer);
+            let base = run_writer.pos;
+            let len = self
+                .reader
+                .read_and(&run, |mut rdr| Ok(io::copy(&mut rdr, &mut *run_writer)?))?;
+            if len != run.len {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            new_pos.extend(
+                records[start..end]
+                    .iter()
+                    .map(|(_, cmd_pos, _)| base + cmd_pos.pos - first.pos),
+            );
+            start = end;
+        }
+        if value_writer.is_some() {
+            // the oldest versions first, the live values last
+            let mut order: Vec<usize> = (0..records.len()).collect();
+            order.sort_by_key(|&i| Reverse(records[i].2));
+            for i in order {
+                let (key, cmd_pos, _) = &records[i];
+                let pos = new_pos[i];
+                write_record(
+                    &mut writer,
+                    &Command::set_ref(key.clone(), pos, cmd_pos.len, seq),
+                    self.pretty_records,
+                )?;
+            }
+        }
+        for key in removed {
+            write_record(&mut writer, &Command::remove(key, seq), self.pretty_records)?;
+        }
+        let written = self.persist_generation(gen, writer, value_writer)?;
+        // readers drop the handles of the old log before seeing new positions
+        self.reader.epoch.fetch_add(1, Ordering::SeqCst);
 
-        f(cmd_reader)
+        if let Some(filters) = &self.filters {
+            let live: Vec<&str> = records
+                .iter()
+                .filter(|(.., versions_ago)| *versions_ago == 0)
+                .map(|(key, ..)| key.as_str())
+                .collect();
+            filters.build(gen, live.into_iter());
+        }
+        for ((key, cmd_pos, versions_ago), pos) in records.into_iter().zip(new_pos) {
+            let new_cmd_pos = (gen, pos, cmd_pos.len).into();
+            match &self.versions {
+                Some(versions) if versions_ago > 0 => versions.relocate(&key, cmd_pos, new_cmd_pos),
+                _ => {
+                    self.index.insert(key, new_cmd_pos);
+                }
+            }
+        }
+        Ok(written)
     }
 
-    // fn read
-}
-
-/// WriteAgent singleton
-///
-/// Handles all set/remove requests from readers at multiple threads, works as
-/// a singleton for exclusive access, the writer itself is protected under a
-/// lock. Update the shared `IndexMap` during opertions for all readers (in
-/// different threads) to use.
-struct WriteAgent {
-    path: PathBuf,
-    current_gen: u64,
-    // read helper only used in compaction
-    reader: ReadAgent,
-    writer: BufWriterWithPos<File>,
-    // the number of bytes representing "stale" commands that could be
-    // deleted during a compaction.
-    stale_bytes: u64,
+    /// Create the temporary logs of generation `gen`, see
+    /// `persist_generation`.
+    fn new_temp_generation(&self, gen: u64) -> Result<(LogFileWriter, Option<LogFileWriter>)> {
+        let writer = new_temp_log_file(&*self.storage, gen, LogKind::Log)?;
+        let value_writer = match self.value_writer {
+            Some(_) => Some(new_temp_log_file(&*self.storage, gen, LogKind::ValueLog)?),
+            None => None,
+        };
+        Ok((writer, value_writer))
+    }
 
-    // index reference to KvsStore
-    index: Arc<IndexMap>,
-}
-impl WriteAgent {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
-        let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-        let len = self.writer.pos - pos;
+    /// Sync the temporary logs of generation `gen` and persist them, returns
+    /// the number of bytes written to them.
+    fn persist_generation(
+        &self,
+        gen: u64,
+        mut writer: LogFileWriter,
+        value_writer: Option<LogFileWriter>,
+    ) -> Result<u64> {
+        let mut written = 0;
+        // values go first, the log must never refer to a missing value
+        if let Some(mut value_writer) = value_writer {
+            value_writer.sync()?;
+            self.storage.persist_temp(gen, LogKind::ValueLog)?;
+            written += value_writer.pos;
+        }
+        writer.sync()?;
+        self.storage.persist_temp(gen, LogKind::Log)?;
+        Ok(written + writer.pos)
+    }
 
-        if let Command::Set { key, .. } = cmd {
-            if let Some(cmd_pos) = self.index.insert(key, (self.current_gen, pos, len).into()) {
-                // overwritten case
-                self.stale_bytes += cmd_pos.len;
+    /// Copy the values of `records` to `value_writer` storing equal ones
+    /// once, returns their new positions.
+    ///
+    /// A value is looked up by its hash and length among the ones copied,
+    /// then compared with the first copy byte for byte, read again from its
+    /// old position. Records sharing a position already share it again.
+    fn copy_values_deduped(
+        &self,
+        records: &[(String, CommandPos, usize)],
+        value_writer: &mut BufWriterWithPos<Box<dyn LogWriter>>,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<Vec<u64>> {
+        let read = |cmd_pos: &CommandPos| {
+            self.reader.read_and(cmd_pos, |mut rdr| {
+                let mut bytes = Vec::with_capacity(cmd_pos.len as usize);
+                rdr.read_to_end(&mut bytes)?;
+                Ok(bytes)
+            })
+        };
+        let mut by_pos: HashMap<CommandPos, u64> = HashMap::new();
+        // the old and the new positions of the values copied
+        let mut by_hash: HashMap<(u64, u64), Vec<(CommandPos, u64)>> = HashMap::new();
+        let mut new_pos = Vec::with_capacity(records.len());
+        for (_, cmd_pos, _) in records {
+            if let Some(&pos) = by_pos.get(cmd_pos) {
+                new_pos.push(pos);
+                continue;
+            }
+            if let Some(cancel) = cancel {
+                cancel.check()?;
+            }
+            let bytes = read(cmd_pos)?;
+            if bytes.len() as u64 != cmd_pos.len {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            let mut hash = Fnv1a::new();
+            hash.update(&bytes);
+            let copies = by_hash.entry((hash.0, cmd_pos.len)).or_default();
+            let mut shared = None;
+            for (old_pos, pos) in copies.iter() {
+                if read(old_pos)? == bytes {
+                    shared = Some(*pos);
+                    break;
+                }
             }
+            let pos = match shared {
+                Some(pos) => pos,
+                None => {
+                    let pos = value_writer.pos;
+                    value_writer.write_all(&bytes)?;
+                    copies.push((*cmd_pos, pos));
+                    pos
+                }
+            };
+            by_pos.insert(*cmd_pos, pos);
+            new_pos.push(pos);
         }
+        Ok(new_pos)
+    }
 
-        if self.stale_bytes > COMPACTION_THRESHOLD {
-            self.compact()?;
+    /// Merge the adjacent generations `first` and `second` into `first`,
+    /// returns the number of bytes reclaimed.
+    fn merge_generations(&mut self, first: u64, second: u64) -> Result<u64> {
+        let before = self.generation_size(first)? + self.generation_size(second)?;
+        let in_pair = |cmd_pos: &CommandPos| cmd_pos.gen == first || cmd_pos.gen == second;
+
+        // the merged generation takes the place of the pair, so the removals
+        // of keys which are not set anymore are kept for older generations
+        let mut removed = HashSet::new();
+        let mut seq = 0;
+        let mut reset = false;
+        for gen in [first, second] {
+            let mut reader = BufReader::new(self.storage.open(gen, LogKind::Log)?);
+            let (last_seq, has_reset) = scan_removed_keys(&mut reader, &mut removed)?;
+            seq = seq.max(last_seq);
+            reset |= has_reset;
+        }
+        removed.retain(|key| !self.index.contains_key(key));
+        // the removals copied are plain ones, and the values in the merged
+        // generations are left behind
+        let untrashed: Vec<String> = removed
+            .iter()
+            .filter(|key| self.trash.contains_key(*key))
+            .cloned()
+            .collect();
+        // versions are only copied along with their live records
+        if let Some(versions) = &self.versions {
+            let live: HashSet<String> = self
+                .index
+                .iter()
+                .filter(|entry| in_pair(entry.value()))
+                .map(|entry| entry.key().clone())
+                .collect();
+            versions.retain(|key, cmd_pos| !in_pair(cmd_pos) || live.contains(key));
         }
-        // println!("set: {:?}", serde_json::to_string(&cmd).unwrap());
-        Ok(())
-    }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        // don't remove the key immediately, make sure writer successful first!
-        if !self.index.contains_key(&key) {
-            // println!("not find key: {:?}", key);
-            return Err(KvsError::KeyNotFound);
+        // records written anew get the last number of the pair, so the
+        // numbers still grow along the logs
+        self.written_bytes += self.copy_live_records(
+            first,
+            |_, cmd_pos| in_pair(cmd_pos),
+            removed,
+            reset,
+            seq,
+            None,
+        )?;
+        if let Some(filters) = &self.filters {
+            filters.retain(|gen| gen != second);
+        }
+        for key in untrashed {
+            self.trash.remove(&key);
         }
+        self.trash.retain(|_, cmd_pos| !in_pair(cmd_pos));
 
-        // println!("find key: {:?}", &key);
-        let cmd = Command::remove(key);
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
+        for kind in [LogKind::Log, LogKind::ValueLog] {
+            if let Err(err) = self.storage.remove(second, kind) {
+                warn!("Failed to remove file: {}", err);
+            }
+        }
+        Ok(before.saturating_sub(self.generation_size(first)?))
+    }
+}
 
-        // flushed, now we're safe to remove the key
-        if let Command::Remove { key } = cmd {
-            if let Some((_, cmd_pos)) = self.index.remove(&key) {
-                self.stale_bytes += cmd_pos.len;
+impl Drop for WriteAgent {
+    fn drop(&mut self) {
+        let sync = self.fsync_on_drop || self.group.as_ref().is_some_and(GroupCommit::is_pending);
+        let writers = std::iter::once(&mut self.writer).chain(self.value_writer.as_mut());
+        for writer in writers {
+            let res = if sync { writer.sync() } else { writer.flush() };
+            if let Err(err) = res {
+                warn!("Failed to flush the log on drop: {}", err);
             }
         }
-        Ok(())
     }
+}
 
-    /// Collect space by writing entries to a new log file then remove old log
-    /// files, staled bytes then gone.
-    fn compact(&mut self) -> Result<()> {
-        // current_gen + 1 for the compaction log.
-        let compaction_gen = self.current_gen + 1;
-        self.current_gen += 2;
-        self.writer = new_log_file(&self.path, self.current_gen)?;
+impl IntoIterator for KvStore {
+    type Item = Result<(String, String)>;
+    type IntoIter = Dump;
 
-        // write all KV to a new log file.
-        let mut compaction_writer = new_log_file(&self.path, compaction_gen)?;
-        let mut new_pos = 0;
-        for mut cmd_pos in self.index.iter_mut() {
-            let len = self.reader.read_and(&cmd_pos, |mut rdr| {
-                Ok(io::copy(&mut rdr, &mut compaction_writer)?)
-            })?;
-            *cmd_pos = (compaction_gen, new_pos, len).into();
-            new_pos = compaction_writer.pos;
+    /// Consumes the store into an iterator over all the key/value pairs in
+    /// key order, see `Dump`.
+    fn into_iter(self) -> Dump {
+        let mut keys: Vec<String> = self.index.iter().map(|entry| entry.key().clone()).collect();
+        keys.sort_unstable();
+        Dump {
+            store: self,
+            keys: keys.into_iter(),
         }
-        compaction_writer.flush()?;
-
-        // after update `first_gen`, all `ReadAgent`s will sense it and
-        // close files' handles of stale generations
-        self.reader
-            .first_gen
-            .store(compaction_gen, Ordering::SeqCst);
-        self.reader.close_stale_files();
+    }
+}
 
-        // remove stale log files
-        // Note that actually these files are not deleted immediately because `ReadAgent`s
-        // still keep open file handles. When `ReadAgent` is used next time, it will clear
-        // its stale file handles. On Unix, the files will be deleted after all the handles
-        // are closed. On Windows, the deletions below will fail and stale files are expected
-        // to be deleted in the next compaction.
-        let stale_gens = sorted_gen_list(&self.path)?
-            .into_iter()
-            .filter(|gen| gen < &compaction_gen);
+/// An iterator dumping a consumed `KvStore`.
+///
+/// The keys are snapshotted when it's created and each value is read from
+/// the log when it's reached. Writes of other clones still show up, a key
+/// removed by then is skipped. A value failing to read is yielded as an
+/// `Err` and the iteration goes on.
+pub struct Dump {
+    store: KvStore,
+    keys: std::vec::IntoIter<String>,
+}
+impl Iterator for Dump {
+    type Item = Result<(String, String)>;
 
-        for stale_gen in stale_gens {
-            // `remove_file` might return error on windows, but would succeed
-            // eventually at later compactions
-            let file_path = log_file_path(&self.path, stale_gen);
-            if let Err(err) = fs::remove_file(&file_path) {
-                warn!("Failed to remove file: {}", err);
-            }
+    fn next(&mut self) -> Option<Self::Item> {
+        for key in self.keys.by_ref() {
+            let cmd_pos = match self.store.index.get(&key) {
+                Some(cmd_pos) => *cmd_pos,
+                None => continue,
+            };
+            return Some(
+                self.store
+                    .reader
+                    .read_value(&key, &cmd_pos)
+                    .map(|value| (key, value)),
+            );
         }
+        None
+    }
 
-        // fresh as new born
-        self.stale_bytes = 0;
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.keys.len()))
+    }
+}
 
-        Ok(())
+/// What `KvStore::open_verbose` did to recover the store.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OpenReport {
+    /// The number of logs replayed, the ones covered by a checkpoint aren't.
+    pub generations: usize,
+    /// The number of records replayed, transaction markers included.
+    pub records: u64,
+    /// The stale bytes found, which a compaction would reclaim.
+    pub stale_bytes: u64,
+    /// Anything surprising, e.g. an empty log, an outdated checkpoint or a
+    /// transaction cut off by a crash. They're logged too.
+    pub warnings: Vec<String>,
+}
+impl OpenReport {
+    fn warn(&mut self, warning: String) {
+        warn!("{}", warning);
+        self.warnings.push(warning);
     }
 }
 
+/// State carried over the logs during `open`.
+#[derive(Default)]
+struct Replay {
+    // the largest sequence number seen
+    seq: u64,
+    // the last generation with a `Reset`
+    reset_gen: Option<u64>,
+    // stop at an unreadable record with a warning instead of failing
+    lenient: bool,
+    // previous values of the keys, if enabled
+    versions: Option<Arc<Versions>>,
+    // the values of the soft-removed keys
+    trash: HashMap<String, CommandPos>,
+    report: OpenReport,
+}
+
+/// The sealed generations of `KvStore::checkpoint_barrier`, which make up
+/// a consistent snapshot.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CheckpointId {
+    /// The generations in ascending order.
+    pub generations: Vec<u64>,
+    /// The names of their logs and value logs in the store directory, the
+    /// files to copy for a backup.
+    pub files: Vec<String>,
+}
+
+/// The bytes made stale by `KvStore::remove_with_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RemoveStats {
+    /// The length of the `Remove` record written.
+    pub tombstone_bytes: u64,
+    /// The length of the removed record, or the value with the split layout,
+    /// plus the retained versions of the key.
+    pub freed_bytes: u64,
+}
+
+/// The result of `KvStore::compaction_preview`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompactionPreview {
+    /// The stale bytes a compaction would reclaim.
+    pub reclaimable_bytes: u64,
+    /// The number of live records to copy.
+    pub live_entries: usize,
+    /// Roughly the size of the compacted generation. With the split layout
+    /// it's the value log, the value locations in the log come on top.
+    pub output_bytes: u64,
+}
+
+/// The result of `KvStore::import_preview`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImportPreview {
+    /// The number of keys not in the store.
+    pub new_keys: usize,
+    /// The number of keys whose values would change.
+    pub overwritten: usize,
+    /// The first keys in key order whose values would change, at most 10.
+    pub overwritten_sample: Vec<String>,
+    /// The number of keys already set to the same value.
+    pub identical: usize,
+}
+
+/// An operation of `KvStore::transaction`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    /// Sets the value of a key.
+    Set {
+        /// The key to set
+        key: String,
+        /// The new value
+        value: String,
+    },
+    /// Removes a key.
+    Remove {
+        /// The key to remove
+        key: String,
+    },
+}
+
+/// Periodically persists the index of a `WriteAgent`.
+struct Checkpointer {
+    path: PathBuf,
+    interval: Duration,
+    // `Clock::now_ms` of the last checkpoint
+    last_ms: u64,
+}
+
 /// Struct representing a command.
+///
+/// Replaying the log doesn't need values, they're skipped as `ReplayCommand`.
 #[derive(Debug, Serialize, Deserialize)]
-enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+enum Command<V = String> {
+    // `seq` is 0 in the logs written before sequence numbers
+    Set {
+        key: String,
+        value: V,
+        #[serde(default)]
+        seq: u64,
+    },
+    Remove {
+        key: String,
+        #[serde(default)]
+        seq: u64,
+    },
+    // `Set` with the split layout, the value is at `pos` of the value log
+    SetRef {
+        key: String,
+        pos: u64,
+        len: u64,
+        #[serde(default)]
+        seq: u64,
+    },
+    // hides the key till it's set again, the value stays in place until
+    // the next compaction for `KvStore::undelete`
+    SoftRemove {
+        key: String,
+        #[serde(default)]
+        seq: u64,
+    },
+    // commands till `TxnCommit` are applied all or nothing
+    TxnBegin,
+    TxnCommit,
+    // the log holds the whole content, older generations are obsolete
+    Reset,
 }
 impl Command {
-    fn set(key: String, value: String) -> Self {
-        Command::Set { key, value }
+    fn remove(key: String, seq: u64) -> Self {
+        Command::Remove { key, seq }
+    }
+
+    fn soft_remove(key: String, seq: u64) -> Self {
+        Command::SoftRemove { key, seq }
+    }
+
+    fn set_ref(key: String, pos: u64, len: u64, seq: u64) -> Self {
+        Command::SetRef { key, pos, len, seq }
+    }
+
+    fn txn_begin() -> Self {
+        Command::TxnBegin
+    }
+
+    fn txn_commit() -> Self {
+        Command::TxnCommit
+    }
+
+    fn reset() -> Self {
+        Command::Reset
+    }
+}
+impl<V> Command<V> {
+    fn into_key(self) -> String {
+        match self {
+            Command::Set { key, .. }
+            | Command::Remove { key, .. }
+            | Command::SoftRemove { key, .. }
+            | Command::SetRef { key, .. } => key,
+            Command::TxnBegin | Command::TxnCommit | Command::Reset => String::new(),
+        }
     }
 
-    fn remove(key: String) -> Self {
-        Command::Remove { key }
+    /// The sequence number, markers have none.
+    fn seq(&self) -> u64 {
+        match self {
+            Command::Set { seq, .. }
+            | Command::Remove { seq, .. }
+            | Command::SoftRemove { seq, .. }
+            | Command::SetRef { seq, .. } => *seq,
+            Command::TxnBegin | Command::TxnCommit | Command::Reset => 0,
+        }
     }
 }
 
+type ReplayCommand = Command<IgnoredAny>;
+
+/// A `Command` borrowing its fields, only for writing.
+///
+/// It's serialized the same as the `Command` of the same variant.
+#[derive(Serialize)]
+enum CommandRef<'a> {
+    Set {
+        key: &'a str,
+        value: &'a str,
+        seq: u64,
+    },
+    SetRef {
+        key: &'a str,
+        pos: u64,
+        len: u64,
+        seq: u64,
+    },
+}
+
 /// Represents the position and length of a (json)serialized command in the log.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 struct CommandPos {
     gen: u64,
     pos: u64,
@@ -375,6 +4181,37 @@ impl From<(u64, u64, u64)> for CommandPos {
     }
 }
 
+impl From<CommandPosInfo> for CommandPos {
+    fn from(info: CommandPosInfo) -> Self {
+        CommandPos {
+            gen: info.gen,
+            pos: info.pos,
+            len: info.len,
+        }
+    }
+}
+
+/// The location of a record in the logs, or of a value in the value logs
+/// with the split layout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CommandPosInfo {
+    /// The generation of the log.
+    pub gen: u64,
+    /// The offset in the log.
+    pub pos: u64,
+    /// The length in bytes.
+    pub len: u64,
+}
+impl From<CommandPos> for CommandPosInfo {
+    fn from(cmd_pos: CommandPos) -> Self {
+        CommandPosInfo {
+            gen: cmd_pos.gen,
+            pos: cmd_pos.pos,
+            len: cmd_pos.len,
+        }
+    }
+}
+
 // trace pos to reduce several calls to seek for performance
 // struct BufReaderWithPos<R: Read + Seek> {
 //     inner: BufReader<R>,
@@ -404,6 +4241,37 @@ impl From<(u64, u64, u64)> for CommandPos {
 //     }
 // }
 
+type LogFileWriter = BufWriterWithPos<Box<dyn LogWriter>>;
+
+// counts the bytes written to nowhere, to measure serialized records
+struct ByteCounter(u64);
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// 64-bit FNV-1a, stable across platforms and Rust versions unlike the
+// hasher of std
+struct Fnv1a(u64);
+impl Fnv1a {
+    fn new() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
 // trace pos/len because `serde_json::to_write()` doesn't return written size
 struct BufWriterWithPos<W: Write + Seek> {
     inner: BufWriter<W>,
@@ -411,7 +4279,7 @@ struct BufWriterWithPos<W: Write + Seek> {
 }
 impl<W: Write + Seek> BufWriterWithPos<W> {
     fn new(mut inner: W) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
         Ok(BufWriterWithPos {
             inner: BufWriter::new(inner),
             pos,
@@ -419,6 +4287,21 @@ impl<W: Write + Seek> BufWriterWithPos<W> {
     }
 }
 
+impl<W: Write + Seek> BufWriterWithPos<W> {
+    /// The position up to which the data is handed to the inner writer.
+    fn flushed_pos(&self) -> u64 {
+        self.pos - self.inner.buffer().len() as u64
+    }
+}
+
+impl BufWriterWithPos<Box<dyn LogWriter>> {
+    /// Flush and make the written data durable.
+    fn sync(&mut self) -> io::Result<()> {
+        self.inner.flush()?;
+        self.inner.get_mut().sync()
+    }
+}
+
 impl<W: Write + Seek> Write for BufWriterWithPos<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let len = self.inner.write(buf)?;
@@ -438,27 +4321,132 @@ impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
     }
 }
 
+/// Sync the pending group of writes every `interval`, until the writer is
+/// dropped with the last clone of the store.
+///
+/// A failed sync is logged and retried at the next tick.
+fn sync_periodically(writer: &Weak<Mutex<WriteAgent>>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => return,
+        };
+        let mut writer = writer.lock().unwrap();
+        if !writer.group.as_ref().is_some_and(GroupCommit::is_pending) {
+            continue;
+        }
+        if let Err(err) = writer.sync_group() {
+            warn!(
+                "Failed to sync a group of writes in the background: {}",
+                err
+            );
+        }
+    }
+}
+
+/// Compact every `interval` if the policy calls for it, until the writer is
+/// dropped with the last clone of the store.
+///
+/// It only holds the writer while checking, so the store isn't kept alive.
+/// A failed compaction is logged and retried at the next tick.
+fn compact_periodically(writer: &Weak<Mutex<WriteAgent>>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => return,
+        };
+        let mut writer = writer.lock().unwrap();
+        if writer.reader.is_poisoned() || !writer.needs_compaction() {
+            continue;
+        }
+        if let Err(err) = writer.compact() {
+            warn!("Failed to compact in the background: {}", err);
+        }
+    }
+}
+
 fn log_file_path(dir: &Path, gen: u64) -> PathBuf {
     dir.join(format!("{}.log", gen))
 }
 
-/// Create a new log file with given generation number and add the reader to the readers map.
+fn value_log_file_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.vlog", gen))
+}
+
+/// Serialize a record of the log, pretty-printed and followed by a newline
+/// if `pretty`.
+fn write_record(writer: &mut impl Write, record: &impl Serialize, pretty: bool) -> Result<()> {
+    if pretty {
+        serde_json::to_writer_pretty(&mut *writer, record)?;
+        writer.write_all(b"\n")?;
+    } else {
+        serde_json::to_writer(writer, record)?;
+    }
+    Ok(())
+}
+
+/// Create a new log file with given generation number and kind.
 ///
 /// Returns the writer to the log.
-fn new_log_file(path: &Path, gen: u64) -> Result<BufWriterWithPos<File>> {
-    let filepath = log_file_path(path, gen);
-    let file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .append(true)
-        .open(&filepath)?;
+fn new_log_file(storage: &dyn LogStorage, gen: u64, kind: LogKind) -> Result<LogFileWriter> {
+    log_file_writer(storage.create(gen, kind)?, kind)
+}
+
+/// Create the log of generation `gen`, and its value log with the split
+/// layout.
+fn new_generation(
+    storage: &dyn LogStorage,
+    gen: u64,
+    split_values: bool,
+) -> Result<(LogFileWriter, Option<LogFileWriter>)> {
+    let writer = new_log_file(storage, gen, LogKind::Log)?;
+    let value_writer = if split_values {
+        Some(new_log_file(storage, gen, LogKind::ValueLog)?)
+    } else {
+        None
+    };
+    Ok((writer, value_writer))
+}
+
+/// Create a temporary log file, see `LogStorage::create_temp`.
+fn new_temp_log_file(storage: &dyn LogStorage, gen: u64, kind: LogKind) -> Result<LogFileWriter> {
+    log_file_writer(storage.create_temp(gen, kind)?, kind)
+}
 
-    BufWriterWithPos::new(file)
+fn log_file_writer(inner: Box<dyn LogWriter>, kind: LogKind) -> Result<LogFileWriter> {
+    let mut writer = BufWriterWithPos::new(inner)?;
+    // value logs are only read at given locations, they have no header
+    if kind == LogKind::Log && writer.pos == 0 {
+        writer.write_all(FORMAT_MAGIC)?;
+        writer.write_all(&KvStore::FORMAT_VERSION.to_le_bytes())?;
+        writer.flush()?;
+    }
+    Ok(writer)
+}
+
+/// Read the format header at the start of a log.
+///
+/// Returns the format version, the reader is left after the header. A log
+/// without the header is version 0, nothing is consumed then.
+fn read_format_version(reader: &mut impl BufRead) -> Result<u32> {
+    let buf = reader.fill_buf()?;
+    if !buf.starts_with(FORMAT_MAGIC) || buf.len() < FORMAT_HEADER_LEN as usize {
+        return Ok(0);
+    }
+    let mut version = [0; 4];
+    version.copy_from_slice(&buf[FORMAT_MAGIC.len()..FORMAT_HEADER_LEN as usize]);
+    reader.consume(FORMAT_HEADER_LEN as usize);
+    Ok(u32::from_le_bytes(version))
 }
 
 /// Returns sorted generation numbers in the given directory.
+///
+/// It returns `KvsError::DuplicateGeneration` if several logs claim the same
+/// generation, e.g. one with a leading zero.
 fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
-    let mut gen_list: Vec<u64> = fs::read_dir(&path)?
+    let mut gen_list: Vec<u64> = fs::read_dir(path)?
         .flat_map(|res| -> Result<_> { Ok(res?.path()) })
         .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
         .flat_map(|path| {
@@ -471,41 +4459,245 @@ fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
         .collect();
 
     gen_list.sort_unstable();
+    // e.g. `1.log` and `01.log`, only one of them would ever be read
+    if let Some(pair) = gen_list.windows(2).find(|pair| pair[0] == pair[1]) {
+        return Err(KvsError::DuplicateGeneration { gen: pair[0] });
+    }
     Ok(gen_list)
 }
 
-/// Load the whole log file and store value locations in the index map.
+/// Load the log file from `start` and store value locations in the index map.
+///
+/// With the split layout, locations point into the value log. Commands in a
+/// transaction are applied only when its `TxnCommit` is reached.
 ///
-/// Returns how many bytes can be saved after a compaction.
+/// A `Set` longer than `max_record_len` is taken as corruption. With the
+/// split layout, it's the length of the value it claims. A `Reset` clears
+/// the index, what's replayed before is obsolete.
+///
+/// Returns how many bytes can be saved after a compaction, `max_seq` is
+/// raised to the largest sequence number seen.
 fn load_log(
     gen: u64,
-    reader: &mut BufReader<File>,
+    reader: &mut BufReader<Box<dyn LogReader>>,
+    start: u64,
     index: &IndexMap, //&mut BTreeMap<String, CommandPos>
+    split_values: bool,
+    max_record_len: Option<u64>,
+    replay: &mut Replay,
 ) -> Result<u64> {
-    // To make sure we read from the beginning of the file.
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
+    let start = if start == 0 {
+        reader.seek(SeekFrom::Start(0))?;
+        match read_format_version(reader)? {
+            0 => 0,
+            KvStore::FORMAT_VERSION => FORMAT_HEADER_LEN,
+            found => {
+                return Err(KvsError::UnsupportedFormat {
+                    found,
+                    supported: KvStore::FORMAT_VERSION,
+                })
+            }
+        }
+    } else {
+        start
+    };
+    // To make sure we read from the given position of the file.
+    let mut pos = reader.seek(SeekFrom::Start(start))?;
+    let mut stream = Deserializer::from_reader(reader).into_iter::<ReplayCommand>();
+    // commands of the open transaction with their positions and lengths
+    let mut txn: Option<Vec<(ReplayCommand, u64, u64)>> = None;
     let mut stale_bytes = 0; // number of bytes that can be saved after a compaction.
 
     while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set { key, .. } => {
-                if let Some(old) = index.insert(key, (gen, pos, new_pos - pos).into()) {
-                    stale_bytes += old.len;
+        let new_pos = start + stream.byte_offset() as u64;
+        let len = new_pos - pos;
+        let cmd = match cmd {
+            Ok(cmd) => cmd,
+            Err(err) if replay.lenient && !err.is_io() => {
+                replay.report.warn(format!(
+                    "Unreadable record at {} of generation {}, the rest is skipped: {}",
+                    pos, gen, err
+                ));
+                break;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        replay.seq = replay.seq.max(cmd.seq());
+        replay.report.records += 1;
+        let record_len = match cmd {
+            Command::Set { .. } => len,
+            Command::SetRef { len, .. } => len,
+            _ => 0,
+        };
+        if let Some(max) = max_record_len.filter(|&max| record_len > max) {
+            warn!(
+                "Record of {} bytes at {} of generation {} is over the limit",
+                record_len, pos, gen
+            );
+            return Err(KvsError::RecordTooLarge {
+                len: record_len,
+                max,
+            });
+        }
+        match (cmd, &mut txn) {
+            (Command::TxnBegin, txn) => {
+                // a transaction never committed is discarded
+                if let Some(ops) = txn.replace(Vec::new()) {
+                    stale_bytes += ops.iter().map(|(_, _, len)| len).sum::<u64>();
+                }
+                stale_bytes += len;
+            }
+            (Command::TxnCommit, txn) => {
+                for (cmd, pos, len) in txn.take().unwrap_or_default() {
+                    stale_bytes += apply_command(gen, cmd, pos, len, index, split_values, replay)?;
                 }
+                stale_bytes += len;
             }
-            Command::Remove { key } => {
-                if let Some((_, old)) = index.remove(&key) {
-                    stale_bytes += old.len;
+            (Command::Reset, _) => {
+                index.clear();
+                if let Some(versions) = &replay.versions {
+                    versions.clear();
                 }
-                // the "remove" command itself can be deleted in the next compaction.
-                // so we add its length to `uncompacted`.
-                stale_bytes += new_pos - pos;
+                replay.trash.clear();
+                replay.reset_gen = Some(gen);
+                stale_bytes = len;
+            }
+            (cmd, Some(ops)) => ops.push((cmd, pos, len)),
+            (cmd, None) => {
+                stale_bytes += apply_command(gen, cmd, pos, len, index, split_values, replay)?
             }
         }
         pos = new_pos;
     }
+    if let Some(ops) = txn {
+        stale_bytes += ops.iter().map(|(_, _, len)| len).sum::<u64>();
+        replay.report.warn(format!(
+            "Transaction of {} operations at {} of generation {} is never committed, discarded",
+            ops.len(),
+            pos,
+            gen
+        ));
+    }
+
+    Ok(stale_bytes)
+}
+
+/// Collect the keys whose last committed command in the log is a removal,
+/// returns the highest sequence number of the log and whether it has a
+/// `Reset`.
+///
+/// A key set again is taken out of `removed`, so logs are scanned in order.
+fn scan_removed_keys(
+    reader: &mut BufReader<Box<dyn LogReader>>,
+    removed: &mut HashSet<String>,
+) -> Result<(u64, bool)> {
+    read_format_version(reader)?;
+    let mut seq = 0;
+    let mut reset = false;
+    let mut txn: Option<Vec<ReplayCommand>> = None;
+    let mut apply = |cmd: ReplayCommand| match cmd {
+        Command::Remove { key, .. } | Command::SoftRemove { key, .. } => {
+            removed.insert(key);
+        }
+        Command::Set { key, .. } | Command::SetRef { key, .. } => {
+            removed.remove(&key);
+        }
+        // nothing older is replayed
+        Command::Reset => removed.clear(),
+        Command::TxnBegin | Command::TxnCommit => {}
+    };
+    for cmd in Deserializer::from_reader(reader).into_iter::<ReplayCommand>() {
+        let cmd = cmd?;
+        seq = seq.max(cmd.seq());
+        reset |= matches!(cmd, Command::Reset);
+        match (cmd, &mut txn) {
+            (Command::TxnBegin, txn) => *txn = Some(Vec::new()),
+            (Command::TxnCommit, txn) => txn
+                .take()
+                .unwrap_or_default()
+                .into_iter()
+                .for_each(&mut apply),
+            (cmd, Some(ops)) => ops.push(cmd),
+            (cmd, None) => apply(cmd),
+        }
+    }
+    Ok((seq, reset))
+}
+
+/// Collect the keys set by any command of the log, committed or not.
+fn scan_set_keys(
+    reader: &mut BufReader<Box<dyn LogReader>>,
+    keys: &mut HashSet<String>,
+) -> Result<()> {
+    read_format_version(reader)?;
+    for cmd in Deserializer::from_reader(reader).into_iter::<ReplayCommand>() {
+        if let Command::Set { key, .. } | Command::SetRef { key, .. } = cmd? {
+            keys.insert(key);
+        }
+    }
+    Ok(())
+}
 
+/// Apply a command at `pos` of the log to the index map.
+///
+/// An overwritten value is kept in the versions of `replay` if enabled,
+/// it's stale only once it falls out. The value of a soft-removed key goes
+/// to its trash.
+///
+/// Returns how many bytes become stale.
+fn apply_command(
+    gen: u64,
+    cmd: ReplayCommand,
+    pos: u64,
+    len: u64,
+    index: &IndexMap,
+    split_values: bool,
+    replay: &mut Replay,
+) -> Result<u64> {
+    let versions = replay.versions.as_deref();
+    let trash = &mut replay.trash;
+    let mut stale_bytes = 0;
+    let soft = matches!(cmd, Command::SoftRemove { .. });
+    let (key, cmd_pos) = match cmd {
+        Command::Set { key, .. } if !split_values => (key, (gen, pos, len).into()),
+        Command::SetRef {
+            key,
+            pos: value_pos,
+            len: value_len,
+            ..
+        } if split_values => (key, (gen, value_pos, value_len).into()),
+        // the log was written with the other layout
+        Command::Set { .. } | Command::SetRef { .. } => {
+            return Err(KvsError::UnexpectedCommandType)
+        }
+        Command::Remove { key, .. } | Command::SoftRemove { key, .. } => {
+            if let Some((_, old)) = index.remove(&key) {
+                stale_bytes += old.len;
+                if soft {
+                    trash.insert(key.clone(), old);
+                }
+            }
+            if !soft {
+                trash.remove(&key);
+            }
+            if let Some(versions) = versions {
+                stale_bytes += versions.forget(&key);
+            }
+            // the "remove" command itself can be deleted in the next compaction.
+            // so we add its length to `uncompacted`.
+            stale_bytes += len;
+            return Ok(stale_bytes);
+        }
+        // markers are handled by `load_log`
+        Command::TxnBegin | Command::TxnCommit | Command::Reset => return Ok(0),
+    };
+    trash.remove(&key);
+    let versioned_key = versions.map(|_| key.clone());
+    if let Some(old) = index.insert(key, cmd_pos) {
+        stale_bytes += match (versions, versioned_key) {
+            (Some(versions), Some(key)) => versions.push(&key, old).map_or(0, |old| old.len),
+            _ => old.len,
+        };
+    }
     Ok(stale_bytes)
 }
@@ -0,0 +1,49 @@
+use std::sync::Arc;
+
+use dashmap::DashMap;
+
+use super::Clock;
+
+/// Wall-clock time of the last access of the keys, see
+/// `KvStoreOptions::track_access_times`.
+///
+/// The times aren't persisted, keys not accessed since `open` have none.
+#[derive(Debug)]
+pub(super) struct AccessTimes {
+    clock: Arc<dyn Clock>,
+    times: DashMap<String, u64>,
+}
+
+impl AccessTimes {
+    pub(super) fn new(clock: Arc<dyn Clock>) -> Self {
+        AccessTimes {
+            clock,
+            times: DashMap::new(),
+        }
+    }
+
+    /// Marks a key accessed by a read.
+    pub(super) fn touch(&self, key: &str) {
+        let now = self.clock.now_ms();
+        match self.times.get_mut(key) {
+            Some(mut entry) => *entry = now,
+            None => {
+                self.times.insert(key.to_owned(), now);
+            }
+        }
+    }
+
+    /// Marks a key accessed by a write.
+    pub(super) fn touch_owned(&self, key: String) {
+        self.times.insert(key, self.clock.now_ms());
+    }
+
+    pub(super) fn forget(&self, key: &str) {
+        self.times.remove(key);
+    }
+
+    /// Milliseconds since the Unix epoch of the last access of `key`.
+    pub(super) fn get(&self, key: &str) -> Option<u64> {
+        self.times.get(key).map(|ms| *ms)
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::sync::RwLock;
+
+use super::IndexMap;
+
+// about 1% false positives with 7 hashes
+const BITS_PER_KEY: usize = 10;
+const HASHES: u64 = 7;
+// a generation starts with room for this many keys and doubles when full
+const MIN_CAPACITY: usize = 1024;
+
+/// A bloom filter of keys, sized for a number of keys.
+#[derive(Debug)]
+pub(super) struct BloomFilter {
+    bits: Vec<u64>,
+    capacity: usize,
+    len: usize,
+}
+impl BloomFilter {
+    pub(super) fn new(capacity: usize) -> Self {
+        let words = (capacity.max(1) * BITS_PER_KEY).div_ceil(64);
+        BloomFilter {
+            bits: vec![0; words],
+            capacity,
+            len: 0,
+        }
+    }
+
+    pub(super) fn insert(&mut self, key: &str) {
+        for bit in self.bit_positions(key) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        self.len += 1;
+    }
+
+    /// Whether `key` might have been inserted, it's never `false` for one
+    /// that has been.
+    pub(super) fn may_contain(&self, key: &str) -> bool {
+        self.bit_positions(key)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    fn is_full(&self) -> bool {
+        self.len >= self.capacity
+    }
+
+    // double hashing, the two halves of a single hash
+    fn bit_positions(&self, key: &str) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        let (h1, h2) = (hash & 0xffff_ffff, hash >> 32);
+        let nbits = self.bits.len() as u64 * 64;
+        (0..HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % nbits) as usize)
+    }
+}
+
+/// The bloom filters of the keys of each generation.
+///
+/// A key is added before the index refers to it, and a filter is dropped
+/// only after the index doesn't refer to its generation anymore, so a key
+/// of the index is never missed.
+#[derive(Debug, Default)]
+pub(super) struct BloomFilters {
+    filters: RwLock<BTreeMap<u64, BloomFilter>>,
+}
+impl BloomFilters {
+    /// Filters of the generations `index` refers to.
+    pub(super) fn from_index(index: &IndexMap) -> Self {
+        let mut keys: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+        for entry in index.iter() {
+            keys.entry(entry.gen).or_default().push(entry.key().clone());
+        }
+        let filters = BloomFilters::default();
+        for (gen, keys) in keys {
+            filters.build(gen, keys.iter().map(String::as_str));
+        }
+        filters
+    }
+
+    /// Whether any generation might have `key`.
+    pub(super) fn may_contain(&self, key: &str) -> bool {
+        self.filters
+            .read()
+            .unwrap()
+            .values()
+            .any(|filter| filter.may_contain(key))
+    }
+
+    /// Add `key` to the filter of `gen`. A full filter is rebuilt twice as
+    /// large from the keys of `index` in `gen`.
+    pub(super) fn insert(&self, gen: u64, key: &str, index: &IndexMap) {
+        let mut filters = self.filters.write().unwrap();
+        let filter = filters
+            .entry(gen)
+            .or_insert_with(|| BloomFilter::new(MIN_CAPACITY));
+        if filter.is_full() {
+            let mut grown = BloomFilter::new(filter.capacity * 2);
+            for entry in index.iter().filter(|entry| entry.gen == gen) {
+                grown.insert(entry.key());
+            }
+            *filter = grown;
+        }
+        filter.insert(key);
+    }
+
+    /// Replace the filter of `gen` with one of `keys`.
+    pub(super) fn build<'a>(&self, gen: u64, keys: impl ExactSizeIterator<Item = &'a str>) {
+        let mut filter = BloomFilter::new(keys.len().max(MIN_CAPACITY));
+        for key in keys {
+            filter.insert(key);
+        }
+        self.filters.write().unwrap().insert(gen, filter);
+    }
+
+    /// Drop the filters of the generations not satisfying `keep`.
+    pub(super) fn retain(&self, mut keep: impl FnMut(u64) -> bool) {
+        self.filters.write().unwrap().retain(|&gen, _| keep(gen));
+    }
+
+    /// Move the filter of `gen` to `new_gen`.
+    pub(super) fn rename(&self, gen: u64, new_gen: u64) {
+        let mut filters = self.filters.write().unwrap();
+        if let Some(filter) = filters.remove(&gen) {
+            filters.insert(new_gen, filter);
+        }
+    }
+}
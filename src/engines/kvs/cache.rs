@@ -0,0 +1,133 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Hit and miss counters of the value cache, see `KvStore::cache_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Reads served from the cache.
+    pub hits: u64,
+    /// Reads which went to the log.
+    pub misses: u64,
+    /// The number of cached values.
+    pub entries: usize,
+    /// The bytes of the cached keys and values.
+    pub bytes: u64,
+}
+impl CacheStats {
+    /// The ratio of hits to all reads, `None` if there is no read yet.
+    pub fn hit_rate(&self) -> Option<f64> {
+        let reads = self.hits + self.misses;
+        if reads == 0 {
+            return None;
+        }
+        Some(self.hits as f64 / reads as f64)
+    }
+}
+
+/// Recently read values, bounded in bytes and evicted least recently used
+/// first.
+#[derive(Debug)]
+pub(super) struct ValueCache {
+    max_bytes: u64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    tick: u64,
+    bytes: u64,
+    // key to the value and its last access
+    entries: HashMap<String, (String, u64)>,
+    // last access to the key, the oldest first
+    order: BTreeMap<u64, String>,
+}
+
+impl ValueCache {
+    pub(super) fn new(max_bytes: u64) -> Self {
+        ValueCache {
+            max_bytes,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// The cached value of `key`, counted as a hit or a miss.
+    pub(super) fn get(&self, key: &str) -> Option<String> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let Inner { entries, order, .. } = &mut *inner;
+        match entries.get_mut(key) {
+            Some((value, last)) => {
+                order.remove(last);
+                order.insert(tick, key.to_owned());
+                *last = tick;
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(value.clone())
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Caches a value read from the log if `still_current` holds.
+    ///
+    /// It's checked under the cache lock, so a value overwritten before
+    /// `invalidate` never stays cached.
+    pub(super) fn insert<F>(&self, key: String, value: String, still_current: F)
+    where
+        F: FnOnce() -> bool,
+    {
+        let size = (key.len() + value.len()) as u64;
+        if size > self.max_bytes {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if !still_current() {
+            return;
+        }
+        inner.remove(&key);
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.bytes += size;
+        inner.order.insert(tick, key.clone());
+        inner.entries.insert(key, (value, tick));
+        while inner.bytes > self.max_bytes {
+            let oldest = match inner.order.values().next() {
+                Some(key) => key.clone(),
+                None => break,
+            };
+            inner.remove(&oldest);
+        }
+    }
+
+    /// Drops the cached value of `key`, called after the index is updated.
+    pub(super) fn invalidate(&self, key: &str) {
+        self.inner.lock().unwrap().remove(key);
+    }
+
+    pub(super) fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: inner.entries.len(),
+            bytes: inner.bytes,
+        }
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, key: &str) {
+        if let Some((value, tick)) = self.entries.remove(key) {
+            self.order.remove(&tick);
+            self.bytes -= (key.len() + value.len()) as u64;
+        }
+    }
+}
@@ -0,0 +1,38 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::{KvsError, Result};
+
+/// A flag to give up on a long operation, e.g. `KvStore::compact_cancellable`.
+///
+/// Clones share the flag, so one can be kept by a timer or another request
+/// and cancelled from there. The operation checks it between steps, so it
+/// stops shortly after, not right away.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// A token not cancelled yet.
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    /// Signals the operations checking the token to stop.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether `cancel` was called on the token or any of its clones.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Returns `KvsError::Cancelled` if it's cancelled.
+    pub(super) fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            Err(KvsError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
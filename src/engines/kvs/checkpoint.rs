@@ -0,0 +1,148 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::{CommandPos, Fnv1a, IndexMap, LogKind, LogStorage};
+use crate::Result;
+
+// the bytes at the end of a log hashed to tell it from another one
+const TAIL_LEN: u64 = 64;
+
+/// A snapshot of the index.
+///
+/// It covers every command written before `pos` of generation `gen`, so only
+/// the rest of `gen` and newer generations have to be replayed on open.
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct Checkpoint {
+    pub gen: u64,
+    pub pos: u64,
+    pub stale_bytes: u64,
+    // missing in the checkpoints written before sequence numbers
+    #[serde(default)]
+    pub seq: u64,
+    // missing in the checkpoints written before them, which are never valid
+    #[serde(default)]
+    marks: Vec<GenMark>,
+    pub index: Vec<(String, CommandPos)>,
+}
+
+/// The length of a log covered by a checkpoint and a hash of its bytes
+/// right before it, so a renumbered or rewritten log isn't taken for it.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct GenMark {
+    gen: u64,
+    len: u64,
+    tail: u64,
+}
+impl GenMark {
+    fn read(storage: &dyn LogStorage, gen: u64, len: u64) -> Result<Self> {
+        let start = len.saturating_sub(TAIL_LEN);
+        let mut reader = storage.open(gen, LogKind::Log)?;
+        reader.seek(SeekFrom::Start(start))?;
+        let mut bytes = vec![0; (len - start) as usize];
+        reader.read_exact(&mut bytes)?;
+        let mut hash = Fnv1a::new();
+        hash.update(&bytes);
+        Ok(GenMark {
+            gen,
+            len,
+            tail: hash.0,
+        })
+    }
+}
+
+impl Checkpoint {
+    /// Snapshot `index` covering the logs up to `pos` of `gen`, which must
+    /// be flushed up to there.
+    pub fn capture(
+        storage: &dyn LogStorage,
+        gen: u64,
+        pos: u64,
+        stale_bytes: u64,
+        seq: u64,
+        index: &IndexMap,
+    ) -> Result<Self> {
+        let mut marks = Vec::new();
+        for covered in storage.list_generations()? {
+            let len = match covered.cmp(&gen) {
+                std::cmp::Ordering::Less => storage.len(covered, LogKind::Log)?.unwrap_or(0),
+                std::cmp::Ordering::Equal => pos,
+                std::cmp::Ordering::Greater => break,
+            };
+            marks.push(GenMark::read(storage, covered, len)?);
+        }
+        Ok(Checkpoint {
+            gen,
+            pos,
+            stale_bytes,
+            seq,
+            marks,
+            index: index
+                .iter()
+                .map(|entry| (entry.key().clone(), *entry.value()))
+                .collect(),
+        })
+    }
+
+    /// Load the checkpoint at `path`, returns `None` if there isn't one.
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let reader = BufReader::new(File::open(path)?);
+        Ok(Some(serde_json::from_reader(reader)?))
+    }
+
+    /// Write to a temporary file first then rename it, so a crash never
+    /// leaves a partial checkpoint behind.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Remove the checkpoint at `path`, if there's one.
+    pub fn remove(path: &Path) -> Result<()> {
+        match fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that the generations the checkpoint covers are still the same.
+    ///
+    /// Compaction removes old generations, and renumbering or rewriting them
+    /// moves the records, so a checkpoint taken before is useless and the
+    /// logs have to be fully replayed. The covered generations must all be
+    /// there with the same lengths, only the one of `gen` might have grown,
+    /// and the same bytes at their ends.
+    pub fn is_valid(&self, storage: &dyn LogStorage, gen_list: &[u64]) -> bool {
+        let covered: Vec<u64> = gen_list
+            .iter()
+            .copied()
+            .filter(|&gen| gen <= self.gen)
+            .collect();
+        if self.marks.is_empty() || !self.marks.iter().map(|mark| mark.gen).eq(covered) {
+            return false;
+        }
+        let unchanged = self.marks.iter().all(|mark| {
+            let len_ok = match storage.len(mark.gen, LogKind::Log) {
+                Ok(Some(len)) if mark.gen == self.gen => len >= mark.len,
+                Ok(Some(len)) => len == mark.len,
+                _ => false,
+            };
+            len_ok && GenMark::read(storage, mark.gen, mark.len).is_ok_and(|read| read == *mark)
+        });
+        unchanged
+            && self
+                .index
+                .iter()
+                .all(|(_, cmd_pos)| cmd_pos.gen <= self.gen && gen_list.contains(&cmd_pos.gen))
+    }
+}
@@ -0,0 +1,23 @@
+use std::fmt::Debug;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The source of "now" for time-based behaviors of a `KvStore`.
+///
+/// Tests can inject their own clock to advance time deterministically.
+pub trait Clock: Debug + Send + Sync {
+    /// Milliseconds since the Unix epoch.
+    fn now_ms(&self) -> u64;
+}
+
+/// The system wall clock, used by default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+/// When the writes are synced in groups, see `KvStoreOptions::commit_policy`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CommitPolicy {
+    /// Sync once this many writes are pending, 1 syncs every write.
+    pub max_batch: usize,
+    /// Sync a pending write at the latest this long after it's written.
+    pub max_delay: Duration,
+}
+
+/// The writes not synced yet under a `CommitPolicy`.
+#[derive(Debug)]
+pub(super) struct GroupCommit {
+    policy: CommitPolicy,
+    pending: usize,
+    // when the first pending write is written, by the clock of the store
+    first_ms: u64,
+}
+
+impl GroupCommit {
+    pub(super) fn new(policy: CommitPolicy) -> Self {
+        GroupCommit {
+            policy,
+            pending: 0,
+            first_ms: 0,
+        }
+    }
+
+    /// Counts a write written at `now_ms`, returns whether the group is due
+    /// to be synced.
+    pub(super) fn add(&mut self, now_ms: u64) -> bool {
+        if self.pending == 0 {
+            self.first_ms = now_ms;
+        }
+        self.pending += 1;
+        self.pending >= self.policy.max_batch
+            || now_ms.saturating_sub(self.first_ms) >= self.policy.max_delay.as_millis() as u64
+    }
+
+    pub(super) fn is_pending(&self) -> bool {
+        self.pending > 0
+    }
+
+    /// Marks the group synced.
+    pub(super) fn clear(&mut self) {
+        self.pending = 0;
+    }
+}
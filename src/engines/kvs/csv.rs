@@ -0,0 +1,107 @@
+use std::io::{self, BufRead, Write};
+
+use crate::{KvsError, Result};
+
+/// Write `fields` as a CSV row ended by a newline.
+///
+/// A field with a comma, a quote or a line break is quoted, its quotes
+/// doubled, as in RFC 4180.
+pub(super) fn write_row(w: &mut impl Write, fields: &[&str]) -> io::Result<()> {
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            w.write_all(b",")?;
+        }
+        if field.contains([',', '"', '\r', '\n']) {
+            w.write_all(b"\"")?;
+            w.write_all(field.replace('"', "\"\"").as_bytes())?;
+            w.write_all(b"\"")?;
+        } else {
+            w.write_all(field.as_bytes())?;
+        }
+    }
+    w.write_all(b"\n")
+}
+
+/// Reads the rows written by `write_row`, or any RFC 4180 CSV.
+///
+/// Rows end with `\n` or `\r\n`, line breaks in quoted fields are kept as
+/// they are.
+pub(super) struct CsvReader<R> {
+    inner: R,
+    line: String,
+    // the number of lines read
+    line_no: usize,
+}
+
+impl<R: BufRead> CsvReader<R> {
+    pub(super) fn new(inner: R) -> Self {
+        CsvReader {
+            inner,
+            line: String::new(),
+            line_no: 0,
+        }
+    }
+
+    /// The line the last row returned ends on, from 1.
+    pub(super) fn line_no(&self) -> usize {
+        self.line_no
+    }
+
+    /// The fields of the next row, `None` at the end.
+    pub(super) fn next_row(&mut self) -> Result<Option<Vec<String>>> {
+        let mut fields = Vec::new();
+        let mut field = String::new();
+        let mut quoted = false;
+        // the field is in quotes which aren't closed yet
+        let mut in_quotes = false;
+        loop {
+            self.line.clear();
+            if self.inner.read_line(&mut self.line)? == 0 {
+                if in_quotes {
+                    return Err(self.error("unterminated quoted field"));
+                }
+                if fields.is_empty() && field.is_empty() && !quoted {
+                    return Ok(None);
+                }
+                fields.push(field);
+                return Ok(Some(fields));
+            }
+            self.line_no += 1;
+            let mut chars = self.line.chars().peekable();
+            while let Some(c) = chars.next() {
+                if in_quotes {
+                    match c {
+                        '"' if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            field.push('"');
+                        }
+                        '"' => in_quotes = false,
+                        c => field.push(c),
+                    }
+                    continue;
+                }
+                match c {
+                    '"' if field.is_empty() && !quoted => {
+                        quoted = true;
+                        in_quotes = true;
+                    }
+                    ',' => {
+                        fields.push(std::mem::take(&mut field));
+                        quoted = false;
+                    }
+                    '\r' if chars.peek() == Some(&'\n') => {}
+                    '\n' => {
+                        fields.push(field);
+                        return Ok(Some(fields));
+                    }
+                    _ if quoted => return Err(self.error("text after a quoted field")),
+                    c => field.push(c),
+                }
+            }
+        }
+    }
+
+    fn error(&self, reason: &str) -> KvsError {
+        KvsError::StringError(format!("CSV line {}: {}", self.line_no, reason))
+    }
+}
@@ -0,0 +1,172 @@
+use std::io::{self, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+
+use super::{LogKind, LogReader, LogStorage, LogWriter};
+use crate::Result;
+
+/// When `FaultyStorage` fails the I/O on the logs, counted from the moment
+/// it's scheduled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FaultSchedule {
+    /// Fail the nth write call, 1 for the next one. Nothing is written by
+    /// the failed call.
+    pub fail_write: Option<u64>,
+    /// Fail once this many more bytes are written. The write crossing it is
+    /// cut short, leaving a torn record like a crash would.
+    pub fail_after_bytes: Option<u64>,
+    /// Fail the next sync, the data stays written but not durable.
+    pub fail_sync: bool,
+    /// Fail the nth rename of a log, 1 for the next one. Nothing is renamed
+    /// by the failed call.
+    pub fail_rename: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct FaultState {
+    schedule: FaultSchedule,
+    writes: u64,
+    bytes: u64,
+    renames: u64,
+    faults: u64,
+}
+
+/// A storage failing the I/O on the logs deterministically, for testing
+/// crash consistency.
+///
+/// It wraps another storage, every log opened for writing checks the shared
+/// schedule. Only available with the `fault-injection` feature.
+#[derive(Clone, Debug)]
+pub struct FaultyStorage {
+    inner: Arc<dyn LogStorage>,
+    state: Arc<Mutex<FaultState>>,
+}
+impl FaultyStorage {
+    /// Wraps `inner`, nothing fails until a schedule is set.
+    pub fn new(inner: Arc<dyn LogStorage>) -> Self {
+        FaultyStorage {
+            inner,
+            state: Arc::new(Mutex::new(FaultState::default())),
+        }
+    }
+
+    /// Replaces the schedule, the counts of writes, bytes and renames start
+    /// over.
+    pub fn schedule(&self, schedule: FaultSchedule) {
+        let mut state = self.state.lock().unwrap();
+        state.schedule = schedule;
+        state.writes = 0;
+        state.bytes = 0;
+        state.renames = 0;
+    }
+
+    /// Returns how many faults are injected so far.
+    pub fn faults(&self) -> u64 {
+        self.state.lock().unwrap().faults
+    }
+
+    fn writer(&self, inner: Box<dyn LogWriter>) -> Box<dyn LogWriter> {
+        Box::new(FaultyWriter {
+            inner,
+            state: self.state.clone(),
+        })
+    }
+}
+impl LogStorage for FaultyStorage {
+    fn list_generations(&self) -> Result<Vec<u64>> {
+        self.inner.list_generations()
+    }
+
+    fn open(&self, gen: u64, kind: LogKind) -> Result<Box<dyn LogReader>> {
+        self.inner.open(gen, kind)
+    }
+
+    fn create(&self, gen: u64, kind: LogKind) -> Result<Box<dyn LogWriter>> {
+        Ok(self.writer(self.inner.create(gen, kind)?))
+    }
+
+    fn remove(&self, gen: u64, kind: LogKind) -> Result<()> {
+        self.inner.remove(gen, kind)
+    }
+
+    fn rename(&self, gen: u64, new_gen: u64, kind: LogKind) -> Result<()> {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.renames += 1;
+            if state.schedule.fail_rename == Some(state.renames) {
+                state.schedule.fail_rename = None;
+                state.faults += 1;
+                return Err(injected().into());
+            }
+        }
+        self.inner.rename(gen, new_gen, kind)
+    }
+
+    fn len(&self, gen: u64, kind: LogKind) -> Result<Option<u64>> {
+        self.inner.len(gen, kind)
+    }
+
+    fn create_temp(&self, gen: u64, kind: LogKind) -> Result<Box<dyn LogWriter>> {
+        Ok(self.writer(self.inner.create_temp(gen, kind)?))
+    }
+
+    fn persist_temp(&self, gen: u64, kind: LogKind) -> Result<()> {
+        self.inner.persist_temp(gen, kind)
+    }
+
+    fn remove_temps(&self) -> Result<()> {
+        self.inner.remove_temps()
+    }
+}
+
+fn injected() -> io::Error {
+    io::Error::other("Injected fault")
+}
+
+struct FaultyWriter {
+    inner: Box<dyn LogWriter>,
+    state: Arc<Mutex<FaultState>>,
+}
+impl Write for FaultyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        state.writes += 1;
+        if state.schedule.fail_write == Some(state.writes) {
+            state.schedule.fail_write = None;
+            state.faults += 1;
+            return Err(injected());
+        }
+        let mut len = buf.len();
+        if let Some(max) = state.schedule.fail_after_bytes {
+            let left = max.saturating_sub(state.bytes);
+            if left == 0 {
+                state.schedule.fail_after_bytes = None;
+                state.faults += 1;
+                return Err(injected());
+            }
+            len = len.min(left as usize);
+        }
+        let len = self.inner.write(&buf[..len])?;
+        state.bytes += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+impl Seek for FaultyWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+impl LogWriter for FaultyWriter {
+    fn sync(&mut self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.schedule.fail_sync {
+            state.schedule.fail_sync = false;
+            state.faults += 1;
+            return Err(injected());
+        }
+        self.inner.sync()
+    }
+}
@@ -0,0 +1,57 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::Path;
+
+use serde_json::Deserializer;
+
+use super::{
+    log_file_path, read_format_version, sorted_gen_list, value_log_file_path, ReplayCommand,
+};
+use crate::Result;
+
+/// A generation of a store, see `KvStore::list_generations`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GenInfo {
+    /// The generation number.
+    pub gen: u64,
+    /// The size of the log in bytes.
+    pub log_bytes: u64,
+    /// The size of the value log in bytes, `None` without the split layout.
+    pub value_log_bytes: Option<u64>,
+    /// The number of commands in the log, transaction markers included.
+    /// `None` unless counting is requested.
+    pub records: Option<usize>,
+}
+
+/// List the generations of the store at `path`, see
+/// `KvStore::list_generations`.
+pub(super) fn list_generations(path: &Path, count_records: bool) -> Result<Vec<GenInfo>> {
+    let mut infos = Vec::new();
+    for gen in sorted_gen_list(path)? {
+        let log_path = log_file_path(path, gen);
+        let value_log_bytes = match fs::metadata(value_log_file_path(path, gen)) {
+            Ok(metadata) => Some(metadata.len()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+        let records = if count_records {
+            let mut reader = BufReader::new(File::open(&log_path)?);
+            read_format_version(&mut reader)?;
+            let mut records = 0;
+            for cmd in Deserializer::from_reader(reader).into_iter::<ReplayCommand>() {
+                cmd?;
+                records += 1;
+            }
+            Some(records)
+        } else {
+            None
+        };
+        infos.push(GenInfo {
+            gen,
+            log_bytes: fs::metadata(log_path)?.len(),
+            value_log_bytes,
+            records,
+        });
+    }
+    Ok(infos)
+}
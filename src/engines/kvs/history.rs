@@ -0,0 +1,71 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// only the most recent compactions are kept
+const HISTORY_CAPACITY: usize = 128;
+
+/// A finished compaction.
+#[derive(Clone, Copy, Debug)]
+pub struct CompactionRecord {
+    /// When the compaction finished.
+    pub finished_at: Instant,
+    /// How long the compaction took.
+    pub duration: Duration,
+    /// Stale bytes reclaimed by the compaction.
+    pub reclaimed_bytes: u64,
+}
+
+/// The recent compactions of a `KvStore`, bounded to the last 128 ones.
+#[derive(Clone, Debug, Default)]
+pub struct CompactionHistory {
+    records: VecDeque<CompactionRecord>,
+}
+impl CompactionHistory {
+    pub(super) fn push(&mut self, record: CompactionRecord) {
+        if self.records.len() == HISTORY_CAPACITY {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    /// The kept compactions, from the oldest to the newest.
+    pub fn records(&self) -> impl Iterator<Item = &CompactionRecord> {
+        self.records.iter()
+    }
+
+    /// The number of compactions finished within `window` till now.
+    pub fn count_within(&self, window: Duration) -> usize {
+        self.records
+            .iter()
+            .rev()
+            .take_while(|record| record.finished_at.elapsed() <= window)
+            .count()
+    }
+
+    /// The average duration, `None` if there is no compaction yet.
+    pub fn average_duration(&self) -> Option<Duration> {
+        if self.records.is_empty() {
+            return None;
+        }
+        let total: Duration = self.records.iter().map(|record| record.duration).sum();
+        Some(total / self.records.len() as u32)
+    }
+
+    /// The 99th percentile duration, `None` if there is no compaction yet.
+    pub fn p99_duration(&self) -> Option<Duration> {
+        let mut durations: Vec<_> = self.records.iter().map(|record| record.duration).collect();
+        durations.sort_unstable();
+        let rank = (durations.len() * 99).div_ceil(100);
+        durations.get(rank.checked_sub(1)?).copied()
+    }
+
+    /// The average stale bytes reclaimed, `None` if there is no compaction yet.
+    pub fn average_reclaimed_bytes(&self) -> Option<u64> {
+        let total: u64 = self
+            .records
+            .iter()
+            .map(|record| record.reclaimed_bytes)
+            .sum();
+        total.checked_div(self.records.len() as u64)
+    }
+}
@@ -0,0 +1,40 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+/// A summary of the store for humans and tools, pretty-printed JSON.
+///
+/// It's informational only, `open` never reads it.
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct Manifest {
+    pub current_gen: u64,
+    pub compaction_threshold: u64,
+    pub format_version: u32,
+    // milliseconds since the Unix epoch
+    pub created_ms: u64,
+    pub updated_ms: u64,
+}
+impl Manifest {
+    /// Load the manifest at `path`, `None` if it's missing or unreadable.
+    pub fn load(path: &Path) -> Option<Self> {
+        let reader = BufReader::new(File::open(path).ok()?);
+        serde_json::from_reader(reader).ok()
+    }
+
+    /// Write to a temporary file first then rename it, so a crash never
+    /// leaves a partial manifest behind.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+
+use memmap2::Mmap;
+
+use super::{log_file_path, value_log_file_path, CommandPos, LogKind};
+
+/// Maps `file` read-only into memory.
+///
+/// The mapping is shared with the page cache, so bytes appended after it's
+/// made are only visible beyond its length once it's remapped.
+///
+/// It's unsafe if the file shrinks under it: touching a page past the new
+/// end kills the process with `SIGBUS` instead of failing the read. The
+/// store only appends to its logs and replaces them by renames, which leave
+/// the mapped file alone, but another process truncating a log, e.g. a
+/// repair tool, must not run while the store is open.
+fn map(file: &File) -> io::Result<Mmap> {
+    // SAFETY: see above for the file shrinking under it
+    unsafe { Mmap::map(file) }
+}
+
+/// The mapped logs of a `ReadAgent` by generation, see
+/// `KvStoreOptions::mmap_reads`.
+///
+/// A log is mapped on its first read and remapped when a read goes past the
+/// end of the mapping, i.e. the active generation grew. Clones start with no
+/// mappings, each `ReadAgent` maps on its own like it opens its own files.
+pub(super) struct Mappings {
+    dir: PathBuf,
+    kind: LogKind,
+    maps: RefCell<BTreeMap<u64, Mmap>>,
+}
+
+impl Mappings {
+    pub(super) fn new(dir: PathBuf, kind: LogKind) -> Self {
+        Mappings {
+            dir,
+            kind,
+            maps: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    /// Calls `f` with the mapped bytes of `cmd_pos`, `None` if the log is
+    /// missing or shorter than the record, e.g. the bytes aren't flushed
+    /// yet. The caller falls back to reading the log then.
+    pub(super) fn with_slice<R>(
+        &self,
+        cmd_pos: &CommandPos,
+        f: impl FnOnce(&[u8]) -> R,
+    ) -> io::Result<Option<R>> {
+        let start = cmd_pos.pos as usize;
+        let end = start + cmd_pos.len as usize;
+        let mut maps = self.maps.borrow_mut();
+        if maps.get(&cmd_pos.gen).is_none_or(|map| map.len() < end) {
+            let path = match self.kind {
+                LogKind::Log => log_file_path(&self.dir, cmd_pos.gen),
+                LogKind::ValueLog => value_log_file_path(&self.dir, cmd_pos.gen),
+            };
+            let file = match File::open(path) {
+                Ok(file) => file,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(err) => return Err(err),
+            };
+            let len = file.metadata()?.len() as usize;
+            if len < end || len == 0 {
+                return Ok(None);
+            }
+            maps.insert(cmd_pos.gen, map(&file)?);
+        }
+        Ok(Some(f(&maps[&cmd_pos.gen][start..end])))
+    }
+
+    /// Unmaps the logs older than `first_gen`.
+    pub(super) fn retain_from(&self, first_gen: u64) {
+        let mut maps = self.maps.borrow_mut();
+        *maps = maps.split_off(&first_gen);
+    }
+
+    pub(super) fn clear(&self) {
+        self.maps.borrow_mut().clear();
+    }
+}
+
+impl Clone for Mappings {
+    fn clone(&self) -> Self {
+        Mappings::new(self.dir.clone(), self.kind)
+    }
+}
@@ -0,0 +1,315 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{Clock, CommitPolicy, LogStorage, RetryPolicy};
+
+/// Options to tune a `KvStore` at `KvStore::open_with_options`.
+///
+/// `KvsEngine::open` uses `KvStoreOptions::default()`.
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    /// How often the whole in-memory index is persisted to a checkpoint file.
+    ///
+    /// Checkpointing is disabled with `None`. When enabled, `open` loads the
+    /// checkpoint and only replays the logs written after it.
+    pub checkpoint_interval: Option<Duration>,
+    /// Where the checkpoint is stored, `<store dir>/checkpoint` by default.
+    pub checkpoint_path: Option<PathBuf>,
+    /// Store values in separate value logs (`<gen>.vlog`), only keys and value
+    /// locations are kept in the logs replayed by `open`.
+    ///
+    /// It's a different on-disk format, a store must always be opened with
+    /// the same setting.
+    pub split_values: bool,
+    /// With `split_values`, compactions store equal values once in the
+    /// value log, and the locations of all their keys point at the same
+    /// bytes. Off by default, it's ignored without `split_values`.
+    ///
+    /// Values are compared by a hash then byte for byte, so it costs a read
+    /// of an earlier copy for each duplicate found. Reads are unchanged, a
+    /// shared value is read at its location like any other. Writes between
+    /// compactions aren't deduplicated. The live and stale bytes still
+    /// count a shared value once per key, so they overstate the disk use
+    /// and compactions might come earlier.
+    pub dedup_values: bool,
+    /// Reject a `set` whose record would be longer than this many bytes,
+    /// unlimited with `None`. With the split layout, the record is the value
+    /// in the value log.
+    ///
+    /// `open` takes a longer record as corruption, e.g. one written before
+    /// the limit was lowered, or a broken value length which would allocate
+    /// gigabytes on read.
+    pub max_record_len: Option<u64>,
+    /// Write the records of the logs pretty-printed, one per line, so a log
+    /// is readable by hand. Off by default, it's meant for debugging as the
+    /// logs get much larger.
+    ///
+    /// Both forms are read back, a store can switch between them any time.
+    /// Values in the value logs are always compact.
+    pub pretty_records: bool,
+    /// Compact when more bytes than this are stale, 1 MiB by default. It can
+    /// be changed later with `KvStore::set_compaction_threshold`.
+    pub compaction_threshold: u64,
+    /// Compact when there are more generations than this, no matter how many
+    /// stale bytes there are. Unlimited with `None`.
+    ///
+    /// A compaction leaves two generations, so it should be at least 2.
+    pub max_generations: Option<usize>,
+    /// The least time between two compactions triggered by writes, none by
+    /// default.
+    ///
+    /// Writes hovering around `compaction_threshold` could otherwise compact
+    /// over and over. Within the cooldown after a compaction, writes leave
+    /// the stale bytes to pile up, the first write after it compacts. It's
+    /// measured with `clock`. Explicit compactions ignore it.
+    pub compaction_cooldown: Option<Duration>,
+    /// Check every this often on a background thread whether the compaction
+    /// policy calls for a compaction and run it, off by default.
+    ///
+    /// Writes only compact when they cross the threshold, so it reclaims the
+    /// stale bytes of a store going quiet, e.g. after removals. The thread
+    /// compacts under the writer lock, so writes of any clone wait for it,
+    /// and exits at the first tick after the last clone is dropped. It's not
+    /// spawned for a read-only store. Without it, an application can call
+    /// `KvStore::maybe_compact` from its own timer.
+    pub compaction_interval: Option<Duration>,
+    /// Evict the least recently used keys once the live records take more
+    /// bytes than this, turning the store into a bounded cache. Unbounded
+    /// with `None`.
+    ///
+    /// `get` counts as a use, `peek` doesn't. The key just set is never
+    /// evicted, even if it alone goes over the budget.
+    pub max_bytes: Option<u64>,
+    /// Refuse a `set` with `KvsError::StoreFull` once it would take the
+    /// logs over this many bytes on the storage. Unbounded with `None`.
+    ///
+    /// A set going over first compacts to make room if there are stale
+    /// bytes, whatever `compaction_cooldown`, and is only refused if it
+    /// still doesn't fit. The compaction itself needs room for the live
+    /// records until it removes the old logs. Removals and the other writes
+    /// aren't checked, so the logs can go over a bit.
+    pub max_total_bytes: Option<u64>,
+    /// Track the time of the last `get` or `set` of each key, for
+    /// `KvStore::last_access`, off by default.
+    ///
+    /// It costs a map entry per key accessed and a read of the clock per
+    /// access. The times are kept in memory only.
+    pub track_access_times: bool,
+    /// The clock for time-based behaviors like checkpointing, the system
+    /// clock with `None`.
+    pub clock: Option<Arc<dyn Clock>>,
+    /// Where the logs are stored, files in the store directory with `None`.
+    ///
+    /// The store directory is not created with a custom storage, but
+    /// auxiliary files like the checkpoint are still kept there.
+    pub storage: Option<Arc<dyn LogStorage>>,
+    /// Whether dropping the store also fsyncs the logs, `true` by default.
+    ///
+    /// The logs are always flushed to the OS on drop, which survives a crash
+    /// of the process but not of the machine. Turning the fsync off makes
+    /// shutdown faster, e.g. for test teardown.
+    pub flush_and_fsync_on_drop: bool,
+    /// Sync the logs after groups of writes, `None` by default, where a
+    /// write is only flushed to the OS.
+    ///
+    /// Sets, removals and transactions count as a write each. A write
+    /// updates the index and is flushed before it returns, it's visible and
+    /// survives a crash of the process then. It's durable, surviving a
+    /// crash of the machine, once its group is synced: when `max_batch`
+    /// writes are pending, when a write comes `max_delay` or more after
+    /// the first pending one by the `clock`, or at the latest at the next
+    /// tick of a background thread waking every `max_delay`. A compaction
+    /// syncs everything it copies, and dropping the last clone syncs a
+    /// pending group whatever `flush_and_fsync_on_drop`. A failed sync is
+    /// returned by the write closing the group, which stays in the index.
+    pub commit_policy: Option<CommitPolicy>,
+    /// Whether the store directory is fsynced after a log is created or
+    /// renamed, `true` by default. See `FsStorage::sync_directory` for the
+    /// platforms where it matters, it's ignored with a custom storage.
+    pub sync_directory: bool,
+    /// Whether `remove` of a missing key returns `KvsError::KeyNotFound`,
+    /// `true` by default. It's `Ok(())` otherwise, making removals idempotent.
+    pub remove_missing_is_error: bool,
+    /// Roughly how many keys the store holds, the index is pre-sized for
+    /// them to avoid rehashing during `open`. It's only a hint.
+    pub expected_keys: usize,
+    /// Replay at most this many bytes of logs on `open`, unlimited with
+    /// `None`.
+    ///
+    /// It's dangerous: the commands of the older logs over the limit are
+    /// skipped, so their updates since the checkpoint are lost. It's only
+    /// allowed with a loaded checkpoint, `open` fails with
+    /// `KvsError::IncompleteRecovery` otherwise. The limit applies to whole
+    /// logs, the newest ones fitting in it are replayed.
+    pub recovery_limit: Option<u64>,
+    /// Keep recently read values in memory, so `get` of a hot key doesn't
+    /// read the log. Off by default.
+    ///
+    /// The cache is shared among the clones of the store and invalidated by
+    /// the writes. `KvStore::cache_stats` reports its hit rate.
+    pub cache_values: bool,
+    /// The bytes of keys and values the value cache holds at most, the least
+    /// recently used ones are evicted beyond it. 64 MiB by default.
+    pub value_cache_bytes: u64,
+    /// Refuse writes once a read finds the index pointing to a record which
+    /// is not a `Set`, off by default.
+    ///
+    /// Such a record means the index and the logs disagree, writing more
+    /// would only compound the corruption. `open` also checks every key in
+    /// a background thread. Writes return `KvsError::Poisoned` then, reads
+    /// go on. Without it, only the read fails.
+    pub strict: bool,
+    /// Keep a bloom filter of the keys of each generation, checked before
+    /// the index on reads, off by default.
+    ///
+    /// A key in none of them is missing without probing the index. It's
+    /// redundant while the whole index is in memory, it's meant to save
+    /// the lookups of an index on disk.
+    pub bloom_filters: bool,
+    /// Index the keys by the first this many characters of their values,
+    /// for `KvStore::keys_with_value_prefix`. Off by default.
+    ///
+    /// It's built on `open` by reading every value, and kept up to date by
+    /// every write, so it costs memory, startup time and some write speed.
+    pub value_prefix_len: Option<usize>,
+    /// The character separating the segments of hierarchical keys, for
+    /// `KvStore::list_children`. `:` by default.
+    ///
+    /// Keys are stored as they are whatever it is, it only tells where a
+    /// child ends.
+    pub key_separator: char,
+    /// Leave live records longer than this many bytes in their generations
+    /// on compaction instead of copying them, off by default.
+    ///
+    /// Copying a large value which never changes is mostly write
+    /// amplification. A generation holding such a value is kept whole with
+    /// its stale records, until the value is overwritten or removed and a
+    /// later compaction drops it. Readers keep it open meanwhile, and it's
+    /// not counted towards `max_generations`. With the split layout, it's
+    /// the length of the value.
+    pub compaction_skip_len: Option<u64>,
+    /// Keep a `MANIFEST` file in the store directory for humans and tools,
+    /// off by default.
+    ///
+    /// It's pretty-printed JSON with the active generation, the compaction
+    /// threshold, the format version and when it's created and updated, in
+    /// milliseconds since the Unix epoch. It's rewritten atomically on open,
+    /// rotations and compactions. `open` never reads it, so it might be
+    /// missing or stale.
+    pub write_manifest: bool,
+    /// Retry the I/O on the logs after a transient error, `EINTR` or
+    /// `EAGAIN`, e.g. on network file systems. No retries by default.
+    ///
+    /// Every read, write, flush or sync of the logs is retried, so it covers
+    /// the writes of `set` and `remove` as well as the replay on `open`.
+    /// Other errors like running out of space fail right away.
+    pub io_retry: Option<RetryPolicy>,
+    /// The generation to write to, instead of the one after the newest
+    /// existing generation.
+    ///
+    /// It's meant for seeding a directory whose generations have to fit in
+    /// a sequence, e.g. shards to be merged later. It must be newer than
+    /// every existing generation, `open` returns
+    /// `KvsError::InvalidGeneration` otherwise. `KvStore::reopen` ignores it.
+    pub start_gen: Option<u64>,
+    /// Keep up to this many previous values of each key for
+    /// `KvStore::get_version`, 0 by default.
+    ///
+    /// Their records are not stale, compactions copy them along with the
+    /// live ones, so each version costs its bytes on disk and a location in
+    /// memory. A removal drops the versions of the key. They're rebuilt
+    /// from the logs replayed on `open`, the ones before a checkpoint are
+    /// lost.
+    pub retained_versions: usize,
+    /// Open without writing anything to the directory, e.g. a file system
+    /// snapshot, off by default. See `KvStore::open_read_only`.
+    pub read_only: bool,
+    /// Called with the key after every change of a key, e.g. to invalidate
+    /// an external cache. Nothing is called by default.
+    ///
+    /// It's called once the command is flushed and the index updated, before
+    /// the method returns. Sets, removals, transactions, renames, evictions,
+    /// `replace_all` and `delete_prefix` call it for each key they change,
+    /// compactions don't as values don't change. It runs under the writer
+    /// lock, so it mustn't write to the store.
+    pub on_write: Option<OnWrite>,
+    /// Read values by slicing memory-mapped logs instead of seeking and
+    /// reading them, off by default. It's available with the `mmap` feature.
+    ///
+    /// It saves the system calls of each `get` on large generations. The
+    /// compacted generations never change, the active one is remapped once a
+    /// read goes past its mapped end. It only applies to the default storage
+    /// on Unix, and a log which can't be mapped is read as usual, so a custom
+    /// `storage` falls back to buffered reads entirely. Compactions and scans
+    /// always read the logs.
+    ///
+    /// A mapped log must not be truncated by another process while the store
+    /// is open: reading a page past the new end kills the process with
+    /// `SIGBUS` rather than failing the read.
+    #[cfg(feature = "mmap")]
+    pub mmap_reads: bool,
+}
+
+/// A callback of `KvStoreOptions::on_write`.
+#[derive(Clone)]
+pub struct OnWrite(Arc<dyn Fn(&str) + Send + Sync>);
+impl OnWrite {
+    /// Wraps `f` to be called with the changed key.
+    pub fn new(f: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        OnWrite(Arc::new(f))
+    }
+
+    pub(super) fn call(&self, key: &str) {
+        (self.0)(key)
+    }
+}
+impl fmt::Debug for OnWrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OnWrite(..)")
+    }
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            checkpoint_interval: None,
+            checkpoint_path: None,
+            split_values: false,
+            dedup_values: false,
+            max_record_len: None,
+            pretty_records: false,
+            compaction_threshold: 1024 * 1024,
+            max_generations: None,
+            compaction_cooldown: None,
+            compaction_interval: None,
+            max_bytes: None,
+            max_total_bytes: None,
+            track_access_times: false,
+            clock: None,
+            storage: None,
+            flush_and_fsync_on_drop: true,
+            commit_policy: None,
+            sync_directory: true,
+            remove_missing_is_error: true,
+            expected_keys: 0,
+            recovery_limit: None,
+            cache_values: false,
+            value_cache_bytes: 64 * 1024 * 1024,
+            strict: false,
+            bloom_filters: false,
+            value_prefix_len: None,
+            key_separator: ':',
+            compaction_skip_len: None,
+            write_manifest: false,
+            io_retry: None,
+            start_gen: None,
+            retained_versions: 0,
+            read_only: false,
+            on_write: None,
+            #[cfg(feature = "mmap")]
+            mmap_reads: false,
+        }
+    }
+}
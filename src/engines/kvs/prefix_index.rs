@@ -0,0 +1,100 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+
+/// Keys by the first characters of their values.
+#[derive(Debug)]
+pub(super) struct PrefixIndex {
+    // the number of characters of the prefixes
+    len: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    keys: HashMap<String, BTreeSet<String>>,
+    // key to the prefix of its value, to move it out of its old bucket
+    prefixes: HashMap<String, String>,
+}
+
+impl PrefixIndex {
+    pub(super) fn new(len: usize) -> Self {
+        PrefixIndex {
+            len,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// The indexed prefix of `value`, the whole value if it's shorter.
+    pub(super) fn prefix_of<'a>(&self, value: &'a str) -> &'a str {
+        match value.char_indices().nth(self.len) {
+            Some((end, _)) => &value[..end],
+            None => value,
+        }
+    }
+
+    /// Index `key` by its new `value`.
+    pub(super) fn insert(&self, key: &str, value: &str) {
+        let prefix = self.prefix_of(value).to_owned();
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(key);
+        inner
+            .keys
+            .entry(prefix.clone())
+            .or_default()
+            .insert(key.to_owned());
+        inner.prefixes.insert(key.to_owned(), prefix);
+    }
+
+    /// Index `to` like `from`, they have the same value.
+    pub(super) fn copy(&self, from: &str, to: &str) {
+        let prefix = self.inner.lock().unwrap().prefixes.get(from).cloned();
+        if let Some(prefix) = prefix {
+            // the prefix of a prefix is itself
+            self.insert(to, &prefix);
+        }
+    }
+
+    pub(super) fn remove(&self, key: &str) {
+        self.inner.lock().unwrap().remove(key);
+    }
+
+    /// The keys whose values start with `prefix` as far as the index tells,
+    /// sorted. Values of a longer `prefix` are only matched on the indexed
+    /// characters, the caller has to check the rest.
+    pub(super) fn candidates(&self, prefix: &str) -> Vec<String> {
+        let inner = self.inner.lock().unwrap();
+        if prefix.chars().count() >= self.len {
+            return inner
+                .keys
+                .get(self.prefix_of(prefix))
+                .map(|keys| keys.iter().cloned().collect())
+                .unwrap_or_default();
+        }
+        let mut keys: Vec<String> = inner
+            .keys
+            .iter()
+            .filter(|(indexed, _)| indexed.starts_with(prefix))
+            .flat_map(|(_, keys)| keys.iter().cloned())
+            .collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    /// Whether `candidates` of `prefix` need checking against the values.
+    pub(super) fn is_partial(&self, prefix: &str) -> bool {
+        prefix.chars().count() > self.len
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, key: &str) {
+        if let Some(prefix) = self.prefixes.remove(key) {
+            if let Some(keys) = self.keys.get_mut(&prefix) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.keys.remove(&prefix);
+                }
+            }
+        }
+    }
+}
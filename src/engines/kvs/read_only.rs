@@ -0,0 +1,50 @@
+use std::sync::Arc;
+
+use super::{LogKind, LogReader, LogStorage, LogWriter};
+use crate::{KvsError, Result};
+
+/// A storage refusing every change, for `KvStoreOptions::read_only`.
+///
+/// Temporary logs are left alone instead of being removed, they're never
+/// listed or opened anyway.
+#[derive(Debug)]
+pub(super) struct ReadOnlyStorage {
+    pub inner: Arc<dyn LogStorage>,
+}
+impl LogStorage for ReadOnlyStorage {
+    fn list_generations(&self) -> Result<Vec<u64>> {
+        self.inner.list_generations()
+    }
+
+    fn open(&self, gen: u64, kind: LogKind) -> Result<Box<dyn LogReader>> {
+        self.inner.open(gen, kind)
+    }
+
+    fn create(&self, _gen: u64, _kind: LogKind) -> Result<Box<dyn LogWriter>> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn remove(&self, _gen: u64, _kind: LogKind) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn rename(&self, _gen: u64, _new_gen: u64, _kind: LogKind) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn len(&self, gen: u64, kind: LogKind) -> Result<Option<u64>> {
+        self.inner.len(gen, kind)
+    }
+
+    fn create_temp(&self, _gen: u64, _kind: LogKind) -> Result<Box<dyn LogWriter>> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn persist_temp(&self, _gen: u64, _kind: LogKind) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn remove_temps(&self) -> Result<()> {
+        Ok(())
+    }
+}
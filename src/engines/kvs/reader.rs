@@ -0,0 +1,110 @@
+use std::fmt;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+
+use super::{AccessTimes, BloomFilters, CommandPos, IndexMap, ReadAgent, Recency, ValueCache};
+use crate::Result;
+
+/// A read-only handle of a `KvStore`, see `KvStore::reader`.
+///
+/// It shares the index, the value cache and the bloom filters with the
+/// store, and opens the logs on its own, so a clone can be moved to each
+/// reader thread while the store keeps writing. Like the store, one handle
+/// is `Send` but not `Sync`.
+///
+/// It sees every write once the writing call returns, as the index is
+/// updated before then. A read racing with a write sees either the old or
+/// the new value. Multi-key reads like `range` see each key as of when it's
+/// reached, not a snapshot of the whole store.
+pub struct KvReader {
+    pub(super) index: Arc<IndexMap>,
+    pub(super) reader: ReadAgent,
+    pub(super) recency: Option<Arc<Recency>>,
+    pub(super) access: Option<Arc<AccessTimes>>,
+    pub(super) cache: Option<Arc<ValueCache>>,
+    pub(super) filters: Option<Arc<BloomFilters>>,
+}
+impl Clone for KvReader {
+    fn clone(&self) -> Self {
+        Self {
+            index: self.index.clone(),
+            reader: self.reader.clone(),
+            recency: self.recency.clone(),
+            access: self.access.clone(),
+            cache: self.cache.clone(),
+            filters: self.filters.clone(),
+        }
+    }
+}
+impl fmt::Debug for KvReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KvReader")
+            .field("keys", &self.index.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl KvReader {
+    /// Gets the value of `key` like `KvsEngine::get` of the store.
+    ///
+    /// # Errors
+    /// It propagates I/O or deserialization errors during reading the log.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        let cmd_pos = match self.lookup(&key) {
+            Some(cmd_pos) => cmd_pos,
+            None => return Ok(None),
+        };
+        if let Some(recency) = &self.recency {
+            recency.touch(&key);
+        }
+        if let Some(access) = &self.access {
+            access.touch(&key);
+        }
+        self.reader
+            .read_cached(&key, &cmd_pos, self.cache.as_deref(), &self.index)
+            .map(Some)
+    }
+
+    /// Returns whether `key` is set, without reading its value.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.lookup(key).is_some()
+    }
+
+    /// Returns the pairs whose keys are in `range`, in key order.
+    ///
+    /// The matching keys are collected first, then each value is read, a key
+    /// removed by then is skipped. They don't count as uses for `max_bytes`.
+    ///
+    /// # Errors
+    /// It propagates I/O or deserialization errors during reading the values.
+    pub fn range<'a>(&self, range: impl RangeBounds<&'a str>) -> Result<Vec<(String, String)>> {
+        let mut keys: Vec<String> = self
+            .index
+            .iter()
+            .filter(|entry| range.contains(&entry.key().as_str()))
+            .map(|entry| entry.key().clone())
+            .collect();
+        keys.sort_unstable();
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            let cmd_pos = match self.index.get(&key) {
+                Some(cmd_pos) => *cmd_pos,
+                None => continue,
+            };
+            let value = self.reader.read_value(&key, &cmd_pos)?;
+            pairs.push((key, value));
+        }
+        Ok(pairs)
+    }
+
+    /// The position of the command of `key`, the bloom filters are checked
+    /// first if enabled.
+    fn lookup(&self, key: &str) -> Option<CommandPos> {
+        if let Some(filters) = &self.filters {
+            if !filters.may_contain(key) {
+                return None;
+            }
+        }
+        self.index.get(key).map(|cmd_pos| *cmd_pos)
+    }
+}
@@ -0,0 +1,51 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use dashmap::DashMap;
+
+use super::IndexMap;
+
+/// Last access of the keys, for evicting the least recently used ones.
+///
+/// Accesses are ordered by a logical tick rather than a clock, keys never
+/// accessed since `open` have no tick and are the least recent.
+#[derive(Debug, Default)]
+pub(super) struct Recency {
+    tick: AtomicU64,
+    ticks: DashMap<String, u64>,
+}
+
+impl Recency {
+    /// Marks a key accessed by a read.
+    pub(super) fn touch(&self, key: &str) {
+        let tick = self.tick.fetch_add(1, Ordering::Relaxed) + 1;
+        match self.ticks.get_mut(key) {
+            Some(mut entry) => *entry = tick,
+            None => {
+                self.ticks.insert(key.to_owned(), tick);
+            }
+        }
+    }
+
+    /// Marks a key accessed by a write.
+    pub(super) fn touch_owned(&self, key: String) {
+        let tick = self.tick.fetch_add(1, Ordering::Relaxed) + 1;
+        self.ticks.insert(key, tick);
+    }
+
+    pub(super) fn forget(&self, key: &str) {
+        self.ticks.remove(key);
+    }
+
+    /// Keys of the index from the least to the most recently used.
+    pub(super) fn oldest_first(&self, index: &IndexMap) -> Vec<String> {
+        let mut keys: Vec<(u64, String)> = index
+            .iter()
+            .map(|entry| {
+                let tick = self.ticks.get(entry.key()).map_or(0, |tick| *tick);
+                (tick, entry.key().clone())
+            })
+            .collect();
+        keys.sort_unstable();
+        keys.into_iter().map(|(_, key)| key).collect()
+    }
+}
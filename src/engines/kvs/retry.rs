@@ -0,0 +1,140 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use super::{LogKind, LogReader, LogStorage, LogWriter};
+use crate::Result;
+
+/// How I/O on the logs is retried after a transient error, see
+/// `KvStoreOptions::io_retry`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts of an operation in total, the first one included.
+    pub max_attempts: u32,
+    /// The wait before the first retry, doubled before each next one.
+    pub backoff: Duration,
+}
+impl RetryPolicy {
+    /// Run `op` until it succeeds, fails for real or runs out of attempts.
+    fn run<T>(&self, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+        loop {
+            match op() {
+                Err(err) if is_transient(&err) && attempt < self.max_attempts => {
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+/// Whether an error goes away by itself, `EINTR` or `EAGAIN`. Running out
+/// of space or permissions never does.
+fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+    )
+}
+
+/// A storage whose readers and writers retry every call on a transient
+/// error.
+///
+/// A failed call transfers no data, so retrying a single call is safe, and
+/// the buffers above never see the error.
+#[derive(Debug)]
+pub(super) struct RetryStorage {
+    pub inner: Arc<dyn LogStorage>,
+    pub policy: RetryPolicy,
+}
+impl LogStorage for RetryStorage {
+    fn list_generations(&self) -> Result<Vec<u64>> {
+        self.inner.list_generations()
+    }
+
+    fn open(&self, gen: u64, kind: LogKind) -> Result<Box<dyn LogReader>> {
+        Ok(Box::new(Retrying {
+            inner: self.inner.open(gen, kind)?,
+            policy: self.policy,
+        }))
+    }
+
+    fn create(&self, gen: u64, kind: LogKind) -> Result<Box<dyn LogWriter>> {
+        Ok(Box::new(Retrying {
+            inner: self.inner.create(gen, kind)?,
+            policy: self.policy,
+        }))
+    }
+
+    fn remove(&self, gen: u64, kind: LogKind) -> Result<()> {
+        self.inner.remove(gen, kind)
+    }
+
+    fn rename(&self, gen: u64, new_gen: u64, kind: LogKind) -> Result<()> {
+        self.inner.rename(gen, new_gen, kind)
+    }
+
+    fn len(&self, gen: u64, kind: LogKind) -> Result<Option<u64>> {
+        self.inner.len(gen, kind)
+    }
+
+    fn create_temp(&self, gen: u64, kind: LogKind) -> Result<Box<dyn LogWriter>> {
+        Ok(Box::new(Retrying {
+            inner: self.inner.create_temp(gen, kind)?,
+            policy: self.policy,
+        }))
+    }
+
+    fn persist_temp(&self, gen: u64, kind: LogKind) -> Result<()> {
+        self.inner.persist_temp(gen, kind)
+    }
+
+    fn remove_temps(&self) -> Result<()> {
+        self.inner.remove_temps()
+    }
+}
+
+struct Retrying<T> {
+    inner: T,
+    policy: RetryPolicy,
+}
+impl Read for Retrying<Box<dyn LogReader>> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        self.policy.run(|| inner.read(buf))
+    }
+}
+impl Seek for Retrying<Box<dyn LogReader>> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let inner = &mut self.inner;
+        self.policy.run(|| inner.seek(pos))
+    }
+}
+impl Write for Retrying<Box<dyn LogWriter>> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        self.policy.run(|| inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let inner = &mut self.inner;
+        self.policy.run(|| inner.flush())
+    }
+}
+impl Seek for Retrying<Box<dyn LogWriter>> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let inner = &mut self.inner;
+        self.policy.run(|| inner.seek(pos))
+    }
+}
+impl LogWriter for Retrying<Box<dyn LogWriter>> {
+    fn sync(&mut self) -> io::Result<()> {
+        let inner = &mut self.inner;
+        self.policy.run(|| inner.sync())
+    }
+}
@@ -0,0 +1,328 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use super::{log_file_path, sorted_gen_list, value_log_file_path};
+use crate::Result;
+
+/// The kind of a file in a generation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogKind {
+    /// The log of commands, `<gen>.log` on the file system.
+    Log,
+    /// The value log of the split layout, `<gen>.vlog` on the file system.
+    ValueLog,
+}
+
+/// A log opened for reading.
+pub trait LogReader: Read + Seek + Send {}
+impl<T: Read + Seek + Send> LogReader for T {}
+
+/// A log opened for appending.
+pub trait LogWriter: Write + Seek + Send {
+    /// Make the written data durable, it does nothing by default.
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+impl LogWriter for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+}
+
+/// Where the logs of a `KvStore` live.
+///
+/// `FsStorage` keeps them as files in the store directory. Others like
+/// `MemoryStorage` can be supplied with `KvStoreOptions::storage`.
+pub trait LogStorage: Debug + Send + Sync {
+    /// Returns the sorted generation numbers having a `LogKind::Log`.
+    fn list_generations(&self) -> Result<Vec<u64>>;
+
+    /// Opens an existing log for reading.
+    fn open(&self, gen: u64, kind: LogKind) -> Result<Box<dyn LogReader>>;
+
+    /// Opens a log for appending, it's created if missing.
+    fn create(&self, gen: u64, kind: LogKind) -> Result<Box<dyn LogWriter>>;
+
+    /// Removes a log, it's not an error if the log is missing.
+    fn remove(&self, gen: u64, kind: LogKind) -> Result<()>;
+
+    /// Renames a log to another generation, replacing one that exists.
+    fn rename(&self, gen: u64, new_gen: u64, kind: LogKind) -> Result<()>;
+
+    /// Returns the length of a log, `None` if it's missing.
+    fn len(&self, gen: u64, kind: LogKind) -> Result<Option<u64>>;
+
+    /// Creates a temporary log, it's not listed or opened until persisted.
+    fn create_temp(&self, gen: u64, kind: LogKind) -> Result<Box<dyn LogWriter>>;
+
+    /// Atomically turns a temporary log into the log of the generation.
+    fn persist_temp(&self, gen: u64, kind: LogKind) -> Result<()>;
+
+    /// Removes all temporary logs, e.g. the ones left by a crash.
+    fn remove_temps(&self) -> Result<()>;
+}
+
+/// Logs as files in a directory, the default storage.
+#[derive(Clone, Debug)]
+pub struct FsStorage {
+    dir: PathBuf,
+    sync_dir: bool,
+}
+impl FsStorage {
+    /// Storage in the given directory, see `sync_directory`.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        FsStorage {
+            dir: dir.into(),
+            sync_dir: true,
+        }
+    }
+
+    /// Whether the directory is fsynced after a log is created or renamed,
+    /// `true` by default.
+    ///
+    /// On most Unix file systems, e.g. ext4 or XFS, a new or renamed file
+    /// isn't durable until its directory is synced, a crash might lose the
+    /// whole generation otherwise. Directories can't be synced on Windows,
+    /// it does nothing there.
+    pub fn sync_directory(mut self, sync_dir: bool) -> Self {
+        self.sync_dir = sync_dir;
+        self
+    }
+
+    /// Make the entries of the directory durable, if enabled.
+    fn sync_dir(&self) -> io::Result<()> {
+        #[cfg(unix)]
+        if self.sync_dir {
+            File::open(&self.dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn file_path(&self, gen: u64, kind: LogKind) -> PathBuf {
+        match kind {
+            LogKind::Log => log_file_path(&self.dir, gen),
+            LogKind::ValueLog => value_log_file_path(&self.dir, gen),
+        }
+    }
+
+    fn temp_file_path(&self, gen: u64, kind: LogKind) -> PathBuf {
+        let mut path = self.file_path(gen, kind).into_os_string();
+        path.push(".tmp");
+        path.into()
+    }
+}
+
+impl LogStorage for FsStorage {
+    fn list_generations(&self) -> Result<Vec<u64>> {
+        sorted_gen_list(&self.dir)
+    }
+
+    fn open(&self, gen: u64, kind: LogKind) -> Result<Box<dyn LogReader>> {
+        Ok(Box::new(File::open(self.file_path(gen, kind))?))
+    }
+
+    fn create(&self, gen: u64, kind: LogKind) -> Result<Box<dyn LogWriter>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.file_path(gen, kind))?;
+        self.sync_dir()?;
+        Ok(Box::new(file))
+    }
+
+    fn remove(&self, gen: u64, kind: LogKind) -> Result<()> {
+        match fs::remove_file(self.file_path(gen, kind)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn rename(&self, gen: u64, new_gen: u64, kind: LogKind) -> Result<()> {
+        fs::rename(self.file_path(gen, kind), self.file_path(new_gen, kind))?;
+        self.sync_dir()?;
+        Ok(())
+    }
+
+    fn len(&self, gen: u64, kind: LogKind) -> Result<Option<u64>> {
+        match fs::metadata(self.file_path(gen, kind)) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn create_temp(&self, gen: u64, kind: LogKind) -> Result<Box<dyn LogWriter>> {
+        // truncate whatever an earlier attempt left
+        let file = File::create(self.temp_file_path(gen, kind))?;
+        Ok(Box::new(file))
+    }
+
+    fn persist_temp(&self, gen: u64, kind: LogKind) -> Result<()> {
+        fs::rename(self.temp_file_path(gen, kind), self.file_path(gen, kind))?;
+        self.sync_dir()?;
+        Ok(())
+    }
+
+    fn remove_temps(&self) -> Result<()> {
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let is_temp_log = path
+                .file_name()
+                .and_then(OsStr::to_str)
+                .is_some_and(|name| name.ends_with(".log.tmp") || name.ends_with(".vlog.tmp"));
+            if is_temp_log {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+type SharedBuf = Arc<Mutex<Vec<u8>>>;
+
+/// Logs kept in memory, nothing touches the disk.
+///
+/// Clones share the same logs, so a store can be reopened over them.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStorage {
+    logs: Arc<Mutex<BTreeMap<(u64, LogKind), SharedBuf>>>,
+    temps: Arc<Mutex<BTreeMap<(u64, LogKind), SharedBuf>>>,
+}
+impl MemoryStorage {
+    /// An empty storage.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn get(&self, gen: u64, kind: LogKind) -> Option<SharedBuf> {
+        self.logs.lock().unwrap().get(&(gen, kind)).cloned()
+    }
+}
+
+impl LogStorage for MemoryStorage {
+    fn list_generations(&self) -> Result<Vec<u64>> {
+        Ok(self
+            .logs
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|(_, kind)| *kind == LogKind::Log)
+            .map(|(gen, _)| *gen)
+            .collect())
+    }
+
+    fn open(&self, gen: u64, kind: LogKind) -> Result<Box<dyn LogReader>> {
+        let buf = self
+            .get(gen, kind)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        Ok(Box::new(MemoryLog { buf, pos: 0 }))
+    }
+
+    fn create(&self, gen: u64, kind: LogKind) -> Result<Box<dyn LogWriter>> {
+        let buf = self
+            .logs
+            .lock()
+            .unwrap()
+            .entry((gen, kind))
+            .or_default()
+            .clone();
+        let pos = buf.lock().unwrap().len() as u64;
+        Ok(Box::new(MemoryLog { buf, pos }))
+    }
+
+    fn remove(&self, gen: u64, kind: LogKind) -> Result<()> {
+        self.logs.lock().unwrap().remove(&(gen, kind));
+        Ok(())
+    }
+
+    fn rename(&self, gen: u64, new_gen: u64, kind: LogKind) -> Result<()> {
+        let mut logs = self.logs.lock().unwrap();
+        let buf = logs
+            .remove(&(gen, kind))
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        logs.insert((new_gen, kind), buf);
+        Ok(())
+    }
+
+    fn len(&self, gen: u64, kind: LogKind) -> Result<Option<u64>> {
+        Ok(self
+            .get(gen, kind)
+            .map(|buf| buf.lock().unwrap().len() as u64))
+    }
+
+    fn create_temp(&self, gen: u64, kind: LogKind) -> Result<Box<dyn LogWriter>> {
+        let buf = SharedBuf::default();
+        self.temps.lock().unwrap().insert((gen, kind), buf.clone());
+        Ok(Box::new(MemoryLog { buf, pos: 0 }))
+    }
+
+    fn persist_temp(&self, gen: u64, kind: LogKind) -> Result<()> {
+        let buf = self
+            .temps
+            .lock()
+            .unwrap()
+            .remove(&(gen, kind))
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        self.logs.lock().unwrap().insert((gen, kind), buf);
+        Ok(())
+    }
+
+    fn remove_temps(&self) -> Result<()> {
+        self.temps.lock().unwrap().clear();
+        Ok(())
+    }
+}
+
+/// A handle of a log in `MemoryStorage`, writes always append.
+struct MemoryLog {
+    buf: SharedBuf,
+    pos: u64,
+}
+
+impl Read for MemoryLog {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let buf = self.buf.lock().unwrap();
+        let start = (self.pos as usize).min(buf.len());
+        let len = out.len().min(buf.len() - start);
+        out[..len].copy_from_slice(&buf[start..start + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for MemoryLog {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let mut buf = self.buf.lock().unwrap();
+        buf.extend_from_slice(data);
+        self.pos = buf.len() as u64;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryLog {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.buf.lock().unwrap().len() as i64;
+        let new_pos = match pos {
+            SeekFrom::Start(pos) => pos as i64,
+            SeekFrom::End(offset) => len + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if new_pos < 0 {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl LogWriter for MemoryLog {}
@@ -0,0 +1,229 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::Arc;
+
+use serde_json::Deserializer;
+
+use super::{read_format_version, Command, CommandPosInfo, LogKind, LogStorage, Op};
+use crate::{KvsError, Result};
+
+/// A committed record yielded by `LogTailer`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TailRecord {
+    /// A set or a removal, the ones of a transaction come one by one once
+    /// it's committed. A soft removal is a removal.
+    Op {
+        /// Where the record is in the logs.
+        pos: CommandPosInfo,
+        /// The sequence number of the record.
+        seq: u64,
+        /// The operation, with the value read from the value log with the
+        /// split layout.
+        op: Op,
+    },
+    /// What's yielded before is obsolete, the records after make up the
+    /// whole content. It's a marker written by a compaction, or the log
+    /// read is removed and the tailer starts over.
+    Reset {
+        /// Where the marker is in the logs, the start of the generation the
+        /// tailer starts over from if it's not written.
+        pos: CommandPosInfo,
+    },
+}
+
+/// Follows the logs of a `KvStore` as they grow, see `KvStore::tail`.
+///
+/// `next_record` returns `None` once it's caught up, the caller polls it
+/// again later. It moves to the next generation once the log it reads is
+/// left behind by a rotation or a compaction. Records are yielded only once
+/// they're complete, and the ones of a transaction once it's committed.
+///
+/// It reads the logs on its own and never blocks the store. Once the log it
+/// reads is removed, e.g. by a compaction, it yields a `TailRecord::Reset`
+/// and starts over from the oldest generation left, so the consumer gets
+/// the whole content again.
+pub struct LogTailer {
+    storage: Arc<dyn LogStorage>,
+    gen: u64,
+    // the end of the last committed record read
+    offset: u64,
+    // a newer generation is seen, the log is read once more before moving on
+    sealed: bool,
+    pending: VecDeque<TailRecord>,
+}
+impl fmt::Debug for LogTailer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LogTailer")
+            .field("gen", &self.gen)
+            .field("offset", &self.offset)
+            .finish_non_exhaustive()
+    }
+}
+
+impl LogTailer {
+    pub(super) fn new(storage: Arc<dyn LogStorage>, gen: u64, offset: u64) -> Result<Self> {
+        if storage.len(gen, LogKind::Log)?.is_none() {
+            return Err(KvsError::MissingGeneration { gen });
+        }
+        Ok(LogTailer {
+            storage,
+            gen,
+            offset,
+            sealed: false,
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// Returns the `(generation, offset)` following the records yielded, to
+    /// resume from with `KvStore::tail`.
+    pub fn position(&self) -> (u64, u64) {
+        match self.pending.front() {
+            Some(TailRecord::Op { pos, .. } | TailRecord::Reset { pos }) => (pos.gen, pos.pos),
+            None => (self.gen, self.offset),
+        }
+    }
+
+    /// Returns the next committed record, `None` if there's none yet.
+    ///
+    /// # Errors
+    /// It propagates I/O or deserialization errors during reading the logs,
+    /// a record cut short by a write in progress isn't an error.
+    pub fn next_record(&mut self) -> Result<Option<TailRecord>> {
+        loop {
+            if let Some(record) = self.pending.pop_front() {
+                return Ok(Some(record));
+            }
+            let read = match self.read_log() {
+                Err(KvsError::MissingGeneration { gen }) => {
+                    // what's left makes up the whole content, see `open`
+                    let first = match self.storage.list_generations()?.first() {
+                        Some(&first) => first,
+                        None => return Err(KvsError::MissingGeneration { gen }),
+                    };
+                    self.gen = first;
+                    self.offset = 0;
+                    self.sealed = false;
+                    return Ok(Some(TailRecord::Reset {
+                        pos: CommandPosInfo {
+                            gen: first,
+                            pos: 0,
+                            len: 0,
+                        },
+                    }));
+                }
+                read => read?,
+            };
+            if read {
+                continue;
+            }
+            let newer = self
+                .storage
+                .list_generations()?
+                .into_iter()
+                .find(|&gen| gen > self.gen);
+            match newer {
+                None => return Ok(None),
+                // the last writes to the log may have landed after it's read
+                Some(_) if !self.sealed => self.sealed = true,
+                Some(gen) => {
+                    self.gen = gen;
+                    self.offset = 0;
+                    self.sealed = false;
+                }
+            }
+        }
+    }
+
+    /// Reads the committed records after `offset` into `pending`, returns
+    /// whether any is read.
+    fn read_log(&mut self) -> Result<bool> {
+        let mut reader = match self.storage.len(self.gen, LogKind::Log)? {
+            Some(len) if len > self.offset => self.storage.open(self.gen, LogKind::Log)?,
+            Some(_) => return Ok(false),
+            None => return Err(KvsError::MissingGeneration { gen: self.gen }),
+        };
+        reader.seek(SeekFrom::Start(self.offset))?;
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let mut start = 0;
+        if self.offset == 0 {
+            let mut slice = &bytes[..];
+            read_format_version(&mut slice)?;
+            start = bytes.len() - slice.len();
+        }
+
+        let base = self.offset + start as u64;
+        let mut committed = start;
+        let mut txn: Option<Vec<TailRecord>> = None;
+        let mut records = Vec::new();
+        let mut pos = 0;
+        let mut stream = Deserializer::from_slice(&bytes[start..]).into_iter::<Command>();
+        while let Some(cmd) = stream.next() {
+            let cmd = match cmd {
+                Ok(cmd) => cmd,
+                // the rest is being written
+                Err(err) if err.is_eof() => break,
+                Err(err) => return Err(err.into()),
+            };
+            let end = stream.byte_offset();
+            let cmd_pos = CommandPosInfo {
+                gen: self.gen,
+                pos: base + pos as u64,
+                len: (end - pos) as u64,
+            };
+            pos = end;
+            let record = match cmd {
+                Command::TxnBegin => {
+                    txn = Some(Vec::new());
+                    continue;
+                }
+                Command::TxnCommit => {
+                    records.extend(txn.take().unwrap_or_default());
+                    committed = start + end;
+                    continue;
+                }
+                Command::Reset => TailRecord::Reset { pos: cmd_pos },
+                cmd => self.op_record(cmd, cmd_pos)?,
+            };
+            match &mut txn {
+                Some(ops) => ops.push(record),
+                None => {
+                    records.push(record);
+                    committed = start + end;
+                }
+            }
+        }
+        self.offset += committed as u64;
+        let read = !records.is_empty();
+        self.pending.extend(records);
+        Ok(read)
+    }
+
+    fn op_record(&self, cmd: Command, pos: CommandPosInfo) -> Result<TailRecord> {
+        let seq = cmd.seq();
+        let op = match cmd {
+            Command::Set { key, value, .. } => Op::Set { key, value },
+            Command::SetRef {
+                key,
+                pos: value_pos,
+                len,
+                ..
+            } => {
+                let mut reader = self.storage.open(pos.gen, LogKind::ValueLog)?;
+                reader.seek(SeekFrom::Start(value_pos))?;
+                let mut bytes = vec![0; len as usize];
+                reader.read_exact(&mut bytes)?;
+                Op::Set {
+                    key,
+                    value: serde_json::from_slice(&bytes)?,
+                }
+            }
+            Command::Remove { key, .. } | Command::SoftRemove { key, .. } => Op::Remove { key },
+            Command::TxnBegin | Command::TxnCommit | Command::Reset => {
+                return Err(KvsError::UnexpectedCommandType)
+            }
+        };
+        Ok(TailRecord::Op { pos, seq, op })
+    }
+}
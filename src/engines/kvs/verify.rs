@@ -0,0 +1,93 @@
+use std::fs;
+use std::path::Path;
+
+use serde_json::Deserializer;
+
+use super::{
+    log_file_path, read_format_version, sorted_gen_list, KvStore, ReplayCommand, FORMAT_HEADER_LEN,
+};
+use crate::Result;
+
+/// A byte range of a log which is not a valid command.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CorruptSpan {
+    /// The generation of the log.
+    pub gen: u64,
+    /// Offset of the first corrupt byte.
+    pub start: u64,
+    /// Offset after the last corrupt byte, where the next valid command
+    /// starts or the end of the log.
+    pub end: u64,
+    /// Why the first command of the span can't be read.
+    pub reason: String,
+}
+
+/// The result of `KvStore::verify`.
+#[derive(Clone, Debug, Default)]
+pub struct VerifyReport {
+    /// Corrupt spans of all logs, ordered by generation and offset.
+    pub corrupt_spans: Vec<CorruptSpan>,
+}
+impl VerifyReport {
+    /// Whether no corruption is found.
+    pub fn is_ok(&self) -> bool {
+        self.corrupt_spans.is_empty()
+    }
+}
+
+/// Check every log of the store at `path`, see `KvStore::verify`.
+pub(super) fn verify(path: &Path) -> Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    for gen in sorted_gen_list(path)? {
+        let bytes = fs::read(log_file_path(path, gen))?;
+        let mut pos = match read_format_version(&mut &bytes[..])? {
+            0 => 0,
+            KvStore::FORMAT_VERSION => FORMAT_HEADER_LEN as usize,
+            found => {
+                report.corrupt_spans.push(CorruptSpan {
+                    gen,
+                    start: 0,
+                    end: bytes.len() as u64,
+                    reason: format!("unsupported format version {}", found),
+                });
+                continue;
+            }
+        };
+        while pos < bytes.len() {
+            let mut stream = Deserializer::from_slice(&bytes[pos..]).into_iter::<ReplayCommand>();
+            let err = loop {
+                match stream.next() {
+                    Some(Ok(_)) => {}
+                    Some(Err(err)) => break Some(err),
+                    None => break None,
+                }
+            };
+            let start = pos + stream.byte_offset();
+            let err = match err {
+                Some(err) => err,
+                None => break,
+            };
+            // skip to the next position where a command can be read
+            let end = (start + 1..bytes.len())
+                .find(|&next| bytes[next] == b'{' && starts_with_command(&bytes[next..]))
+                .unwrap_or(bytes.len());
+            report.corrupt_spans.push(CorruptSpan {
+                gen,
+                start: start as u64,
+                end: end as u64,
+                reason: err.to_string(),
+            });
+            pos = end;
+        }
+    }
+    Ok(report)
+}
+
+fn starts_with_command(bytes: &[u8]) -> bool {
+    matches!(
+        Deserializer::from_slice(bytes)
+            .into_iter::<ReplayCommand>()
+            .next(),
+        Some(Ok(_))
+    )
+}
@@ -0,0 +1,107 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use super::CommandPos;
+
+/// The locations of the previous values of the keys, the newest first.
+///
+/// Only live keys have versions, a removal drops them. Their records are
+/// not stale, compactions copy them along with the live ones.
+#[derive(Debug)]
+pub(super) struct Versions {
+    // the number of previous values kept per key
+    depth: usize,
+    inner: Mutex<HashMap<String, VecDeque<CommandPos>>>,
+}
+
+impl Versions {
+    pub(super) fn new(depth: usize) -> Self {
+        Versions {
+            depth,
+            inner: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Keep `old` as the previous value of `key`, returns the oldest version
+    /// if it falls out.
+    pub(super) fn push(&self, key: &str, old: CommandPos) -> Option<CommandPos> {
+        let mut inner = self.inner.lock().unwrap();
+        let versions = match inner.get_mut(key) {
+            Some(versions) => versions,
+            None => inner.entry(key.to_owned()).or_default(),
+        };
+        versions.push_front(old);
+        if versions.len() > self.depth {
+            versions.pop_back()
+        } else {
+            None
+        }
+    }
+
+    /// The value `versions_ago` before the current one, 1 for the previous.
+    pub(super) fn get(&self, key: &str, versions_ago: usize) -> Option<CommandPos> {
+        let inner = self.inner.lock().unwrap();
+        inner.get(key)?.get(versions_ago.checked_sub(1)?).copied()
+    }
+
+    /// Drop the versions of `key`, returns their total length.
+    pub(super) fn forget(&self, key: &str) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(key).map_or(0, |versions| {
+            versions.iter().map(|cmd_pos| cmd_pos.len).sum()
+        })
+    }
+
+    pub(super) fn clear(&self) {
+        self.inner.lock().unwrap().clear();
+    }
+
+    /// An empty set keeping as many versions.
+    pub(super) fn emptied(&self) -> Versions {
+        Versions::new(self.depth)
+    }
+
+    /// Take the versions of `other` in place of these.
+    pub(super) fn replace_with(&self, other: &Versions) {
+        let versions = std::mem::take(&mut *other.inner.lock().unwrap());
+        *self.inner.lock().unwrap() = versions;
+    }
+
+    /// Every version with its key and how many versions ago it is.
+    pub(super) fn entries(&self) -> Vec<(String, CommandPos, usize)> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .iter()
+            .flat_map(|(key, versions)| {
+                (1..)
+                    .zip(versions)
+                    .map(move |(versions_ago, &cmd_pos)| (key.clone(), cmd_pos, versions_ago))
+            })
+            .collect()
+    }
+
+    /// Point the version of `key` at `old` to its copy at `new`.
+    pub(super) fn relocate(&self, key: &str, old: CommandPos, new: CommandPos) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(versions) = inner.get_mut(key) {
+            if let Some(cmd_pos) = versions.iter_mut().find(|cmd_pos| **cmd_pos == old) {
+                *cmd_pos = new;
+            }
+        }
+    }
+
+    /// Update the location of every version, e.g. after renumbering.
+    pub(super) fn update(&self, mut f: impl FnMut(&mut CommandPos)) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.values_mut().flatten().for_each(&mut f);
+    }
+
+    /// Drop the versions not satisfying `keep`, e.g. in removed generations.
+    pub(super) fn retain(&self, mut keep: impl FnMut(&str, &CommandPos) -> bool) {
+        let mut inner = self.inner.lock().unwrap();
+        for (key, versions) in inner.iter_mut() {
+            versions.retain(|cmd_pos| keep(key, cmd_pos));
+        }
+        inner.retain(|_, versions| !versions.is_empty());
+    }
+}
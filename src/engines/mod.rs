@@ -31,8 +31,20 @@ pub trait KvsEngine: Clone + Send + 'static {
     fn remove(&self, key: String) -> Result<()>;
 }
 
+#[cfg(feature = "tokio")]
+mod asynchronous;
 mod kvs;
 mod sled;
 
-pub use self::kvs::KvStore;
+#[cfg(feature = "tokio")]
+pub use self::asynchronous::{AsyncKvStore, DEFAULT_QUEUE_CAPACITY};
+pub use self::kvs::{
+    CacheStats, CancellationToken, CheckpointId, Clock, CommandPosInfo, CommitPolicy,
+    CompactionHistory, CompactionPreview, CompactionRecord, CorruptSpan, Dump, FsStorage, GenInfo,
+    ImportPreview, KvReader, KvStore, KvStoreOptions, LogKind, LogReader, LogStorage, LogTailer,
+    LogWriter, MemoryStorage, OnWrite, Op, OpenReport, RemoveStats, RetryPolicy, SystemClock,
+    TailRecord, VerifyReport,
+};
+#[cfg(feature = "fault-injection")]
+pub use self::kvs::{FaultSchedule, FaultyStorage};
 pub use self::sled::SledKvsEngine;
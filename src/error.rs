@@ -1,5 +1,9 @@
+// `failure_derive` generates its impls inside an anonymous const
+#![allow(non_local_definitions)]
+
 use failure::Fail;
 use std::io;
+use std::path::PathBuf;
 use std::string::FromUtf8Error;
 
 /// Error type for kvs
@@ -14,6 +18,9 @@ pub enum KvsError {
     /// Removing non-existent key error
     #[fail(display = "Key not found")]
     KeyNotFound,
+    /// Key already exists error
+    #[fail(display = "Key already exists")]
+    KeyExists,
     /// Unexpected command type error.
     /// It indicated a corrupted log or a program bug.
     #[fail(display = "Unexpected command type")]
@@ -21,6 +28,108 @@ pub enum KvsError {
     /// Key or value is invalid UTF-8 sequence
     #[fail(display = "UTF-8 error: {}", _0)]
     Utf8(#[cause] FromUtf8Error),
+    /// Value exists but is invalid UTF-8 sequence
+    #[fail(display = "Value of key {} is not valid UTF-8", key)]
+    InvalidUtf8 {
+        /// The key of the value
+        key: String,
+    },
+    /// The log is written in a format version this build can't read
+    #[fail(
+        display = "Unsupported log format version {}, up to {} is supported",
+        found, supported
+    )]
+    UnsupportedFormat {
+        /// The version of the log
+        found: u32,
+        /// The latest version supported
+        supported: u32,
+    },
+    /// Value exists but can't be parsed into the requested type
+    #[fail(display = "Value of key {} can't be parsed: {}", key, reason)]
+    Parse {
+        /// The key of the value
+        key: String,
+        /// The error of the parser
+        reason: String,
+    },
+    /// A generation referred to by the index is missing from the storage
+    #[fail(display = "Generation {} is missing", gen)]
+    MissingGeneration {
+        /// The missing generation
+        gen: u64,
+    },
+    /// A generation can't be used as the target, e.g. it's taken
+    #[fail(display = "Generation {} can't be used as the target", gen)]
+    InvalidGeneration {
+        /// The generation
+        gen: u64,
+    },
+    /// Several logs in the directory claim the same generation
+    #[fail(display = "Several logs claim generation {}", gen)]
+    DuplicateGeneration {
+        /// The generation
+        gen: u64,
+    },
+    /// The replay limit is hit without a checkpoint to start from
+    #[fail(
+        display = "Replay needs {} bytes over the limit of {} without a checkpoint",
+        needed, limit
+    )]
+    IncompleteRecovery {
+        /// The replay limit in bytes
+        limit: u64,
+        /// The bytes of the logs to replay
+        needed: u64,
+    },
+    /// A record is longer than `max_record_len`
+    #[fail(display = "Record of {} bytes is over the limit of {}", len, max)]
+    RecordTooLarge {
+        /// The length of the record
+        len: u64,
+        /// The limit
+        max: u64,
+    },
+    /// A write would take the logs over `max_total_bytes`
+    #[fail(
+        display = "Store is full, {} bytes to write go over the limit of {}",
+        needed, limit
+    )]
+    StoreFull {
+        /// The `max_total_bytes` limit
+        limit: u64,
+        /// The bytes of the record to write
+        needed: u64,
+    },
+    /// Writes are refused after strict mode detected an inconsistency
+    #[fail(display = "Store is poisoned by an inconsistent index, writes are refused")]
+    Poisoned,
+    /// The store is opened read-only
+    #[fail(display = "Store is opened read-only, writes are refused")]
+    ReadOnly,
+    /// The operation is given up through its `CancellationToken`
+    #[fail(display = "Operation is cancelled")]
+    Cancelled,
+    /// The directory belongs to another engine
+    #[fail(display = "Directory belongs to the {} engine", found)]
+    WrongEngine {
+        /// The engine named by the engine file
+        found: String,
+    },
+    /// A record up to the sequence number to roll back to comes after one
+    /// past it, so no cut of the logs discards only the newer records
+    #[fail(
+        display = "Can't roll back to seq {}, seq {} comes after a newer record in generation {}",
+        seq, found, gen
+    )]
+    SeqOutOfOrder {
+        /// The sequence number to roll back to
+        seq: u64,
+        /// The sequence number of the record out of order
+        found: u64,
+        /// The generation of the record
+        gen: u64,
+    },
     /// Sled error
     #[fail(display = "sled error: {}", _0)]
     Sled(#[cause] sled::Error),
@@ -29,6 +138,68 @@ pub enum KvsError {
     StringError(String),
 }
 
+/// Error type of `KvStore::try_open`, telling why a store can't be opened
+#[derive(Fail, Debug)]
+pub enum OpenError {
+    /// The path exists but is not a directory
+    #[fail(display = "{} is not a directory", _0)]
+    NotADirectory(String),
+    /// The directory or a log can't be accessed
+    #[fail(display = "Permission denied: {}", _0)]
+    PermissionDenied(#[cause] io::Error),
+    /// A log is locked by another process
+    #[fail(display = "Log is locked: {}", _0)]
+    Locked(#[cause] io::Error),
+    /// A log can't be replayed, it's damaged or written by a bug
+    #[fail(display = "Log is corrupt: {}", _0)]
+    CorruptLog(#[cause] KvsError),
+    /// The directory belongs to another engine
+    #[fail(display = "Directory belongs to the {} engine", found)]
+    WrongEngine {
+        /// The engine named by the engine file
+        found: String,
+    },
+    /// Any other error of `open`
+    #[fail(display = "{}", _0)]
+    Other(#[cause] KvsError),
+}
+
+impl OpenError {
+    /// Classifies an error of opening the store at `path`.
+    pub(crate) fn classify(path: PathBuf, err: KvsError) -> OpenError {
+        match err {
+            KvsError::Io(err) => match err.kind() {
+                io::ErrorKind::NotADirectory => {
+                    OpenError::NotADirectory(path.display().to_string())
+                }
+                io::ErrorKind::PermissionDenied => OpenError::PermissionDenied(err),
+                io::ErrorKind::ResourceBusy => OpenError::Locked(err),
+                _ => OpenError::Other(KvsError::Io(err)),
+            },
+            KvsError::Serde(_)
+            | KvsError::UnexpectedCommandType
+            | KvsError::Utf8(_)
+            | KvsError::InvalidUtf8 { .. } => OpenError::CorruptLog(err),
+            KvsError::WrongEngine { found } => OpenError::WrongEngine { found },
+            err => OpenError::Other(err),
+        }
+    }
+}
+
+impl From<OpenError> for KvsError {
+    fn from(err: OpenError) -> KvsError {
+        match err {
+            OpenError::NotADirectory(path) => KvsError::Io(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                format!("{} is not a directory", path),
+            )),
+            OpenError::PermissionDenied(err) | OpenError::Locked(err) => KvsError::Io(err),
+            OpenError::CorruptLog(err) | OpenError::Other(err) => err,
+            OpenError::WrongEngine { found } => KvsError::WrongEngine { found },
+        }
+    }
+}
+
 impl From<io::Error> for KvsError {
     fn from(err: io::Error) -> KvsError {
         KvsError::Io(err)
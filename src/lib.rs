@@ -2,8 +2,18 @@
 //! A simple key/value store.
 
 pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
-pub use error::{KvsError, Result};
+#[cfg(feature = "tokio")]
+pub use engines::{AsyncKvStore, DEFAULT_QUEUE_CAPACITY};
+pub use engines::{
+    CacheStats, CancellationToken, CheckpointId, Clock, CommandPosInfo, CommitPolicy,
+    CompactionHistory, CompactionPreview, CompactionRecord, CorruptSpan, Dump, FsStorage, GenInfo,
+    ImportPreview, KvReader, KvStore, KvStoreOptions, KvsEngine, LogKind, LogReader, LogStorage,
+    LogTailer, LogWriter, MemoryStorage, OnWrite, Op, OpenReport, RemoveStats, RetryPolicy,
+    SledKvsEngine, SystemClock, TailRecord, VerifyReport,
+};
+#[cfg(feature = "fault-injection")]
+pub use engines::{FaultSchedule, FaultyStorage};
+pub use error::{KvsError, OpenError, Result};
 pub use server::KvsServer;
 // pub use thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool};
 
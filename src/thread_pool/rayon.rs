@@ -7,7 +7,7 @@ pub struct RayonThreadPool(rayon::ThreadPool);
 impl ThreadPool for RayonThreadPool {
     fn new(threads: usize) -> Result<Self> {
         let pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(threads as usize)
+            .num_threads(threads)
             .build()
             .map_err(|e| KvsError::StringError(e.to_string()))?;
 
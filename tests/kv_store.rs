@@ -1,6 +1,14 @@
-use kvs::{KvStore, KvsEngine, Result};
-use std::sync::{Arc, Barrier};
+use kvs::{
+    CancellationToken, Clock, CommitPolicy, ImportPreview, KvStore, KvStoreOptions, KvsEngine,
+    KvsError, LogKind, LogReader, LogStorage, LogTailer, LogWriter, MemoryStorage, OnWrite, Op,
+    OpenError, Result, RetryPolicy, TailRecord,
+};
+use std::fs;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Barrier, Mutex};
 use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -63,21 +71,3228 @@ fn get_non_existent_value() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn get_into_buffer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "line1\nline2 \"quoted\"".to_owned())?;
+
+    let mut buf = "previous".to_owned();
+    assert!(store.get_into("key1".to_owned(), &mut buf)?);
+    assert_eq!(buf, "value1");
+    assert!(store.get_into("key2".to_owned(), &mut buf)?);
+    assert_eq!(buf, "line1\nline2 \"quoted\"");
+    assert!(!store.get_into("key3".to_owned(), &mut buf)?);
+    assert!(buf.is_empty());
+    Ok(())
+}
+
+// Should read values into the buffer of the store
+#[test]
+fn get_buffered() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "line1\nline2 \"quoted\"".to_owned())?;
+
+    assert_eq!(store.get_buffered("key1".to_owned())?, Some("value1"));
+    assert_eq!(
+        store.get_buffered("key2".to_owned())?,
+        Some("line1\nline2 \"quoted\"")
+    );
+    assert_eq!(store.get_buffered("key3".to_owned())?, None);
+    let mut clone = store.clone();
+    assert_eq!(clone.get_buffered("key1".to_owned())?, Some("value1"));
+    assert_eq!(
+        store.get_buffered("key2".to_owned())?.map(str::len),
+        Some(20)
+    );
+    Ok(())
+}
+
+// Should write borrowed strings the same as owned ones
+#[test]
+fn set_borrowed() -> Result<()> {
+    for split_values in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = || KvStoreOptions {
+            split_values,
+            ..Default::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options())?;
+        store.set_ref("key1", "value1")?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.set_ref("key2", "line1\nline2")?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(
+            store.get("key2".to_owned())?,
+            Some("line1\nline2".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// Should reject oversized records on write and take them as corruption on open
+#[test]
+fn max_record_len() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let record = r#"{"Set":{"key":"key1","value":"value1","seq":1}}"#;
+    let options = |max_record_len| KvStoreOptions {
+        max_record_len,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options(Some(record.len() as u64)))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(
+        store.set("key2".to_owned(), "value22".to_owned()),
+        Err(KvsError::RecordTooLarge { max, .. }) if max == record.len() as u64
+    ));
+    assert!(matches!(
+        store.transaction(vec![Op::Set {
+            key: "key2".to_owned(),
+            value: "value22".to_owned(),
+        }]),
+        Err(KvsError::RecordTooLarge { .. })
+    ));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+
+    KvStore::open_with_options(temp_dir.path(), options(Some(record.len() as u64)))?;
+    match KvStore::open_with_options(temp_dir.path(), options(Some(record.len() as u64 - 1))) {
+        Err(KvsError::RecordTooLarge { len, .. }) => assert_eq!(len, record.len() as u64),
+        other => panic!("unexpected result: {:?}", other.map(|_| ())),
+    }
+    Ok(())
+}
+
+// Should write pretty-printed records which read back like compact ones
+#[test]
+fn pretty_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = |pretty_records| KvStoreOptions {
+        pretty_records,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options(true))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.transaction(vec![Op::Remove {
+        key: "key2".to_owned(),
+    }])?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let log = fs::read(temp_dir.path().join("1.log"))?;
+    assert!(String::from_utf8_lossy(&log).contains("{\n  \"Set\": {\n"));
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options(false))?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options(true))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.compact_if_worthwhile(0)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Should read headerless logs of version 0 and rewrite them on compaction
+#[test]
+fn format_version_migration() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}"#,
+    )?;
+    assert_eq!(KvStore::format_version(temp_dir.path())?, 0);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(KvStore::format_version(temp_dir.path())?, 0);
+    drop(store);
+
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions {
+            max_generations: Some(2),
+            ..Default::default()
+        },
+    )?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(
+        KvStore::format_version(temp_dir.path())?,
+        KvStore::FORMAT_VERSION
+    );
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 1..=3 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// Should reject logs written in a newer format
+#[test]
+fn unsupported_format_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut log = b"\0kvs".to_vec();
+    log.extend_from_slice(&99u32.to_le_bytes());
+    fs::write(temp_dir.path().join("1.log"), log)?;
+
+    assert_eq!(KvStore::format_version(temp_dir.path())?, 99);
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::UnsupportedFormat { found, supported }) => {
+            assert_eq!(found, 99);
+            assert_eq!(supported, KvStore::FORMAT_VERSION);
+        }
+        other => panic!("unexpected result: {:?}", other.map(|_| ())),
+    }
+    Ok(())
+}
+
+// Should count the keys of a closed store
+#[test]
+fn estimate_key_count() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert_eq!(KvStore::estimate_key_count(temp_dir.path())?, 0);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set("key0".to_owned(), "value".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key10".to_owned(), "value10".to_owned())?;
+    drop(store);
+
+    assert_eq!(KvStore::estimate_key_count(temp_dir.path())?, 10);
+    Ok(())
+}
+
+// Should list the generations of a closed store
+#[test]
+fn list_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let gens = KvStore::list_generations(temp_dir.path(), false)?;
+    assert_eq!(
+        gens.iter().map(|info| info.gen).collect::<Vec<_>>(),
+        vec![1, 2]
+    );
+    assert_eq!(
+        gens[0].log_bytes,
+        fs::metadata(temp_dir.path().join("1.log"))?.len()
+    );
+    assert_eq!(gens[0].value_log_bytes, None);
+    assert_eq!(gens[0].records, None);
+
+    let gens = KvStore::list_generations(temp_dir.path(), true)?;
+    assert_eq!(gens[0].records, Some(2));
+    assert_eq!(gens[1].records, Some(1));
+    Ok(())
+}
+
+// Should locate the corrupt byte ranges of the logs
+#[test]
+fn verify_corrupt_spans() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert!(KvStore::verify(temp_dir.path())?.is_ok());
+
+    let valid = r#"{"Set":{"key":"key1","value":"value1"}}"#;
+    let log = format!("{}garbage{}{{\"Set\":{{\"key", valid, valid);
+    fs::write(temp_dir.path().join("2.log"), &log)?;
+    let report = KvStore::verify(temp_dir.path())?;
+    let spans: Vec<_> = report
+        .corrupt_spans
+        .iter()
+        .map(|span| (span.gen, span.start, span.end))
+        .collect();
+    let len = valid.len() as u64;
+    assert_eq!(
+        spans,
+        vec![(2, len, len + 7), (2, 2 * len + 7, log.len() as u64)]
+    );
+    assert!(report
+        .corrupt_spans
+        .iter()
+        .all(|span| !span.reason.is_empty()));
+    Ok(())
+}
+
+// Should parse values stored as strings
+#[test]
+fn get_parsed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("count".to_owned(), 42.to_string())?;
+    store.set("enabled".to_owned(), true.to_string())?;
+
+    assert_eq!(store.get_parsed::<u32>("count".to_owned())?, Some(42));
+    assert_eq!(store.get_parsed::<bool>("enabled".to_owned())?, Some(true));
+    assert_eq!(store.get_parsed::<u32>("missing".to_owned())?, None);
+    match store.get_parsed::<u32>("enabled".to_owned()) {
+        Err(KvsError::Parse { key, .. }) => assert_eq!(key, "enabled"),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    Ok(())
+}
+
+// Should return an error when a log is deleted under the store
+#[test]
+fn missing_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    // a clone opens the logs lazily
+    let reader = store.clone();
+    fs::remove_file(temp_dir.path().join("1.log"))?;
+    match reader.get("key1".to_owned()) {
+        Err(KvsError::MissingGeneration { gen }) => assert_eq!(gen, 1),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(reader.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should number every write and recover the last number on open
+#[test]
+fn sequence_numbers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}"#,
+    )?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.current_seq(), 0);
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.transaction(vec![
+        Op::Set {
+            key: "key2".to_owned(),
+            value: "value2".to_owned(),
+        },
+        Op::Set {
+            key: "key3".to_owned(),
+            value: "value3".to_owned(),
+        },
+    ])?;
+    assert_eq!(store.current_seq(), 4);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.current_seq(), 4);
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.current_seq(), 5);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should discard the records after a sequence number, in sealed logs too
+#[test]
+fn rollback_to_seq() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // the first record after it is in the sealed generation
+    store.rollback_to_seq(2)?;
+    assert_eq!(store.current_seq(), 2);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert!(!temp_dir.path().join("2.log").exists());
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.current_seq(), 3);
+    store.rollback_to_seq(3)?;
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.current_seq(), 3);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    // a record up to it can't follow the cut
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    store.append_raw(br#"{"Set":{"key":"key6","value":"value6","seq":1}}"#)?;
+    assert!(matches!(
+        store.rollback_to_seq(3),
+        Err(KvsError::SeqOutOfOrder {
+            seq: 3,
+            found: 1,
+            ..
+        })
+    ));
+    assert_eq!(store.get("key5".to_owned())?, Some("value5".to_owned()));
+    Ok(())
+}
+
+// Should cut a transaction as a whole and the values of the split layout
+#[test]
+fn rollback_to_seq_split_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions {
+        split_values: true,
+        ..Default::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let value_len = fs::metadata(temp_dir.path().join("1.vlog"))?.len();
+    store.transaction(vec![
+        Op::Set {
+            key: "key1".to_owned(),
+            value: "value2".to_owned(),
+        },
+        Op::Set {
+            key: "key2".to_owned(),
+            value: "value2".to_owned(),
+        },
+    ])?;
+
+    // the transaction is partly after it
+    store.rollback_to_seq(2)?;
+    assert_eq!(store.current_seq(), 1);
+    assert_eq!(
+        fs::metadata(temp_dir.path().join("1.vlog"))?.len(),
+        value_len
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Should report the end of the flushed commands of the active log
+#[test]
+fn durable_offset() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.durable_offset(), (1, 8));
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    let len = fs::metadata(temp_dir.path().join("1.log"))?.len();
+    assert_eq!(store.durable_offset(), (1, len));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.durable_offset(), (2, 8));
+    Ok(())
+}
+
+// Should serve hot keys from the value cache, invalidated by writes
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions {
+            cache_values: true,
+            value_cache_bytes: 15,
+            ..Default::default()
+        },
+    )?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let stats = store.cache_stats().unwrap();
+    assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+    assert_eq!(stats.hit_rate(), Some(0.5));
+
+    // only one key and value fit, key1 is evicted
+    assert_eq!(
+        store.clone().get("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(store.cache_stats().unwrap().entries, 1);
+    assert_eq!(store.cache_stats().unwrap().bytes, 10);
+
+    store.set("key2".to_owned(), "new".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("new".to_owned()));
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.cache_stats().unwrap().entries, 0);
+
+    let store = KvStore::open(TempDir::new()?.path())?;
+    assert_eq!(store.cache_stats(), None);
+    Ok(())
+}
+
+// Should find every key through the bloom filters across compactions and reopens
+#[test]
+fn bloom_filters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions {
+        bloom_filters: true,
+        max_generations: Some(3),
+        ..Default::default()
+    };
+    let check = |store: &KvStore, keys: usize| -> Result<()> {
+        for i in 0..keys {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+        assert_eq!(store.get("missing".to_owned())?, None);
+        assert_eq!(store.peek("missing".to_owned())?, None);
+        Ok(())
+    };
+
+    // more keys than a filter starts with
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for i in 0..3000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    check(&store, 3000)?;
+    store.remove("key0".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    store.set("key0".to_owned(), "value0".to_owned())?;
+
+    for _ in 0..3 {
+        store.reopen()?;
+        check(&store, 3000)?;
+    }
+    store.set_compaction_threshold(0)?;
+    check(&store, 3000)?;
+    store.normalize_generations()?;
+    store.set("key3000".to_owned(), "value3000".to_owned())?;
+    check(&store, 3001)?;
+    Ok(())
+}
+
+// Should look up the keys by value prefix, kept up to date by writes
+#[test]
+fn keys_with_value_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions {
+        value_prefix_len: Some(3),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("a".to_owned(), "apple".to_owned())?;
+    store.set("b".to_owned(), "apricot".to_owned())?;
+    store.set("c".to_owned(), "ap".to_owned())?;
+    store.set("d".to_owned(), "banana".to_owned())?;
+    assert_eq!(store.keys_with_value_prefix("ap")?, vec!["a", "b", "c"]);
+    assert_eq!(store.keys_with_value_prefix("app")?, vec!["a"]);
+    assert_eq!(store.keys_with_value_prefix("apri")?, vec!["b"]);
+    assert_eq!(store.keys_with_value_prefix("aprx")?, Vec::<String>::new());
+
+    store.set("a".to_owned(), "banana".to_owned())?;
+    store.remove("b".to_owned())?;
+    store.rename("c".to_owned(), "e".to_owned(), false)?;
+    store.transaction(vec![Op::Set {
+        key: "f".to_owned(),
+        value: "apple".to_owned(),
+    }])?;
+    assert_eq!(store.keys_with_value_prefix("ap")?, vec!["e", "f"]);
+    assert_eq!(store.keys_with_value_prefix("ban")?, vec!["a", "d"]);
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.keys_with_value_prefix("")?, vec!["a", "d", "e", "f"]);
+    assert_eq!(store.keys_with_value_prefix("ap")?, vec!["e", "f"]);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.keys_with_value_prefix("ap").is_err());
+    Ok(())
+}
+
+// Should refuse writes in strict mode after reading an unexpected command
+#[test]
+fn strict_mode() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("1.log");
+    let set = r#"{"Set":{"key":"k","value":"v"}}"#;
+    // a removal of the same length in place of the set
+    let remove = r#"{"Remove":{"key":"0123456789"}}"#;
+    assert_eq!(set.len(), remove.len());
+
+    fs::write(&log_path, set)?;
+    let store = KvStore::open(temp_dir.path())?;
+    fs::write(&log_path, remove)?;
+    assert!(matches!(
+        store.get("k".to_owned()),
+        Err(KvsError::UnexpectedCommandType)
+    ));
+    assert!(!store.is_poisoned());
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    fs::write(&log_path, set)?;
+    let strict = || KvStoreOptions {
+        strict: true,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), strict())?;
+    fs::write(&log_path, remove)?;
+    assert!(matches!(
+        store.get("k".to_owned()),
+        Err(KvsError::UnexpectedCommandType)
+    ));
+    assert!(store.is_poisoned());
+    assert!(matches!(
+        store.set("key2".to_owned(), "value2".to_owned()),
+        Err(KvsError::Poisoned)
+    ));
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::Poisoned)
+    ));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Should pass the health check without touching the data or the counters
+#[test]
+fn health_check() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let (seq, offset) = (store.current_seq(), store.durable_offset());
+
+    store.health_check()?;
+    assert_eq!(store.current_seq(), seq);
+    assert_eq!(store.durable_offset(), offset);
+    assert_eq!(store.stale_bytes(), 0);
+
+    fs::remove_file(temp_dir.path().join("1.log"))?;
+    assert!(store.health_check().is_err());
+    Ok(())
+}
+
+// Should iterate the values in key order
+#[test]
+fn values_in_key_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.values().count(), 0);
+    for key in ["b", "c", "a", "d"] {
+        store.set(key.to_owned(), format!("value_{}", key))?;
+    }
+    store.remove("d".to_owned())?;
+
+    let values: Vec<String> = store.values().collect::<Result<_>>()?;
+    assert_eq!(values, vec!["value_a", "value_b", "value_c"]);
+    Ok(())
+}
+
+// Should dump the pairs of a consumed store in key order
+#[test]
+fn into_iter_dump() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3".to_owned())?;
+
+    let pairs = store.into_iter().collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "value2".to_owned()),
+        ]
+    );
+    Ok(())
+}
+
+// Should peek the same values as get
+#[test]
+fn peek_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert_eq!(store.peek("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.peek("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Should report a value which is not valid UTF-8 with its key
+#[test]
+fn get_invalid_utf8_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut log = br#"{"Set":{"key":"key1","value":"value1"}}"#.to_vec();
+    log.extend_from_slice(b"{\"Set\":{\"key\":\"key2\",\"value\":\"\xff\xfe\"}}");
+    fs::write(temp_dir.path().join("1.log"), log)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    match store.get("key2".to_owned()) {
+        Err(KvsError::InvalidUtf8 { key }) => assert_eq!(key, "key2"),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    Ok(())
+}
+
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
+    assert!(store.remove("key1".to_owned()).is_err());
+    Ok(())
+}
+
+// Should remove a missing key without an error if configured to
+#[test]
+fn remove_missing_key_idempotent() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions {
+            remove_missing_is_error: false,
+            ..Default::default()
+        },
+    )?;
+    store.remove("key1".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Should read from other threads through read-only handles seeing the writes
+#[test]
+fn reader() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "value1".to_owned())?;
+    store.set("b".to_owned(), "value2".to_owned())?;
+    store.set("c".to_owned(), "value3".to_owned())?;
+    let reader = store.reader();
+    assert!(reader.contains_key("a"));
+    assert!(!reader.contains_key("d"));
+    assert_eq!(
+        reader.range("a".."c")?,
+        vec![
+            ("a".to_owned(), "value1".to_owned()),
+            ("b".to_owned(), "value2".to_owned()),
+        ]
+    );
+    assert_eq!(reader.range("b"..)?.len(), 2);
+
+    let (sender, receiver) = mpsc::channel();
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let reader = reader.clone();
+            let sender = sender.clone();
+            thread::spawn(move || {
+                let value = reader.get("a".to_owned()).unwrap();
+                sender.send(value).unwrap();
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    drop(sender);
+    assert!(receiver
+        .iter()
+        .all(|value| value == Some("value1".to_owned())));
+
+    store.set("a".to_owned(), "value4".to_owned())?;
+    store.remove("b".to_owned())?;
+    store.set_compaction_threshold(0)?;
+    let value = thread::spawn(move || reader.get("a".to_owned()).unwrap())
+        .join()
+        .unwrap();
+    assert_eq!(value, Some("value4".to_owned()));
+    assert!(!store.reader().contains_key("b"));
+    Ok(())
+}
+
+// Should read the newest and older generations alike across rotations and
+// compactions
+#[test]
+fn read_across_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.checkpoint_barrier()?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    for _ in 0..2 {
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.set_compaction_threshold(0)?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    store.normalize_generations()?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// Should return the location with the value, which moves on writes
+#[test]
+fn get_versioned() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_versioned("key1".to_owned())?, None);
+    assert_eq!(store.position("key1"), None);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let (value, version) = store.get_versioned("key1".to_owned())?.unwrap();
+    assert_eq!(value, "value1");
+    assert_eq!(store.position("key1"), Some(version));
+
+    // another key doesn't move it
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.position("key1"), Some(version));
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_ne!(store.position("key1"), Some(version));
+    let (_, version) = store.get_versioned("key1".to_owned())?.unwrap();
+    store.set_compaction_threshold(0)?;
+    assert_ne!(store.position("key1"), Some(version));
+    Ok(())
+}
+
+// Should return the bytes of the record as written
+#[test]
+fn raw_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.raw_record("key1".to_owned())?, None);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let record: serde_json::Value =
+        serde_json::from_slice(&store.raw_record("key1".to_owned())?.unwrap()).unwrap();
+    assert_eq!(record["Set"]["key"], "key1");
+    assert_eq!(record["Set"]["value"], "value1");
+
+    let record = br#"{"Set":{"key":"raw","value":"value2"}}"#;
+    let info = store.append_raw(record)?;
+    store.index_raw("raw".to_owned(), Some(info))?;
+    assert_eq!(store.raw_record("raw".to_owned())?, Some(record.to_vec()));
+    Ok(())
+}
+
+// Should append raw records and index them only on request
+#[test]
+fn append_raw() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let record = br#"{"Set":{"key":"raw","value":"value2"}}"#;
+    let info = store.append_raw(record)?;
+    assert_eq!((info.gen, info.len), (1, record.len() as u64));
+    assert_eq!(store.get("raw".to_owned())?, None);
+
+    store.index_raw("raw".to_owned(), Some(info))?;
+    assert_eq!(store.get("raw".to_owned())?, Some("value2".to_owned()));
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("raw".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    store.index_raw("raw".to_owned(), None)?;
+    assert_eq!(store.get("raw".to_owned())?, None);
+
+    let store = KvStore::open_with_options(
+        TempDir::new()?.path(),
+        KvStoreOptions {
+            split_values: true,
+            ..Default::default()
+        },
+    )?;
+    assert!(store.index_raw("raw".to_owned(), Some(info)).is_err());
+    Ok(())
+}
+
+// Should rebuild the index from the logs, undoing changes made only to it
+#[test]
+fn rebuild_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    let stale_bytes = store.stale_bytes();
+    store.append_raw(br#"{"Set":{"key":"raw","value":"value4"}}"#)?;
+    store.index_raw("key2".to_owned(), None)?;
+    assert_eq!(store.get("raw".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    store.rebuild_index()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("raw".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.stale_bytes(), stale_bytes);
+    Ok(())
+}
+
+// Should skip writing a value equal to the current one
+#[test]
+fn set_if_changed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.set_if_changed("key1".to_owned(), "value1".to_owned())?);
+    let offset = store.durable_offset();
+
+    assert!(!store.set_if_changed("key1".to_owned(), "value1".to_owned())?);
+    assert_eq!(store.durable_offset(), offset);
+    assert_eq!(store.stale_bytes(), 0);
+
+    assert!(store.set_if_changed("key1".to_owned(), "value2".to_owned())?);
+    assert!(store.stale_bytes() > 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should report the tombstone written and the bytes freed by a removal
+#[test]
+fn remove_with_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions {
+            remove_missing_is_error: false,
+            ..Default::default()
+        },
+    )?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let live_bytes = store.live_bytes();
+    let (_, offset) = store.durable_offset();
+
+    let stats = store.remove_with_stats("key1".to_owned())?;
+    assert_eq!(stats.freed_bytes, live_bytes);
+    assert_eq!(stats.tombstone_bytes, store.durable_offset().1 - offset);
+    assert_eq!(
+        stats.tombstone_bytes,
+        r#"{"Remove":{"key":"key1","seq":2}}"#.len() as u64
+    );
+    assert_eq!(store.stale_bytes(), live_bytes);
+
+    let stats = store.remove_with_stats("key1".to_owned())?;
+    assert_eq!((stats.tombstone_bytes, stats.freed_bytes), (0, 0));
+    Ok(())
+}
+
+#[test]
+fn remove_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.remove("key1".to_owned()).is_ok());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Should pick up logs changed on disk, keeping the options
+#[test]
+fn reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions {
+            remove_missing_is_error: false,
+            ..Default::default()
+        },
+    )?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    fs::write(
+        temp_dir.path().join("5.log"),
+        r#"{"Set":{"key":"key2","value":"value2"}}"#,
+    )?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    store.reopen()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.remove("key3".to_owned())?;
+    assert_eq!(store.durable_offset(), (6, 8));
+    Ok(())
+}
+
+// Should classify the reasons a store can't be opened
+#[test]
+fn try_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let file = temp_dir.path().join("file");
+    fs::write(&file, "")?;
+    assert!(matches!(
+        KvStore::try_open(&file),
+        Err(OpenError::NotADirectory(_))
+    ));
+
+    let sled_dir = temp_dir.path().join("sled");
+    fs::create_dir(&sled_dir)?;
+    fs::write(sled_dir.join("engine"), "sled")?;
+    match KvStore::try_open(&sled_dir) {
+        Err(OpenError::WrongEngine { found }) => assert_eq!(found, "sled"),
+        _ => panic!("the sled directory is opened"),
+    }
+    assert!(matches!(
+        KvStore::open(&sled_dir),
+        Err(KvsError::WrongEngine { .. })
+    ));
+
+    let corrupt_dir = temp_dir.path().join("corrupt");
+    fs::create_dir(&corrupt_dir)?;
+    fs::write(corrupt_dir.join("1.log"), "{\"Set\":")?;
+    assert!(matches!(
+        KvStore::try_open(&corrupt_dir),
+        Err(OpenError::CorruptLog(_))
+    ));
+
+    let kvs_dir = temp_dir.path().join("kvs");
+    fs::create_dir(&kvs_dir)?;
+    fs::write(kvs_dir.join("engine"), "kvs")?;
+    let store = KvStore::try_open(&kvs_dir).expect("unable to open the kvs directory");
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    Ok(())
+}
+
+// Should write to the given generation if it's newer than the others
+#[test]
+fn start_gen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = |start_gen| KvStoreOptions {
+        start_gen: Some(start_gen),
+        ..Default::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options(100))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.durable_offset().0, 100);
+    store.reopen()?;
+    assert_eq!(store.durable_offset().0, 101);
+    drop(store);
+
+    for gen in [1, 101] {
+        assert!(matches!(
+            KvStore::open_with_options(temp_dir.path(), options(gen)),
+            Err(KvsError::InvalidGeneration { .. })
+        ));
+    }
+    let store = KvStore::open_with_options(temp_dir.path(), options(200))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Should report the logs and records replayed and the surprises on open
+#[test]
+fn open_verbose() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+    fs::write(temp_dir.path().join("5.log"), "")?;
+    fs::write(
+        temp_dir.path().join("6.log"),
+        r#""TxnBegin"{"Set":{"key":"key2","value":"value2"}}"#,
+    )?;
+
+    let (store, report) = KvStore::open_verbose(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!((report.generations, report.records), (3, 4));
+    assert!(report.stale_bytes > 0);
+    assert_eq!(report.warnings.len(), 2);
+    assert!(report.warnings[0].contains("generation 5"));
+    assert!(report.warnings[1].contains("generation 6"));
+    Ok(())
+}
+
+// Should send the new values of a watched key and prune dropped watchers
+#[test]
+fn watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let key1 = store.watch("key1".to_owned());
+    let dropped = store.watch("key1".to_owned());
+    drop(dropped);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.clone().set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.rename("key2".to_owned(), "key1".to_owned(), false)?;
+    assert_eq!(
+        key1.try_iter().collect::<Vec<_>>(),
+        vec![
+            Some("value1".to_owned()),
+            Some("value2".to_owned()),
+            None,
+            Some("value2".to_owned()),
+        ]
+    );
+    Ok(())
+}
+
+// Should open without writing anything, keeping what's readable of a torn log
+#[test]
+fn open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let mut log = fs::OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("1.log"))?;
+    log.write_all(br#"{"Set":{"key":"key3","val"#)?;
+    drop(log);
+    let snapshot = || -> Vec<(std::path::PathBuf, Vec<u8>)> {
+        let mut files: Vec<_> = WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap().into_path())
+            .filter(|path| path.is_file())
+            .map(|path| {
+                let bytes = fs::read(&path).unwrap();
+                (path, bytes)
+            })
+            .collect();
+        files.sort();
+        files
+    };
+    let before = snapshot();
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert!(matches!(
+        store.set("key1".to_owned(), "value3".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    drop(store);
+    assert_eq!(snapshot(), before);
+
+    let missing = temp_dir.path().join("missing");
+    assert!(KvStore::open_read_only(&missing).is_err());
+    assert!(!missing.exists());
+    Ok(())
+}
+
+// Should call the write hook with every changed key
+#[test]
+fn on_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let keys = Arc::new(Mutex::new(Vec::new()));
+    let hook_keys = keys.clone();
+    let options = KvStoreOptions {
+        on_write: Some(OnWrite::new(move |key| {
+            hook_keys.lock().unwrap().push(key.to_owned())
+        })),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    // nothing changes
+    assert!(store.remove("key1".to_owned()).is_err());
+    store.transaction(vec![
+        Op::Set {
+            key: "key2".to_owned(),
+            value: "value2".to_owned(),
+        },
+        Op::Set {
+            key: "key3".to_owned(),
+            value: "value3".to_owned(),
+        },
+    ])?;
+    store.set_compaction_threshold(0)?;
+    assert_eq!(*keys.lock().unwrap(), ["key1", "key1", "key2", "key3"]);
+    Ok(())
+}
+
+// Should rebuild the same content from a copy of the logs
+#[test]
+fn replay_log_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let src = temp_dir.path().join("src");
+    let dst = temp_dir.path().join("dst");
+    for split_values in [false, true] {
+        let options = KvStoreOptions {
+            split_values,
+            ..Default::default()
+        };
+        let _ = fs::remove_dir_all(&src);
+        let _ = fs::remove_dir_all(&dst);
+        let mut store = KvStore::open_with_options(&src, options.clone())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.set_compaction_threshold(0)?;
+        store.set("key1".to_owned(), "value3".to_owned())?;
+        store.remove("key2".to_owned())?;
+        store.set("key3".to_owned(), "value4".to_owned())?;
+
+        let mut replayed = KvStore::replay_log_bytes(&src, &dst, options.clone())?;
+        assert_eq!(replayed.content_hash()?, store.content_hash()?);
+        assert_eq!(replayed.get("key1".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(replayed.get("key2".to_owned())?, None);
+        drop(replayed);
+        assert!(matches!(
+            KvStore::replay_log_bytes(&src, &dst, options),
+            Err(KvsError::InvalidGeneration { .. })
+        ));
+    }
+    Ok(())
+}
+
+// Should tell where the store lives
+#[test]
+fn store_path() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.path(), temp_dir.path());
+    assert!(format!("{:?}", store).contains(&format!("{:?}", temp_dir.path())));
+    Ok(())
+}
+
+// Should refuse to open a directory where two logs claim the same generation
+#[test]
+fn duplicate_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    fs::copy(
+        temp_dir.path().join("1.log"),
+        temp_dir.path().join("01.log"),
+    )?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::DuplicateGeneration { gen }) => assert_eq!(gen, 1),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    fs::remove_file(temp_dir.path().join("01.log"))?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Should recover from a checkpoint plus the logs written after it
+#[test]
+fn checkpoint_recovery() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions {
+        checkpoint_interval: Some(Duration::from_secs(3600)),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.checkpoint()?;
+    assert!(temp_dir.path().join("checkpoint").exists());
+
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+
+    // a broken checkpoint falls back to a full replay
+    drop(store);
+    fs::write(temp_dir.path().join("checkpoint"), "garbage")?;
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Should replay all logs once a log covered by the checkpoint is rewritten
+#[test]
+fn checkpoint_rewritten_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions {
+        checkpoint_interval: Some(Duration::from_secs(3600)),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.checkpoint()?;
+    drop(store);
+
+    // as long as before, with the records moved
+    let log_path = temp_dir.path().join("1.log");
+    let mut log = fs::read(&log_path)?;
+    log.truncate(8);
+    log.extend_from_slice(
+        concat!(
+            r#"{"Set":{"key":"key2","value":"value2","seq":1}}"#,
+            r#"{"Set":{"key":"key1","value":"value3","seq":2}}"#,
+        )
+        .as_bytes(),
+    );
+    fs::write(&log_path, log)?;
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should seal the logs written so far as a snapshot to copy while writing on
+#[test]
+fn checkpoint_barrier() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions {
+            split_values: true,
+            ..Default::default()
+        },
+    )?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let id = store.checkpoint_barrier()?;
+    assert_eq!(id.generations, vec![1]);
+    assert_eq!(id.files, vec!["1.log", "1.vlog"]);
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    for file in &id.files {
+        fs::copy(temp_dir.path().join(file), backup_dir.path().join(file))?;
+    }
+    let backup = KvStore::open_with_options(
+        backup_dir.path(),
+        KvStoreOptions {
+            split_values: true,
+            ..Default::default()
+        },
+    )?;
+    assert_eq!(backup.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(backup.get("key2".to_owned())?, None);
+
+    assert_eq!(store.checkpoint_barrier()?.generations, vec![1, 2]);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should swap in the content of a backup only if it replays cleanly
+#[test]
+fn recover_from() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let id = store.checkpoint_barrier()?;
+    for file in &id.files {
+        fs::copy(temp_dir.path().join(file), backup_dir.path().join(file))?;
+    }
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // an empty or damaged backup leaves the store alone
+    let empty_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(matches!(
+        store.recover_from(empty_dir.path()),
+        Err(KvsError::StringError(_))
+    ));
+    let damaged_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut log = fs::read(backup_dir.path().join("1.log"))?;
+    log.truncate(log.len() - 3);
+    fs::write(damaged_dir.path().join("1.log"), log)?;
+    assert!(matches!(
+        store.recover_from(damaged_dir.path()),
+        Err(KvsError::StringError(_))
+    ));
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    store.recover_from(backup_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+// Should count the bytes rewritten by compactions over the ones written
+#[test]
+fn write_amplification() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.write_amplification(), 1.0);
+
+    // only the one live record is rewritten
+    for _ in 0..100 {
+        store.set("key".to_owned(), "value".to_owned())?;
+    }
+    store.remove("key".to_owned())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(store.write_amplification(), 1.0);
+    assert!(store.compact_if_worthwhile(0)?);
+    let amplification = store.write_amplification();
+    assert!(amplification > 1.0 && amplification < 1.1);
+
+    // all the records are live and rewritten
+    for i in 0..1000 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    assert!(store.compact_if_worthwhile(0)?);
+    assert!(store.write_amplification() > 1.5);
+
+    Ok(())
+}
+
+// Should skip the oldest logs over the replay limit only with a checkpoint
+#[test]
+fn recovery_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = |recovery_limit| KvStoreOptions {
+        checkpoint_interval: Some(Duration::from_secs(3600)),
+        recovery_limit,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options(None))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.checkpoint()?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options(None))?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    // only the log of the second open fits, key2 after the checkpoint is lost
+    let limit = fs::metadata(temp_dir.path().join("2.log"))?.len();
+    let store = KvStore::open_with_options(temp_dir.path(), options(Some(limit)))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    fs::remove_file(temp_dir.path().join("checkpoint"))?;
+    match KvStore::open_with_options(temp_dir.path(), options(Some(limit))) {
+        Err(KvsError::IncompleteRecovery { limit: found, .. }) => assert_eq!(found, limit),
+        other => panic!("unexpected result: {:?}", other.map(|_| ())),
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+struct ManualClock(AtomicU64);
+impl ManualClock {
+    fn advance(&self, ms: u64) {
+        self.0.fetch_add(ms, Ordering::SeqCst);
+    }
+}
+impl Clock for ManualClock {
+    fn now_ms(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+// Should checkpoint only when the injected clock says the interval elapsed
+#[test]
+fn checkpoint_with_clock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::default());
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions {
+            checkpoint_interval: Some(Duration::from_secs(1)),
+            clock: Some(clock.clone()),
+            ..Default::default()
+        },
+    )?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    clock.advance(999);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(!temp_dir.path().join("checkpoint").exists());
+
+    clock.advance(1);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert!(temp_dir.path().join("checkpoint").exists());
+
+    Ok(())
+}
+
+// Should track the last get or set of each key by the injected clock
+#[test]
+fn last_access() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::default());
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions {
+            track_access_times: true,
+            clock: Some(clock.clone()),
+            ..Default::default()
+        },
+    )?;
+    let at = |ms| Some(UNIX_EPOCH + Duration::from_millis(ms));
+    clock.advance(10);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.last_access("key1"), at(10));
+    assert_eq!(store.last_access("key3"), None);
+
+    clock.advance(10);
+    store.get("key1".to_owned())?;
+    store.peek("key2".to_owned())?;
+    assert_eq!(store.last_access("key1"), at(20));
+    assert_eq!(store.last_access("key2"), at(10));
+    clock.advance(10);
+    store.reader().get("key2".to_owned())?;
+    assert_eq!(store.last_access("key2"), at(30));
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.last_access("key1"), None);
+    assert_eq!(
+        store.reader().range("a".."z")?,
+        vec![("key2".to_owned(), "value2".to_owned())]
+    );
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.last_access("key2"), None);
+    Ok(())
+}
+
+// Should defer compactions triggered by writes until the cooldown elapsed
+#[test]
+fn compaction_cooldown() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::default());
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions {
+            compaction_threshold: 0,
+            compaction_cooldown: Some(Duration::from_secs(1)),
+            clock: Some(clock.clone()),
+            ..Default::default()
+        },
+    )?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.compaction_history().records().count(), 1);
+
+    for i in 3..10 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    assert_eq!(store.compaction_history().records().count(), 1);
+    assert!(store.needs_compaction());
+
+    clock.advance(1000);
+    store.set("key1".to_owned(), "value10".to_owned())?;
+    assert_eq!(store.compaction_history().records().count(), 2);
+    assert_eq!(store.get("key1".to_owned())?, Some("value10".to_owned()));
+
+    // explicit compactions don't wait
+    store.set("key1".to_owned(), "value11".to_owned())?;
+    assert!(store.compact_if_worthwhile(1)?);
+    assert_eq!(store.compaction_history().records().count(), 3);
+
+    Ok(())
+}
+
+// Should compact only when the policy calls for it
+#[test]
+fn maybe_compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction_threshold: 100,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".repeat(20))?;
+    assert!(!store.maybe_compact()?);
+
+    store.remove("key1".to_owned())?;
+    assert!(store.maybe_compact()?);
+    assert!(!store.needs_compaction());
+    assert_eq!(store.compaction_history().records().count(), 1);
+    Ok(())
+}
+
+// Should reclaim the stale bytes of a quiet store on a background thread
+#[test]
+fn compaction_interval() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction_threshold: 100,
+        compaction_interval: Some(Duration::from_millis(10)),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".repeat(20))?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    for _ in 0..500 {
+        if !store.needs_compaction() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(!store.needs_compaction());
+    assert_eq!(store.compaction_history().records().count(), 1);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should keep the manifest up to date with its creation time, if enabled
+#[test]
+fn write_manifest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::default());
+    let manifest = || -> serde_json::Value {
+        serde_json::from_slice(&fs::read(temp_dir.path().join("MANIFEST")).unwrap()).unwrap()
+    };
+    clock.advance(1000);
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions {
+            write_manifest: true,
+            clock: Some(clock.clone()),
+            ..Default::default()
+        },
+    )?;
+    assert_eq!(manifest()["current_gen"], 1);
+    assert_eq!(manifest()["format_version"], KvStore::FORMAT_VERSION);
+    assert_eq!(manifest()["compaction_threshold"], 1024 * 1024);
+
+    clock.advance(1000);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set_compaction_threshold(0)?;
+    assert_eq!(manifest()["current_gen"], 3);
+    assert_eq!(manifest()["compaction_threshold"], 0);
+    assert_eq!(manifest()["created_ms"], 1000);
+    assert_eq!(manifest()["updated_ms"], 2000);
+
+    store.checkpoint_barrier()?;
+    assert_eq!(manifest()["current_gen"], 4);
+    drop(store);
+
+    // it's informational only
+    fs::write(temp_dir.path().join("MANIFEST"), "garbage")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should work over logs kept in memory without touching the disk
+#[test]
+fn memory_storage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path().join("store");
+    let storage = MemoryStorage::new();
+    let options = || KvStoreOptions {
+        storage: Some(Arc::new(storage.clone())),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(&dir, options())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!dir.exists());
+
+    // Open again over the same logs
+    drop(store);
+    let store = KvStore::open_with_options(&dir, options())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// A storage counting how many times the logs are synced
+#[derive(Debug, Default)]
+struct SyncCountingStorage {
+    inner: MemoryStorage,
+    syncs: Arc<AtomicU64>,
+}
+impl LogStorage for SyncCountingStorage {
+    fn list_generations(&self) -> Result<Vec<u64>> {
+        self.inner.list_generations()
+    }
+    fn open(&self, gen: u64, kind: LogKind) -> Result<Box<dyn LogReader>> {
+        self.inner.open(gen, kind)
+    }
+    fn create(&self, gen: u64, kind: LogKind) -> Result<Box<dyn LogWriter>> {
+        Ok(Box::new(SyncCountingWriter {
+            inner: self.inner.create(gen, kind)?,
+            syncs: self.syncs.clone(),
+        }))
+    }
+    fn remove(&self, gen: u64, kind: LogKind) -> Result<()> {
+        self.inner.remove(gen, kind)
+    }
+    fn rename(&self, gen: u64, new_gen: u64, kind: LogKind) -> Result<()> {
+        self.inner.rename(gen, new_gen, kind)
+    }
+    fn len(&self, gen: u64, kind: LogKind) -> Result<Option<u64>> {
+        self.inner.len(gen, kind)
+    }
+    fn create_temp(&self, gen: u64, kind: LogKind) -> Result<Box<dyn LogWriter>> {
+        Ok(Box::new(SyncCountingWriter {
+            inner: self.inner.create_temp(gen, kind)?,
+            syncs: self.syncs.clone(),
+        }))
+    }
+    fn persist_temp(&self, gen: u64, kind: LogKind) -> Result<()> {
+        self.inner.persist_temp(gen, kind)
+    }
+    fn remove_temps(&self) -> Result<()> {
+        self.inner.remove_temps()
+    }
+}
+struct SyncCountingWriter {
+    inner: Box<dyn LogWriter>,
+    syncs: Arc<AtomicU64>,
+}
+impl Write for SyncCountingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+impl Seek for SyncCountingWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+impl LogWriter for SyncCountingWriter {
+    fn sync(&mut self) -> io::Result<()> {
+        self.syncs.fetch_add(1, Ordering::SeqCst);
+        self.inner.sync()
+    }
+}
+
+// A storage whose writers fail the next writes with the given error
+#[derive(Debug, Default)]
+struct FlakyStorage {
+    inner: MemoryStorage,
+    failures: Arc<AtomicU64>,
+    kind: Arc<std::sync::Mutex<Option<io::ErrorKind>>>,
+}
+impl FlakyStorage {
+    fn fail_next(&self, writes: u64, kind: io::ErrorKind) {
+        *self.kind.lock().unwrap() = Some(kind);
+        self.failures.store(writes, Ordering::SeqCst);
+    }
+    fn writer(&self, inner: Box<dyn LogWriter>) -> Box<dyn LogWriter> {
+        Box::new(FlakyWriter {
+            inner,
+            failures: self.failures.clone(),
+            kind: self.kind.clone(),
+        })
+    }
+}
+impl LogStorage for FlakyStorage {
+    fn list_generations(&self) -> Result<Vec<u64>> {
+        self.inner.list_generations()
+    }
+    fn open(&self, gen: u64, kind: LogKind) -> Result<Box<dyn LogReader>> {
+        self.inner.open(gen, kind)
+    }
+    fn create(&self, gen: u64, kind: LogKind) -> Result<Box<dyn LogWriter>> {
+        Ok(self.writer(self.inner.create(gen, kind)?))
+    }
+    fn remove(&self, gen: u64, kind: LogKind) -> Result<()> {
+        self.inner.remove(gen, kind)
+    }
+    fn rename(&self, gen: u64, new_gen: u64, kind: LogKind) -> Result<()> {
+        self.inner.rename(gen, new_gen, kind)
+    }
+    fn len(&self, gen: u64, kind: LogKind) -> Result<Option<u64>> {
+        self.inner.len(gen, kind)
+    }
+    fn create_temp(&self, gen: u64, kind: LogKind) -> Result<Box<dyn LogWriter>> {
+        Ok(self.writer(self.inner.create_temp(gen, kind)?))
+    }
+    fn persist_temp(&self, gen: u64, kind: LogKind) -> Result<()> {
+        self.inner.persist_temp(gen, kind)
+    }
+    fn remove_temps(&self) -> Result<()> {
+        self.inner.remove_temps()
+    }
+}
+struct FlakyWriter {
+    inner: Box<dyn LogWriter>,
+    failures: Arc<AtomicU64>,
+    kind: Arc<std::sync::Mutex<Option<io::ErrorKind>>>,
+}
+impl Write for FlakyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let failing = self
+            .failures
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok();
+        match *self.kind.lock().unwrap() {
+            Some(kind) if failing => Err(io::Error::from(kind)),
+            _ => self.inner.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+impl Seek for FlakyWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+impl LogWriter for FlakyWriter {}
+
+// Should retry only transient errors, as many times as configured
+#[test]
+fn io_retry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let storage = Arc::new(FlakyStorage::default());
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions {
+            storage: Some(storage.clone()),
+            io_retry: Some(RetryPolicy {
+                max_attempts: 3,
+                backoff: Duration::from_millis(1),
+            }),
+            ..Default::default()
+        },
+    )?;
+    storage.fail_next(2, io::ErrorKind::WouldBlock);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    storage.fail_next(2, io::ErrorKind::Interrupted);
+    store.remove("key1".to_owned())?;
+
+    storage.fail_next(3, io::ErrorKind::WouldBlock);
+    assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+    // a real failure isn't retried, the second failure is left
+    storage.fail_next(2, io::ErrorKind::StorageFull);
+    assert!(store.set("key3".to_owned(), "value3".to_owned()).is_err());
+    assert_eq!(storage.failures.load(Ordering::SeqCst), 1);
+    Ok(())
+}
+
+// Should sync the logs once a group of writes is full or old enough
+#[test]
+fn commit_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let storage = Arc::new(SyncCountingStorage::default());
+    let clock = Arc::new(ManualClock::default());
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions {
+            storage: Some(storage.clone()),
+            clock: Some(clock.clone()),
+            flush_and_fsync_on_drop: false,
+            commit_policy: Some(CommitPolicy {
+                max_batch: 3,
+                max_delay: Duration::from_secs(3600),
+            }),
+            ..Default::default()
+        },
+    )?;
+    let syncs = || storage.syncs.load(Ordering::SeqCst);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(syncs(), 0);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(syncs(), 1);
+
+    // the group is due once its first write is old enough
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    clock.advance(3_600_000);
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(syncs(), 2);
+
+    // a pending group is synced on drop
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    drop(store);
+    assert_eq!(syncs(), 3);
+    Ok(())
+}
+
+// Should fsync the logs on drop only if configured to
+#[test]
+fn fsync_on_drop() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for fsync in [true, false] {
+        let storage = Arc::new(SyncCountingStorage::default());
+        let store = KvStore::open_with_options(
+            temp_dir.path(),
+            KvStoreOptions {
+                storage: Some(storage.clone()),
+                flush_and_fsync_on_drop: fsync,
+                ..Default::default()
+            },
+        )?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let reader = store.clone();
+        drop(store);
+        assert_eq!(storage.syncs.load(Ordering::SeqCst), 0);
+        drop(reader);
+        assert_eq!(storage.syncs.load(Ordering::SeqCst), u64::from(fsync));
+
+        let store = KvStore::open_with_options(
+            temp_dir.path(),
+            KvStoreOptions {
+                storage: Some(storage.clone()),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+    Ok(())
+}
+
+// Should store values in value logs with the split layout
+#[test]
+fn split_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions {
+        split_values: true,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert!(temp_dir.path().join("1.vlog").exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // overwrite a large value until a compaction is triggered
+    let large = "v".repeat(1000);
+    for i in 0..1100 {
+        store.set("key3".to_owned(), format!("{}{}", large, i))?;
+    }
+    assert!(store.stale_bytes() < 1024 * 1024);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(
+        store.get("key3".to_owned())?,
+        Some(format!("{}1099", large))
+    );
+
+    // the layout is part of the on-disk format
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn set_if_absent() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.set_if_absent("key1".to_owned(), "value1".to_owned())?);
+    assert!(!store.set_if_absent("key1".to_owned(), "value2".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.stale_bytes(), 0);
+    Ok(())
+}
+
+// Should delete a prefix by a compaction, without bringing it back after a crash
+#[test]
+fn delete_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a/1".to_owned(), "value1".to_owned())?;
+    store.set("a/2".to_owned(), "value2".to_owned())?;
+    store.set("b/1".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let old_log = temp_dir.path().join("1.log");
+    let old_bytes = fs::read(&old_log)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.delete_prefix("c/")?, 0);
+    assert_eq!(store.delete_prefix("a/")?, 2);
+    assert_eq!(store.get("a/1".to_owned())?, None);
+    assert_eq!(store.get("b/1".to_owned())?, Some("value3".to_owned()));
+    assert!(store.pending_tombstones()?.is_empty());
+    store.set("a/3".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    // as if the crash came before the old log is removed
+    fs::write(&old_log, old_bytes)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a/1".to_owned())?, None);
+    assert_eq!(store.get("a/2".to_owned())?, None);
+    assert_eq!(store.get("a/3".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("b/1".to_owned())?, Some("value3".to_owned()));
+    // the interrupted removal is finished
+    assert!(!old_log.exists());
+    Ok(())
+}
+
+// Should return the pairs under a prefix and remove them
+#[test]
+fn drain_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("job/2".to_owned(), "value2".to_owned())?;
+    store.set("job/1".to_owned(), "value1".to_owned())?;
+    store.set("other".to_owned(), "value3".to_owned())?;
+    assert!(store.drain_prefix("none/")?.is_empty());
+
+    assert_eq!(
+        store.drain_prefix("job/")?,
+        vec![
+            ("job/1".to_owned(), "value1".to_owned()),
+            ("job/2".to_owned(), "value2".to_owned()),
+        ]
+    );
+    assert_eq!(store.get("job/1".to_owned())?, None);
+    assert!(store.drain_prefix("job/")?.is_empty());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("job/2".to_owned())?, None);
+    assert_eq!(store.get("other".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Should hide a soft-removed key and restore it until a compaction
+#[test]
+fn soft_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(!store.undelete("key1".to_owned())?);
+    assert!(matches!(
+        store.soft_remove("key3".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    store.soft_remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.position("key1"), None);
+    assert!(store.undelete("key1".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!store.undelete("key1".to_owned())?);
+
+    // a soft removal survives a reopen, setting the key drops the old value
+    store.soft_remove("key1".to_owned())?;
+    store.soft_remove("key2".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    assert!(!store.undelete("key2".to_owned())?);
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert!(store.undelete("key1".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // compactions remove the key for good
+    store.soft_remove("key1".to_owned())?;
+    store.compact_if_worthwhile(0)?;
+    assert!(!store.undelete("key1".to_owned())?);
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!store.undelete("key1".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Should follow the committed records across generations
+#[test]
+fn tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let (gen, _) = store.durable_offset();
+    let mut tailer = store.tail(gen, 0)?;
+    assert_eq!(tailer.next_record()?, None);
+    let ops = |tailer: &mut LogTailer| -> Result<Vec<Op>> {
+        let mut ops = Vec::new();
+        while let Some(record) = tailer.next_record()? {
+            match record {
+                TailRecord::Op { op, .. } => ops.push(op),
+                TailRecord::Reset { .. } => ops.clear(),
+            }
+        }
+        Ok(ops)
+    };
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.transaction(vec![
+        Op::Set {
+            key: "key2".to_owned(),
+            value: "value2".to_owned(),
+        },
+        Op::Remove {
+            key: "key1".to_owned(),
+        },
+    ])?;
+    assert_eq!(
+        ops(&mut tailer)?,
+        vec![
+            Op::Set {
+                key: "key1".to_owned(),
+                value: "value1".to_owned(),
+            },
+            Op::Set {
+                key: "key2".to_owned(),
+                value: "value2".to_owned(),
+            },
+            Op::Remove {
+                key: "key1".to_owned(),
+            },
+        ]
+    );
+    assert_eq!(tailer.position(), store.durable_offset());
+
+    // a reopen starts a new generation
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(
+        ops(&mut tailer)?,
+        vec![Op::Set {
+            key: "key3".to_owned(),
+            value: "value3".to_owned(),
+        }]
+    );
+    assert_eq!(tailer.position(), store.durable_offset());
+
+    // a compaction copies the live records after a reset
+    store.compact_if_worthwhile(0)?;
+    assert_eq!(ops(&mut tailer)?.len(), 2);
+    assert_eq!(tailer.position(), store.durable_offset());
+    assert!(matches!(
+        store.tail(1, 0),
+        Err(KvsError::MissingGeneration { gen: 1 })
+    ));
+    Ok(())
+}
+
+// Should refuse sets over the size limit once a compaction can't make room
+#[test]
+fn max_total_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_total_bytes: Some(512),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let value = "v".repeat(100);
+    store.set("key1".to_owned(), value.clone())?;
+    store.set("key2".to_owned(), value.clone())?;
+    store.set("key3".to_owned(), value.clone())?;
+    assert!(matches!(
+        store.set("key4".to_owned(), value.clone()),
+        Err(KvsError::StoreFull { limit: 512, .. })
+    ));
+    assert_eq!(store.get("key4".to_owned())?, None);
+
+    // the compaction reclaims the removed value
+    store.remove("key1".to_owned())?;
+    store.set("key4".to_owned(), value.clone())?;
+    assert_eq!(store.get("key4".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key4".to_owned())?, Some(value));
+    Ok(())
+}
+
+// Should store equal values once in compactions with the split layout
+#[test]
+fn dedup_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        split_values: true,
+        dedup_values: true,
+        ..KvStoreOptions::default()
+    };
+    let value_log_bytes = || -> Result<u64> {
+        let mut total = 0;
+        for entry in fs::read_dir(temp_dir.path())? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "vlog") {
+                total += fs::metadata(path)?.len();
+            }
+        }
+        Ok(total)
+    };
+    let shared = "v".repeat(1000);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), shared.clone())?;
+    }
+    store.set("other".to_owned(), "w".repeat(1000))?;
+    store.set("key0".to_owned(), shared.clone())?;
+    assert!(value_log_bytes()? > 11_000);
+
+    store.compact_if_worthwhile(0)?;
+    assert!(value_log_bytes()? < 2_100);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact_if_worthwhile(0)?;
+    assert!(value_log_bytes()? < 2_100);
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    for i in [0, 2, 9] {
+        assert_eq!(store.get(format!("key{}", i))?, Some(shared.clone()));
+    }
+    assert_eq!(store.get("other".to_owned())?, Some("w".repeat(1000)));
+    Ok(())
+}
+
+// Should replace the whole content, either the old or the new one after a crash
+#[test]
+fn replace_all() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    drop(store);
+    // the old logs as if the crash came before their removal
+    let mut old_logs = Vec::new();
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "log") {
+            old_logs.push((path.clone(), fs::read(&path)?));
+        }
+    }
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.replace_all(vec![
+        ("key2".to_owned(), "new2".to_owned()),
+        ("key3".to_owned(), "old3".to_owned()),
+        ("key3".to_owned(), "new3".to_owned()),
+    ])?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("new2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("new3".to_owned()));
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    for (path, bytes) in old_logs {
+        fs::write(path, bytes)?;
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("new2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("new3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// Should rename every key in a compaction, the last one winning a collision
+#[test]
+fn compact_with_key_transform() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "stale".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("KEY2".to_owned(), "value3".to_owned())?;
+
+    store.compact_with_key_transform(|key| format!("ns/{}", key.to_lowercase()))?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("ns/key1".to_owned())?, Some("value1".to_owned()));
+    // "key2" comes after "KEY2"
+    assert_eq!(store.get("ns/key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.compaction_history().records().count(), 1);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("KEY2".to_owned())?, None);
+    assert_eq!(store.get("ns/key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("ns/key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.transaction(vec![
+        Op::Set {
+            key: "key2".to_owned(),
+            value: "value2".to_owned(),
+        },
+        Op::Remove {
+            key: "key1".to_owned(),
+        },
+    ])?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // nothing is applied if any operation fails
+    let ops = vec![
+        Op::Set {
+            key: "key3".to_owned(),
+            value: "value3".to_owned(),
+        },
+        Op::Remove {
+            key: "key1".to_owned(),
+        },
+    ];
+    assert!(store.transaction(ops).is_err());
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    // a transaction without its commit marker is discarded on open
+    drop(store);
+    fs::write(
+        temp_dir.path().join("100.log"),
+        r#""TxnBegin"{"Set":{"key":"key4","value":"value4"}}{"Remove":{"key":"key2"}}"#,
+    )?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should list the next segments of the keys under a prefix
+#[test]
+fn list_children() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in ["a:b:c", "a:b:d", "a:b:d:e", "a:bc:f", "a:b:", "a:b::g", "h"] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+    assert_eq!(store.list_children("a:b:"), vec!["", "c", "d"]);
+    assert_eq!(store.list_children("a:b"), vec!["", "c", "d"]);
+    assert_eq!(store.list_children("a:b:d"), vec!["e"]);
+    assert_eq!(store.list_children(""), vec!["a", "h"]);
+    assert_eq!(store.list_children("x"), Vec::<String>::new());
+    drop(store);
+
+    let options = KvStoreOptions {
+        key_separator: '/',
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("a/b/c".to_owned(), "value".to_owned())?;
+    assert_eq!(store.list_children("a"), vec!["b"]);
+    assert_eq!(store.list_children("a/b/"), vec!["c"]);
+
+    Ok(())
+}
+
+#[test]
+fn rename_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    store.rename("key1".to_owned(), "key3".to_owned(), false)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value1".to_owned()));
+
+    assert!(store
+        .rename("key1".to_owned(), "key4".to_owned(), false)
+        .is_err());
+    assert!(store
+        .rename("key2".to_owned(), "key3".to_owned(), false)
+        .is_err());
+    store.rename("key2".to_owned(), "key3".to_owned(), true)?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value2".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value2".to_owned()));
+
+    // the split layout refers to the value in the active value log
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions {
+        split_values: true,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.rename("key1".to_owned(), "key2".to_owned(), false)?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn swap_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    store.swap("key1".to_owned(), "key2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        store.swap("key1".to_owned(), "key3".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    store.swap("key1".to_owned(), "key1".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Should ignore and remove the partial output of an interrupted compaction
+#[test]
+fn stale_compaction_output() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let temp_log = temp_dir.path().join("3.log.tmp");
+    fs::write(
+        &temp_log,
+        r#"{"Set":{"key":"key1","value":"partial"}}{"Set":{"#,
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!temp_log.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // a compaction leaves no temporary file behind
+    drop(store);
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions {
+            max_generations: Some(2),
+            ..Default::default()
+        },
+    )?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.compaction_history().records().count(), 1);
+    for entry in fs::read_dir(temp_dir.path())? {
+        let name = entry?.file_name().into_string().unwrap();
+        assert!(!name.ends_with(".tmp"), "{} is left", name);
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Should compact when there are too many generations
+#[test]
+fn max_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions {
+        max_generations: Some(3),
+        ..Default::default()
+    };
+    let log_count = || {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+            .count()
+    };
+
+    for i in 0..3 {
+        let store = KvStore::open_with_options(temp_dir.path(), options())?;
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        assert!(store.compaction_history().records().next().is_none());
+    }
+    assert_eq!(log_count(), 3);
+
+    // every open adds a generation
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.compaction_history().records().count(), 1);
+    assert_eq!(log_count(), 2);
+    for i in 0..4 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// Should evict the least recently used keys over the byte budget
+#[test]
+fn max_bytes_eviction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    // all the records are as long as this one
+    let len = store.live_bytes();
+    drop(store);
+
+    let options = KvStoreOptions {
+        max_bytes: Some(3 * len),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.live_bytes(), 3 * len);
+    assert_eq!(store.peek("key1".to_owned())?, None);
+
+    // peeking doesn't save key2 from being the least recently used
+    assert_eq!(store.peek("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.peek("key2".to_owned())?, None);
+    drop(store);
+
+    // evictions are persisted
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    for key in ["key0", "key3", "key4"] {
+        assert!(store.get(key.to_owned())?.is_some());
+    }
+    Ok(())
+}
+
+// Should compact only when enough bytes are stale
+#[test]
+fn compact_if_worthwhile() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    let stale_bytes = store.stale_bytes();
+    assert!(stale_bytes > 0);
+
+    assert!(!store.compact_if_worthwhile(stale_bytes + 1)?);
+    assert_eq!(store.stale_bytes(), stale_bytes);
+    assert!(store.compact_if_worthwhile(stale_bytes)?);
+    assert_eq!(store.stale_bytes(), 0);
+    assert_eq!(store.compaction_history().records().count(), 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should read the retained previous values, through compactions and reopens
+#[test]
+fn get_version() -> Result<()> {
+    for split_values in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            retained_versions: 2,
+            split_values,
+            ..Default::default()
+        };
+        let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.set("key2".to_owned(), "other".to_owned())?;
+        for i in 1..=4 {
+            store.set("key1".to_owned(), format!("value{}", i))?;
+        }
+        let check = |store: &mut KvStore| -> Result<()> {
+            assert_eq!(
+                store.get_version("key1".to_owned(), 0)?,
+                Some("value4".to_owned())
+            );
+            assert_eq!(
+                store.get_version("key1".to_owned(), 1)?,
+                Some("value3".to_owned())
+            );
+            assert_eq!(
+                store.get_version("key1".to_owned(), 2)?,
+                Some("value2".to_owned())
+            );
+            assert_eq!(store.get_version("key1".to_owned(), 3)?, None);
+            assert_eq!(store.get_version("key2".to_owned(), 1)?, None);
+            Ok(())
+        };
+        check(&mut store)?;
+        // only the version falling out is stale
+        assert!(store.stale_bytes() > 0);
+        assert!(store.compact_if_worthwhile(0)?);
+        check(&mut store)?;
+        drop(store);
+
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        check(&mut store)?;
+        assert_eq!(store.stale_bytes(), 0);
+        store.remove("key1".to_owned())?;
+        assert_eq!(store.get_version("key1".to_owned(), 1)?, None);
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.get_version("key1".to_owned(), 1).is_err());
+    Ok(())
+}
+
+// Should hash the live content the same whatever the write order or compaction
+#[test]
+fn content_hash() -> Result<()> {
+    let temp_dir1 = TempDir::new().expect("unable to create temporary working directory");
+    let temp_dir2 = TempDir::new().expect("unable to create temporary working directory");
+    let mut store1 = KvStore::open(temp_dir1.path())?;
+    let mut store2 = KvStore::open(temp_dir2.path())?;
+    let empty = store1.content_hash()?;
+    store1.set("key1".to_owned(), "value1".to_owned())?;
+    store1.set("key2".to_owned(), "value2".to_owned())?;
+    store2.set("key2".to_owned(), "stale".to_owned())?;
+    store2.set("key3".to_owned(), "value3".to_owned())?;
+    store2.set("key2".to_owned(), "value2".to_owned())?;
+    store2.set("key1".to_owned(), "value1".to_owned())?;
+    assert_ne!(store1.content_hash()?, store2.content_hash()?);
+
+    store2.remove("key3".to_owned())?;
+    let hash = store1.content_hash()?;
+    assert_eq!(store2.content_hash()?, hash);
+    assert_ne!(hash, empty);
+    store2.set_compaction_threshold(0)?;
+    assert_eq!(store2.content_hash()?, hash);
+    Ok(())
+}
+
+// Should export CSV rows with escaping and import them back
+#[test]
+fn export_import_csv() -> Result<()> {
+    let temp_dir1 = TempDir::new().expect("unable to create temporary working directory");
+    let temp_dir2 = TempDir::new().expect("unable to create temporary working directory");
+    let mut store1 = KvStore::open(temp_dir1.path())?;
+    store1.set("b".to_owned(), "plain".to_owned())?;
+    store1.set("a,1".to_owned(), "say \"hi\"".to_owned())?;
+    store1.set("c".to_owned(), "two\r\nlines".to_owned())?;
+    store1.set("d".to_owned(), String::new())?;
+
+    let mut csv = Vec::new();
+    store1.export_csv(&mut csv)?;
+    assert_eq!(
+        String::from_utf8(csv.clone()).unwrap(),
+        "\"a,1\",\"say \"\"hi\"\"\"\nb,plain\nc,\"two\r\nlines\"\nd,\n"
+    );
+
+    let mut store2 = KvStore::open(temp_dir2.path())?;
+    assert_eq!(store2.import_csv(&csv[..])?, 4);
+    assert_eq!(store2.content_hash()?, store1.content_hash()?);
+
+    assert!(store2.import_csv(&b"e,1\nf\n"[..]).is_err());
+    assert_eq!(store2.get("e".to_owned())?, Some("1".to_owned()));
+    assert!(store2.import_csv(&b"g,\"open\n"[..]).is_err());
+    Ok(())
+}
+
+// Should count the new, overwritten and identical keys of an import
+#[test]
+fn import_preview() -> Result<()> {
+    let temp_dir1 = TempDir::new().expect("unable to create temporary working directory");
+    let temp_dir2 = TempDir::new().expect("unable to create temporary working directory");
+    let src = KvStore::open(temp_dir1.path())?;
+    let store = KvStore::open(temp_dir2.path())?;
+    for i in 0..20 {
+        src.set(format!("key{:02}", i), "new".to_owned())?;
+    }
+    for i in 0..15 {
+        store.set(format!("key{:02}", i), "old".to_owned())?;
+    }
+    store.set("key14".to_owned(), "new".to_owned())?;
+
+    let preview = store.import_preview(src.clone())?;
+    assert_eq!(preview.new_keys, 5);
+    assert_eq!(preview.overwritten, 14);
+    assert_eq!(preview.identical, 1);
+    let sample: Vec<String> = (0..10).map(|i| format!("key{:02}", i)).collect();
+    assert_eq!(preview.overwritten_sample, sample);
+    // nothing is written
+    assert_eq!(store.get("key00".to_owned())?, Some("old".to_owned()));
+    assert_eq!(store.get("key19".to_owned())?, None);
+
+    let preview = store.import_csv_preview(&b"key00,1\nkey00,old\nnew,1\n"[..])?;
+    assert_eq!(
+        preview,
+        ImportPreview {
+            new_keys: 1,
+            overwritten: 0,
+            overwritten_sample: Vec::new(),
+            identical: 1,
+        }
+    );
+    assert!(store.import_csv_preview(&b"a,1\nb\n"[..]).is_err());
+    Ok(())
+}
+
+// A storage cancelling a token once a compaction starts writing
+#[derive(Debug)]
+struct CancellingStorage {
+    inner: MemoryStorage,
+    cancel: CancellationToken,
+}
+impl LogStorage for CancellingStorage {
+    fn list_generations(&self) -> Result<Vec<u64>> {
+        self.inner.list_generations()
+    }
+    fn open(&self, gen: u64, kind: LogKind) -> Result<Box<dyn LogReader>> {
+        self.inner.open(gen, kind)
+    }
+    fn create(&self, gen: u64, kind: LogKind) -> Result<Box<dyn LogWriter>> {
+        self.inner.create(gen, kind)
+    }
+    fn remove(&self, gen: u64, kind: LogKind) -> Result<()> {
+        self.inner.remove(gen, kind)
+    }
+    fn rename(&self, gen: u64, new_gen: u64, kind: LogKind) -> Result<()> {
+        self.inner.rename(gen, new_gen, kind)
+    }
+    fn len(&self, gen: u64, kind: LogKind) -> Result<Option<u64>> {
+        self.inner.len(gen, kind)
+    }
+    fn create_temp(&self, gen: u64, kind: LogKind) -> Result<Box<dyn LogWriter>> {
+        self.cancel.cancel();
+        self.inner.create_temp(gen, kind)
+    }
+    fn persist_temp(&self, gen: u64, kind: LogKind) -> Result<()> {
+        self.inner.persist_temp(gen, kind)
+    }
+    fn remove_temps(&self) -> Result<()> {
+        self.inner.remove_temps()
+    }
+}
+
+// Should give up a cancelled compaction leaving the store consistent
+#[test]
+fn compact_cancellable() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let storage = MemoryStorage::new();
+    let cancel = CancellationToken::new();
+    let options = || KvStoreOptions {
+        storage: Some(Arc::new(CancellingStorage {
+            inner: storage.clone(),
+            cancel: cancel.clone(),
+        })),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+
+    // cancelled in the middle, once the compaction log is created
+    match store.compact_cancellable(&cancel) {
+        Err(KvsError::Cancelled) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert!(store.compaction_history().records().next().is_none());
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value4".to_owned())?;
+
+    // cancelled already, nothing is done
+    assert!(matches!(
+        store.compact_cancellable(&cancel),
+        Err(KvsError::Cancelled)
+    ));
+    let gens = storage.list_generations()?;
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert!(storage.list_generations()?.starts_with(&gens));
+    for (key, value) in [("key1", "value2"), ("key2", "value3"), ("key3", "value4")] {
+        assert_eq!(store.get(key.to_owned())?, Some(value.to_owned()));
+    }
+    store.compact_cancellable(&CancellationToken::new())?;
+    assert_eq!(store.compaction_history().records().count(), 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should tell when the stale bytes are over the threshold
+#[test]
+fn needs_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction_threshold: 100,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert!(!store.needs_compaction());
+    store.set("key1".to_owned(), "value1".repeat(20))?;
+    assert!(!store.needs_compaction());
+
+    // removals don't compact by themselves
+    store.remove("key1".to_owned())?;
+    assert!(store.needs_compaction());
+    assert!(store.compact_if_worthwhile(0)?);
+    assert!(!store.needs_compaction());
+    Ok(())
+}
+
+// Should preview the compaction without writing anything
+#[test]
+fn compaction_preview() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let preview = store.compaction_preview();
+    assert_eq!(preview.reclaimable_bytes, store.stale_bytes());
+    assert_eq!(preview.live_entries, 2);
+    assert_eq!(store.compaction_history().records().count(), 0);
+
+    store.set_compaction_threshold(0)?;
+    // the compaction generation
+    let compacted = fs::metadata(temp_dir.path().join("2.log"))?.len();
+    assert_eq!(preview.output_bytes, compacted);
+    Ok(())
+}
+
+// Should compact right away when the threshold is lowered under the stale bytes
+#[test]
+fn set_compaction_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    let stale_bytes = store.stale_bytes();
+
+    store.set_compaction_threshold(stale_bytes)?;
+    assert_eq!(store.stale_bytes(), stale_bytes);
+    store.set_compaction_threshold(stale_bytes - 1)?;
+    assert_eq!(store.stale_bytes(), 0);
+    assert_eq!(store.compaction_history().records().count(), 1);
+
+    // the next overwrite goes over the threshold
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.compaction_history().records().count(), 2);
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Should leave the generations of large live values out of compactions
+#[test]
+fn compaction_skip_len() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions {
+        compaction_skip_len: Some(50),
+        ..Default::default()
+    };
+    let log_exists = |gen: u64| temp_dir.path().join(format!("{}.log", gen)).exists();
+    let big = "x".repeat(100);
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("big".to_owned(), big.clone())?;
+    store.set("small".to_owned(), "value1".to_owned())?;
+    store.set("gone".to_owned(), "value1".to_owned())?;
+    store.set("small".to_owned(), "value2".to_owned())?;
+    store.remove("gone".to_owned())?;
+
+    // compacted into generation 2, writing to 3
+    store.set_compaction_threshold(0)?;
+    assert!(log_exists(1) && log_exists(2) && log_exists(3));
+    assert_eq!(store.get("big".to_owned())?, Some(big.clone()));
+    assert_eq!(store.get("small".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("gone".to_owned())?, None);
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("big".to_owned())?, Some(big));
+    assert_eq!(store.get("small".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("gone".to_owned())?, None);
+
+    // the large value is stale, its generation goes at last
+    store.set("big".to_owned(), "small now".to_owned())?;
+    store.set_compaction_threshold(0)?;
+    assert!(!log_exists(1));
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("big".to_owned())?, Some("small now".to_owned()));
+    assert_eq!(store.get("gone".to_owned())?, None);
+    Ok(())
+}
+
+// Should leave the generations of pinned keys out of compactions
+#[test]
+fn pin() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_exists = |gen: u64| temp_dir.path().join(format!("{}.log", gen)).exists();
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("hot".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("cold".to_owned(), "value1".to_owned())?;
+    store.set("cold".to_owned(), "value2".to_owned())?;
+    store.pin("hot".to_owned());
+    store.pin("missing".to_owned());
+
+    // compacted into generation 3, writing to 4
+    store.compact_if_worthwhile(0)?;
+    assert!(log_exists(1) && !log_exists(2) && log_exists(3) && log_exists(4));
+    assert_eq!(store.position("hot").map(|pos| pos.gen), Some(1));
+    assert_eq!(store.position("cold").map(|pos| pos.gen), Some(3));
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("hot".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("cold".to_owned())?, Some("value2".to_owned()));
+
+    // pins aren't persisted
+    store.pin("hot".to_owned());
+    assert!(store.unpin("hot"));
+    assert!(!store.unpin("hot"));
+    store.set("cold".to_owned(), "value3".to_owned())?;
+    store.compact_if_worthwhile(0)?;
+    assert!(!log_exists(1));
+    assert_eq!(store.get("hot".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Should merge the small generations only, keeping removals of old keys
+#[test]
+fn compact_tiered() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let gens = || {
+        let mut gens: Vec<u64> = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter_map(|name| name.strip_suffix(".log").map(|gen| gen.parse().unwrap()))
+            .collect();
+        gens.sort_unstable();
+        gens
+    };
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.remove("key0".to_owned())?;
+    store.set("key20".to_owned(), "old".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key20".to_owned(), "value20".to_owned())?;
+    store.set("key21".to_owned(), "value21".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(gens(), vec![1, 2, 3, 4]);
+    // only generation 2 and 3 are of similar sizes
+    assert!(store.compact_tiered()? > 0);
+    assert_eq!(gens(), vec![1, 2, 4]);
+    assert_eq!(store.compact_tiered()?, 0);
+    store.set("key22".to_owned(), "value22".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for i in 1..23 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// Should merge generations in place, so a rollback to a later sequence
+// number keeps the merged records
+#[test]
+fn compact_tiered_rollback() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.remove("key0".to_owned())?;
+    store.set("key20".to_owned(), "old".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key20".to_owned(), "value20".to_owned())?;
+    store.set("key21".to_owned(), "value21".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 22..42 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    // generation 2 and 3 are merged into 2, before the newer generation 4
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.compact_tiered()? > 0);
+    assert!(!temp_dir.path().join("3.log").exists());
+    assert_eq!(store.current_seq(), 44);
+    store.rollback_to_seq(34)?;
+    assert_eq!(store.current_seq(), 34);
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for i in 1..32 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+        for i in 32..42 {
+            assert_eq!(store.get(format!("key{}", i))?, None);
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.current_seq(), 34);
+    check(&store)
+}
+
+// Should keep the reads of other clones right while generations are merged
+// in place
+#[cfg(feature = "test-util")]
+#[test]
+fn compact_tiered_concurrent_reads() -> Result<()> {
+    use std::sync::atomic::AtomicBool;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    // the records of `a` stay where they are in the merged generation, the
+    // ones of `b` move, the first ones first
+    for i in 0..10 {
+        store.set(format!("a{}", i), format!("value-a{}", i))?;
+    }
+    for i in 0..500 {
+        store.set(format!("b{}", i), format!("value-b{}-", i))?;
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut handles = Vec::new();
+    for _ in 0..4 {
+        let store = store.clone();
+        let stop = stop.clone();
+        handles.push(thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                for i in 1..10 {
+                    assert_eq!(
+                        store.get(format!("a{}", i)).unwrap(),
+                        Some(format!("value-a{}", i))
+                    );
+                    // the newer generation of the pair can be removed under
+                    // the read, but the old log is never read at new positions
+                    match store.get(format!("b{}", i)) {
+                        Ok(value) => {
+                            assert!(value.unwrap().starts_with(&format!("value-b{}-", i)))
+                        }
+                        Err(KvsError::MissingGeneration { .. }) => {}
+                        Err(err) => panic!("{}", err),
+                    }
+                }
+            }
+        }));
+    }
+
+    for round in 1..50 {
+        // the lengths change, so the old log has other records at the new
+        // positions
+        let value = |i| format!("value-b{}-{}", i, "x".repeat(round % 5));
+        store.set_in_new_generation("b0".to_owned(), value(0))?;
+        assert!(store.compact_tiered()? > 0 || round == 1);
+        for i in 0..500 {
+            store.set(format!("b{}", i), value(i))?;
+        }
+    }
+    stop.store(true, Ordering::SeqCst);
+    for handle in handles {
+        handle.join().unwrap();
+    }
     Ok(())
 }
 
+// Should drop only the removals of keys never set before them
 #[test]
-fn remove_key() -> Result<()> {
+fn purge_tombstones() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        concat!(
+            r#"{"Remove":{"key":"key0"}}"#,
+            r#"{"Set":{"key":"key1","value":"value1"}}"#,
+            r#"{"Remove":{"key":"key1"}}"#,
+            r#"{"Set":{"key":"key2","value":"value2"}}"#,
+        ),
+    )?;
     let store = KvStore::open(temp_dir.path())?;
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert!(store.remove("key1".to_owned()).is_ok());
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3".to_owned())?;
+
+    // only the removal of key0 goes, the log gets the format header
+    let len = fs::metadata(temp_dir.path().join("1.log"))?.len();
+    let removal_len = r#"{"Remove":{"key":"key0"}}"#.len() as u64;
+    assert_eq!(store.purge_tombstones()?, removal_len - 8);
+    assert_eq!(
+        fs::metadata(temp_dir.path().join("1.log"))?.len(),
+        len + 8 - removal_len
+    );
+    assert_eq!(store.purge_tombstones()?, 0);
     assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+// Should list the keys of the removals on disk which are not live
+#[test]
+fn pending_tombstones() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in ["key1", "key2", "key3"] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+    store.remove("key3".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key2".to_owned(), "value".to_owned())?;
+    assert_eq!(store.pending_tombstones()?, vec!["key1", "key3"]);
+
+    store.compact_if_worthwhile(0)?;
+    assert!(store.pending_tombstones()?.is_empty());
+    Ok(())
+}
+
+// Should renumber generations contiguously from 1
+#[test]
+fn normalize_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions {
+        max_generations: Some(3),
+        ..Default::default()
+    };
+    let gens = || {
+        let mut gens: Vec<String> = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(".log"))
+            .collect();
+        gens.sort();
+        gens
+    };
+    for i in 0..4 {
+        let store = KvStore::open_with_options(temp_dir.path(), options())?;
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    // compacted into generation 5, writing to 6
+    assert_eq!(gens(), vec!["5.log", "6.log"]);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    let reader = store.clone();
+    assert_eq!(reader.get("key0".to_owned())?, Some("value0".to_owned()));
+    store.normalize_generations()?;
+    assert_eq!(gens(), vec!["1.log", "2.log", "3.log"]);
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    for i in 0..5 {
+        assert_eq!(
+            reader.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+
+    drop(reader);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..5 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// Should leave a consistent store when renaming a log fails halfway through
+// renumbering the generations
+#[cfg(feature = "fault-injection")]
+#[test]
+fn normalize_generations_failed_rename() -> Result<()> {
+    use kvs::{FaultSchedule, FaultyStorage, FsStorage};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let storage = FaultyStorage::new(Arc::new(FsStorage::new(temp_dir.path())));
+    let options = || KvStoreOptions {
+        storage: Some(Arc::new(storage.clone())),
+        max_generations: Some(3),
+        checkpoint_interval: Some(Duration::from_secs(3600)),
+        ..Default::default()
+    };
+    for i in 0..4 {
+        let store = KvStore::open_with_options(temp_dir.path(), options())?;
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    // compacted into generation 5, writing to 6 then 7
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.checkpoint()?;
+    assert!(temp_dir.path().join("checkpoint").exists());
+
+    storage.schedule(FaultSchedule {
+        fail_rename: Some(2),
+        ..Default::default()
+    });
+    assert!(store.normalize_generations().is_err());
+    assert_eq!(storage.faults(), 1);
+    assert!(!temp_dir.path().join("checkpoint").exists());
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    for i in 0..5 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for i in 0..5 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    store.normalize_generations()?;
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for i in 0..5 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
     Ok(())
 }
 
@@ -127,6 +3342,437 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// Should record each compaction in the history
+#[test]
+fn compaction_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.compaction_history().records().count(), 0);
+    assert_eq!(store.compaction_history().average_duration(), None);
+
+    for iter in 0..1000 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        let history = store.compaction_history();
+        if history.records().count() == 0 {
+            continue;
+        }
+        assert_eq!(history.records().count(), 1);
+        assert_eq!(history.count_within(Duration::from_secs(60)), 1);
+        assert!(history.average_reclaimed_bytes().unwrap() > 1024 * 1024);
+        assert_eq!(history.average_duration(), history.p99_duration());
+        return Ok(());
+    }
+
+    panic!("No compaction detected");
+}
+
+// Should point every key at its exact record after compacting runs of small
+// records interleaved with stale ones across generations
+#[test]
+fn compaction_copies_runs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // the keys set again by each generation and every other key of the
+    // generations are stale, the live records are left in short runs
+    for gen in 0..3 {
+        let store = KvStore::open(temp_dir.path())?;
+        for i in 0..30 {
+            store.set(format!("key{}", i), format!("{}-{}", gen, i))?;
+            store.set(format!("gen{}-key{}", gen, i), format!("v{}", i))?;
+        }
+        for i in (0..30).step_by(2) {
+            store.set(format!("gen{}-key{}", gen, i), format!("new{}", i))?;
+        }
+    }
+
+    let expected = |key: &str| {
+        let (prefix, i) = key.split_once("key").unwrap();
+        let i: usize = i.parse().unwrap();
+        match prefix {
+            "" => format!("2-{}", i),
+            _ if i % 2 == 1 => format!("v{}", i),
+            _ => format!("new{}", i),
+        }
+    };
+    let check = |store: &KvStore| -> Result<Vec<_>> {
+        let mut keys: Vec<String> = (0..30).map(|i| format!("key{}", i)).collect();
+        for gen in 0..3 {
+            keys.extend((0..30).map(|i| format!("gen{}-key{}", gen, i)));
+        }
+        let mut positions = Vec::new();
+        for key in keys {
+            let value = expected(&key);
+            assert_eq!(store.get(key.clone())?, Some(value.clone()));
+            let pos = store.position(&key).unwrap();
+            let log = fs::read(temp_dir.path().join(format!("{}.log", pos.gen)))?;
+            let record: serde_json::Value =
+                serde_json::from_slice(&log[pos.pos as usize..(pos.pos + pos.len) as usize])
+                    .unwrap();
+            assert_eq!(record["Set"]["key"], key.as_str());
+            assert_eq!(record["Set"]["value"], value.as_str());
+            positions.push(pos);
+        }
+        Ok(positions)
+    };
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.compact_if_worthwhile(0)?);
+    let positions = check(&store)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(check(&store)?, positions);
+    Ok(())
+}
+
+// Should work the same whatever the expected number of keys
+#[test]
+fn expected_keys() -> Result<()> {
+    for expected_keys in [0, 100_000] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = || KvStoreOptions {
+            expected_keys,
+            ..Default::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options())?;
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        store.remove("key0".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options())?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for i in 1..100 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+    }
+    Ok(())
+}
+
+// Should compact and reopen the same with or without syncing the directory
+#[test]
+fn sync_directory() -> Result<()> {
+    for sync_directory in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = || KvStoreOptions {
+            sync_directory,
+            ..Default::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options())?;
+        for i in 0..10 {
+            store.set(format!("key{}", i), format!("old{}", i))?;
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        store.remove("key0".to_owned())?;
+        assert!(store.compact_if_worthwhile(0)?);
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options())?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for i in 1..10 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+    }
+    Ok(())
+}
+
+// Stale bytes above the threshold trigger a compaction on the next write
+#[cfg(feature = "test-util")]
+#[test]
+fn forced_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert!(store.stale_bytes() > 0);
+
+    store.set_stale_bytes(1 << 30);
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.stale_bytes(), 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stale_bytes(), 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should spread the keys across a generation each
+#[cfg(feature = "test-util")]
+#[test]
+fn set_in_new_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..5 {
+        store.set_in_new_generation(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert_eq!(KvStore::list_generations(temp_dir.path(), false)?.len(), 6);
+    for i in 0..5 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    store.compact_if_worthwhile(0)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..5 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// Should read from mapped logs, the growing active one and compacted ones
+#[cfg(feature = "mmap")]
+#[test]
+fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for split_values in [false, true] {
+        let dir = temp_dir.path().join(split_values.to_string());
+        let options = || KvStoreOptions {
+            mmap_reads: true,
+            split_values,
+            ..Default::default()
+        };
+        let store = KvStore::open_with_options(&dir, options())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        // past the end of the mapping
+        store.set("key2".to_owned(), "value\n2".to_owned())?;
+        assert_eq!(store.get("key2".to_owned())?, Some("value\n2".to_owned()));
+        let mut buf = String::new();
+        assert!(store.get_into("key1".to_owned(), &mut buf)?);
+        assert_eq!(buf, "value1");
+
+        store.set("key1".to_owned(), "value3".to_owned())?;
+        store.set_compaction_threshold(0)?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value\n2".to_owned()));
+        drop(store);
+
+        let store = KvStore::open_with_options(&dir, options())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+        store.normalize_generations()?;
+        assert_eq!(store.get("key2".to_owned())?, Some("value\n2".to_owned()));
+    }
+    Ok(())
+}
+
+// Should leave a reopenable store after failed writes and syncs, a crash
+// right after them included
+#[cfg(feature = "fault-injection")]
+#[test]
+fn injected_faults() -> Result<()> {
+    use kvs::{FaultSchedule, FaultyStorage, FsStorage};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        let storage = FaultyStorage::new(Arc::new(FsStorage::new(temp_dir.path())));
+        let store = KvStore::open_with_options(
+            temp_dir.path(),
+            KvStoreOptions {
+                storage: Some(Arc::new(storage.clone())),
+                ..Default::default()
+            },
+        );
+        store.map(|store| (store, storage))
+    };
+
+    let (store, storage) = open()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    storage.schedule(FaultSchedule {
+        fail_write: Some(1),
+        ..Default::default()
+    });
+    assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+    assert_eq!(storage.faults(), 1);
+    // a crash, nothing is flushed on drop
+    std::mem::forget(store);
+
+    let (store, storage) = open()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    storage.schedule(FaultSchedule {
+        fail_sync: true,
+        ..Default::default()
+    });
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(store.checkpoint_barrier().is_err());
+    std::mem::forget(store);
+
+    let (store, _) = open()?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should report a record torn by a crash in the middle of a write as a
+// corrupt log
+#[cfg(feature = "fault-injection")]
+#[test]
+fn injected_torn_write() -> Result<()> {
+    use kvs::{FaultSchedule, FaultyStorage, FsStorage};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let storage = FaultyStorage::new(Arc::new(FsStorage::new(temp_dir.path())));
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions {
+            storage: Some(Arc::new(storage.clone())),
+            ..Default::default()
+        },
+    )?;
+    storage.schedule(FaultSchedule {
+        fail_after_bytes: Some(10),
+        ..Default::default()
+    });
+    assert!(store.set("key1".to_owned(), "value1".to_owned()).is_err());
+    std::mem::forget(store);
+
+    assert!(matches!(
+        KvStore::try_open(temp_dir.path()),
+        Err(OpenError::CorruptLog(_))
+    ));
+    Ok(())
+}
+
+// Should compact into the given generation unless it's taken
+#[cfg(feature = "test-util")]
+#[test]
+fn compact_into() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    fs::write(temp_dir.path().join("6.log"), "")?;
+
+    for gen in [1, 5, 6] {
+        assert!(matches!(
+            store.compact_into(gen),
+            Err(KvsError::InvalidGeneration { .. })
+        ));
+    }
+    store.compact_into(10)?;
+    assert_eq!(store.stale_bytes(), 0);
+    assert_eq!(store.durable_offset(), (11, 8));
+    assert!(temp_dir.path().join("10.log").exists());
+    assert!(!temp_dir.path().join("1.log").exists());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should run the operations in order without blocking the caller
+#[cfg(feature = "tokio")]
+#[test]
+fn async_store() -> Result<()> {
+    use kvs::AsyncKvStore;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+    runtime.block_on(async {
+        let store = AsyncKvStore::new(KvStore::open(temp_dir.path())?)?;
+        let other = store.clone();
+        // queued in the order they're polled first
+        let (set1, set2, get, remove) = tokio::join!(
+            store.set("key1".to_owned(), "value1".to_owned()),
+            other.set("key1".to_owned(), "value2".to_owned()),
+            store.get("key1".to_owned()),
+            store.remove("key2".to_owned()),
+        );
+        set1?;
+        set2?;
+        assert_eq!(get?, Some("value2".to_owned()));
+        assert!(matches!(remove, Err(KvsError::KeyNotFound)));
+        Ok(())
+    })
+}
+
+// Should fail outside of a tokio runtime
+#[cfg(feature = "tokio")]
+#[test]
+fn async_store_without_runtime() -> Result<()> {
+    use kvs::AsyncKvStore;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(matches!(
+        AsyncKvStore::new(KvStore::open(temp_dir.path())?),
+        Err(KvsError::StringError(_))
+    ));
+    Ok(())
+}
+
+// An engine whose sets wait for a go from the test
+#[cfg(feature = "tokio")]
+#[derive(Clone)]
+struct GatedEngine {
+    inner: KvStore,
+    go: Arc<Mutex<mpsc::Receiver<()>>>,
+}
+#[cfg(feature = "tokio")]
+impl KvsEngine for GatedEngine {
+    fn open(_: impl Into<std::path::PathBuf>) -> Result<Self> {
+        unimplemented!()
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.go.lock().unwrap().recv().unwrap();
+        self.inner.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.inner.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.inner.remove(key)
+    }
+}
+
+// Should hold the operations over the queue capacity until there's room
+#[cfg(feature = "tokio")]
+#[test]
+fn async_store_backpressure() -> Result<()> {
+    use kvs::AsyncKvStore;
+    use std::future::{poll_fn, Future};
+    use std::pin::pin;
+    use std::task::Poll;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (go, receiver) = mpsc::channel();
+    let engine = GatedEngine {
+        inner: KvStore::open(temp_dir.path())?,
+        go: Arc::new(Mutex::new(receiver)),
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+    runtime.block_on(async {
+        let store = AsyncKvStore::with_capacity(engine, 1)?;
+        let mut ops = pin!(async {
+            tokio::join!(
+                store.set("key1".to_owned(), "value1".to_owned()),
+                store.set("key1".to_owned(), "value2".to_owned()),
+                store.get("key1".to_owned()),
+            )
+        });
+        // the first set blocks the task, the queue is full
+        let pending = poll_fn(|cx| Poll::Ready(ops.as_mut().poll(cx).is_pending())).await;
+        assert!(pending);
+        assert_eq!(store.queue_depth(), 1);
+
+        go.send(()).unwrap();
+        go.send(()).unwrap();
+        let (set1, set2, get) = ops.await;
+        set1?;
+        set2?;
+        assert_eq!(get?, Some("value2".to_owned()));
+        assert_eq!(store.queue_depth(), 0);
+        Ok(())
+    })
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");